@@ -0,0 +1,188 @@
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+use failure::{bail, format_err, Fallible};
+
+use crate::checkpoint::CheckpointData;
+
+/// What duma needs from an aria2 `.aria2` control file: which pieces of
+/// the file are already on disk.
+///
+/// The format is version, extension, info hash, piece length, total
+/// length, upload length and the bitfield of finished pieces, followed by
+/// the pieces in flight. Version 1 files are big endian, version 0 ones
+/// are in the byte order of the machine that wrote them, taken to be
+/// little endian. Pieces in flight count as missing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlFile {
+    pub piece_length: u64,
+    pub total_length: u64,
+    /// One bit per piece, the most significant bit of the first byte for
+    /// the first piece.
+    pub bitfield: Vec<u8>,
+}
+
+impl ControlFile {
+    pub fn parse(data: &[u8]) -> Fallible<ControlFile> {
+        let mut reader = Reader { data, pos: 0 };
+        let version = u16::from_be_bytes(reader.take(2)?.try_into()?);
+        let big_endian = match version {
+            0 => false,
+            1 => true,
+            _ => bail!("unsupported aria2 control file version {}", version),
+        };
+        reader.uint(4, big_endian)?; // extension
+        let hash_len = reader.uint(4, big_endian)?;
+        reader.take(hash_len as usize)?;
+        let piece_length = reader.uint(4, big_endian)?;
+        let total_length = reader.uint(8, big_endian)?;
+        reader.uint(8, big_endian)?; // upload length
+        let bitfield_len = reader.uint(4, big_endian)? as usize;
+        let bitfield = reader.take(bitfield_len)?.to_vec();
+        if piece_length == 0 {
+            bail!("aria2 control file has a piece length of 0");
+        }
+        let pieces = total_length.div_ceil(piece_length);
+        if bitfield_len as u64 != pieces.div_ceil(8) {
+            bail!(
+                "aria2 control file has a {} byte bitfield for {} pieces",
+                bitfield_len,
+                pieces
+            );
+        }
+        Ok(ControlFile {
+            piece_length,
+            total_length,
+            bitfield,
+        })
+    }
+
+    /// The finished pieces as a checkpoint of `file` downloaded from
+    /// `url`, with runs of pieces merged.
+    pub fn to_checkpoint(&self, url: &str, file: &str) -> CheckpointData {
+        let mut data = CheckpointData::new(url, file);
+        data.total_bytes = self.total_length;
+        let pieces = self.total_length.div_ceil(self.piece_length);
+        for idx in 0..pieces {
+            let offset = idx * self.piece_length;
+            let length = self.piece_length.min(self.total_length - offset);
+            if self.bitfield[(idx / 8) as usize] & (0x80 >> (idx % 8)) != 0 {
+                data.add_chunk(offset, length);
+            }
+        }
+        data
+    }
+}
+
+pub fn load_control_file(path: &Path) -> Fallible<ControlFile> {
+    let data =
+        fs::read(path).map_err(|e| format_err!("failed to read {}: {}", path.display(), e))?;
+    ControlFile::parse(&data).map_err(|e| format_err!("invalid {}: {}", path.display(), e))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Fallible<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| format_err!("aria2 control file is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Reads a `len` byte unsigned integer.
+    fn uint(&mut self, len: usize, big_endian: bool) -> Fallible<u64> {
+        let bytes = self.take(len)?;
+        let fold = |n: u64, &b: &u8| n << 8 | u64::from(b);
+        Ok(if big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::ChunkRange;
+
+    /// A version 1 control file for a 10000 byte download in 1024 byte
+    /// pieces, with pieces 0, 1, 4 and 9 finished and piece 2 in flight.
+    fn sample() -> Vec<u8> {
+        let mut data = vec![0, 1];
+        data.extend(&0u32.to_be_bytes()); // extension
+        data.extend(&0u32.to_be_bytes()); // no info hash
+        data.extend(&1024u32.to_be_bytes());
+        data.extend(&10000u64.to_be_bytes());
+        data.extend(&0u64.to_be_bytes()); // uploaded
+        data.extend(&2u32.to_be_bytes());
+        data.extend(&[0b1100_1000, 0b0100_0000]);
+        // one piece in flight: piece 2, 1024 bytes, no blocks done yet
+        data.extend(&1u32.to_be_bytes());
+        data.extend(&2u32.to_be_bytes());
+        data.extend(&1024u32.to_be_bytes());
+        data.extend(&1u32.to_be_bytes());
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn test_parse_control_file() {
+        let control = ControlFile::parse(&sample()).unwrap();
+        assert_eq!(control.piece_length, 1024);
+        assert_eq!(control.total_length, 10000);
+
+        let cp = control.to_checkpoint("http://example.com/foo.bin", "foo.bin");
+        assert_eq!(
+            cp.chunks_completed,
+            vec![
+                ChunkRange {
+                    offset: 0,
+                    length: 2048
+                },
+                ChunkRange {
+                    offset: 4096,
+                    length: 1024
+                },
+                // the last piece is shorter
+                ChunkRange {
+                    offset: 9216,
+                    length: 784
+                },
+            ]
+        );
+        assert_eq!(cp.completed_bytes(), 3856);
+        assert_eq!(cp.chunk_offsets(4096), vec![(2048, 4095), (5120, 9215)]);
+    }
+
+    #[test]
+    fn test_parse_little_endian_version_0() {
+        let mut data = vec![0, 0];
+        data.extend(&0u32.to_le_bytes());
+        data.extend(&20u32.to_le_bytes());
+        data.extend(&[0xab; 20]); // a torrent's info hash
+        data.extend(&1024u32.to_le_bytes());
+        data.extend(&2048u64.to_le_bytes());
+        data.extend(&0u64.to_le_bytes());
+        data.extend(&1u32.to_le_bytes());
+        data.push(0b1000_0000);
+        let control = ControlFile::parse(&data).unwrap();
+        assert_eq!((control.piece_length, control.total_length), (1024, 2048));
+        assert_eq!(control.bitfield, vec![0b1000_0000]);
+    }
+
+    #[test]
+    fn test_reject_bad_control_files() {
+        let mut data = sample();
+        data[1] = 2;
+        assert!(ControlFile::parse(&data).is_err());
+        assert!(ControlFile::parse(&sample()[..30]).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use chrono::Utc;
+use failure::{format_err, Fallible};
+use serde::{Deserialize, Serialize};
+
+use crate::core::EventsHandler;
+use crate::sha256::{parse_hex_digest, to_hex, Sha256};
+
+/// One line of the audit log.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditRecord {
+    timestamp: String,
+    offset: u64,
+    length: u64,
+    sha256: String,
+}
+
+/// Appends an NDJSON record to a file for every piece reported by
+/// `on_data_verified`, as an audit trail of what was received intact.
+///
+/// The records are chained: the `sha256` of each is the SHA-256 of the
+/// previous record's `sha256` followed by the piece's own hash, starting
+/// from 32 zero bytes. Dropping or altering a record breaks every one
+/// after it. A log that already has records is appended to, going on
+/// from the last of them.
+pub struct AuditLogHandler {
+    file: File,
+    chain: [u8; 32],
+    /// The first write that failed, reported by `on_finish` since
+    /// `on_data_verified` can't return it.
+    error: Option<failure::Error>,
+}
+
+impl AuditLogHandler {
+    pub fn new<P: AsRef<Path>>(path: P) -> Fallible<AuditLogHandler> {
+        let path = path.as_ref();
+        let chain = last_chain_link(path)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLogHandler {
+            file,
+            chain,
+            error: None,
+        })
+    }
+
+    fn record(&mut self, offset: u64, length: u64, hash: [u8; 32]) -> Fallible<()> {
+        let mut sha = Sha256::new();
+        sha.update(&self.chain);
+        sha.update(&hash);
+        self.chain = sha.finish();
+        let record = AuditRecord {
+            timestamp: Utc::now().to_rfc3339(),
+            offset,
+            length,
+            sha256: to_hex(&self.chain),
+        };
+        writeln!(self.file, "{}", serde_json::to_string(&record)?)?;
+        Ok(())
+    }
+}
+
+/// The `sha256` of the last record of the log at `path`, or 32 zero bytes
+/// for a log that doesn't exist yet or is empty.
+fn last_chain_link(path: &Path) -> Fallible<[u8; 32]> {
+    let log = match fs::read_to_string(path) {
+        Ok(log) => log,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok([0; 32]),
+        Err(e) => return Err(e.into()),
+    };
+    match log.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => {
+            let record: AuditRecord = serde_json::from_str(line)
+                .map_err(|e| format_err!("{}: last record is not valid: {}", path.display(), e))?;
+            parse_hex_digest(&record.sha256)
+        }
+        None => Ok([0; 32]),
+    }
+}
+
+impl EventsHandler for AuditLogHandler {
+    fn on_data_verified(&mut self, offset: u64, length: u64, hash: [u8; 32]) {
+        if self.error.is_none() {
+            if let Err(e) = self.record(offset, length, hash) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn on_finish(&mut self) -> Fallible<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(self.file.sync_all()?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        let mut sha = Sha256::new();
+        sha.update(data);
+        sha.finish()
+    }
+
+    #[test]
+    fn test_records_are_chained() {
+        let path = std::env::temp_dir().join(format!("duma-audit-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut handler = AuditLogHandler::new(&path).unwrap();
+        handler.on_data_verified(4, 4, sha256(b"more"));
+        handler.on_data_verified(0, 4, sha256(b"data"));
+        handler.on_finish().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        let mut chain = [0; 32];
+        for (record, (offset, piece)) in records.iter().zip(&[(4, b"more"), (0, b"data")]) {
+            let mut link = chain.to_vec();
+            link.extend_from_slice(&sha256(*piece));
+            chain = sha256(&link);
+            assert_eq!(record["offset"], *offset);
+            assert_eq!(record["length"], 4);
+            assert_eq!(record["sha256"], to_hex(&chain));
+            assert!(record["timestamp"].as_str().unwrap().contains('T'));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_appending_continues_the_chain() {
+        let path =
+            std::env::temp_dir().join(format!("duma-audit-append-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut handler = AuditLogHandler::new(&path).unwrap();
+        handler.on_data_verified(0, 4, sha256(b"data"));
+        handler.on_finish().unwrap();
+        drop(handler);
+        let mut handler = AuditLogHandler::new(&path).unwrap();
+        handler.on_data_verified(4, 4, sha256(b"more"));
+        handler.on_finish().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        let first = parse_hex_digest(records[0]["sha256"].as_str().unwrap()).unwrap();
+        let mut link = first.to_vec();
+        link.extend_from_slice(&sha256(b"more"));
+        assert_eq!(records[1]["sha256"], to_hex(&sha256(&link)));
+
+        std::fs::write(&path, "not json\n").unwrap();
+        assert!(AuditLogHandler::new(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use std::fs;
+use std::path::Path;
+
+use failure::{format_err, Fallible};
+use reqwest::header::HeaderValue;
+use serde::Deserialize;
+use url::Url;
+
+/// Credentials sent in the `Authorization` header.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AuthScheme {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+impl AuthScheme {
+    pub fn header_value(&self) -> Fallible<HeaderValue> {
+        let value = match self {
+            AuthScheme::Basic { username, password } => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            ),
+            AuthScheme::Bearer { token } => format!("Bearer {}", token),
+        };
+        let mut value = HeaderValue::from_str(&value)?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+/// A host pattern, optionally followed by a path pattern, e.g.
+/// `*.example.com` or `files.example.com/private/*`.
+///
+/// `*` matches any part of a single host label or path segment. A trailing
+/// `*` path segment also matches everything below it, and a pattern without
+/// a path matches every path on the host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UrlPattern {
+    host: Vec<String>,
+    path: Option<Vec<String>>,
+}
+
+impl UrlPattern {
+    pub fn parse(pattern: &str) -> Fallible<UrlPattern> {
+        let (host, path) = match pattern.find('/') {
+            Some(idx) => (&pattern[..idx], Some(&pattern[idx + 1..])),
+            None => (pattern, None),
+        };
+        if host.is_empty() {
+            return Err(format_err!("credential pattern has no host: {}", pattern));
+        }
+        Ok(UrlPattern {
+            host: host.split('.').map(str::to_lowercase).collect(),
+            path: path.map(|path| path.split('/').map(str::to_owned).collect()),
+        })
+    }
+
+    pub fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+        let labels: Vec<&str> = host.split('.').collect();
+        if labels.len() != self.host.len()
+            || !self
+                .host
+                .iter()
+                .zip(&labels)
+                .all(|(pat, label)| glob_match(pat, label))
+        {
+            return false;
+        }
+        let pattern = match self.path {
+            Some(ref pattern) => pattern,
+            None => return true,
+        };
+        let segments: Vec<&str> = url.path().trim_start_matches('/').split('/').collect();
+        for (idx, pat) in pattern.iter().enumerate() {
+            let is_last = idx + 1 == pattern.len();
+            match segments.get(idx) {
+                _ if is_last && pat == "*" => return true,
+                Some(segment) if glob_match(pat, segment) => {}
+                _ => return false,
+            }
+        }
+        segments.len() == pattern.len()
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` stands for any run of
+/// characters.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.last() {
+        Some(last) => *last,
+        None => return rest.is_empty(),
+    };
+    for part in &parts[..parts.len() - 1] {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Credentials for several servers, tried in the order of the file.
+#[derive(Debug, Default)]
+pub struct DigestCredentialStore {
+    pub entries: Vec<(UrlPattern, AuthScheme)>,
+}
+
+#[derive(Deserialize)]
+struct CredentialsFile {
+    #[serde(default)]
+    credential: Vec<CredentialEntry>,
+}
+
+#[derive(Deserialize)]
+struct CredentialEntry {
+    pattern: String,
+    #[serde(flatten)]
+    scheme: AuthScheme,
+}
+
+impl DigestCredentialStore {
+    /// Parses a TOML list of `[[credential]]` tables, each with a `pattern`
+    /// and a `type` of `basic` (`username`, `password`) or `bearer`
+    /// (`token`).
+    pub fn from_toml(contents: &str) -> Fallible<DigestCredentialStore> {
+        let file: CredentialsFile = toml::from_str(contents)?;
+        let mut entries = Vec::with_capacity(file.credential.len());
+        for entry in file.credential {
+            entries.push((UrlPattern::parse(&entry.pattern)?, entry.scheme));
+        }
+        Ok(DigestCredentialStore { entries })
+    }
+
+    pub fn load(path: &Path) -> Fallible<DigestCredentialStore> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format_err!("failed to read credentials {}: {}", path.display(), e))?;
+        DigestCredentialStore::from_toml(&contents)
+            .map_err(|e| format_err!("invalid credentials {}: {}", path.display(), e))
+    }
+}
+
+/// Returns the credential of the first pattern matching `url`.
+pub fn find_credential<'a>(store: &'a DigestCredentialStore, url: &Url) -> Option<&'a AuthScheme> {
+    store
+        .entries
+        .iter()
+        .find(|(pattern, _)| pattern.matches(url))
+        .map(|(_, scheme)| scheme)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, url: &str) -> bool {
+        UrlPattern::parse(pattern)
+            .unwrap()
+            .matches(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_host_patterns() {
+        assert!(matches("example.com", "https://example.com/a/b"));
+        assert!(matches("*.example.com", "https://files.Example.com/"));
+        assert!(matches(
+            "mirror-*.example.com",
+            "http://mirror-eu.example.com/"
+        ));
+        assert!(!matches("*.example.com", "https://example.com/"));
+        assert!(!matches("*.example.com", "https://a.b.example.com/"));
+        assert!(!matches("example.com", "https://example.org/"));
+    }
+
+    #[test]
+    fn test_path_patterns() {
+        assert!(matches(
+            "example.com/private/*",
+            "https://example.com/private/a/b.iso"
+        ));
+        assert!(matches(
+            "example.com/*/file.txt",
+            "https://example.com/v1/file.txt"
+        ));
+        assert!(matches(
+            "example.com/*.iso",
+            "https://example.com/debian.iso"
+        ));
+        assert!(!matches(
+            "example.com/private/*",
+            "https://example.com/public/a"
+        ));
+        assert!(!matches(
+            "example.com/*.iso",
+            "https://example.com/a/debian.iso"
+        ));
+    }
+
+    #[test]
+    fn test_first_matching_credential_wins() {
+        let store = DigestCredentialStore::from_toml(
+            r#"
+            [[credential]]
+            pattern = "files.example.com/private/*"
+            type = "bearer"
+            token = "abc"
+
+            [[credential]]
+            pattern = "*.example.com"
+            type = "basic"
+            username = "user"
+            password = "pass"
+            "#,
+        )
+        .unwrap();
+        let url = Url::parse("https://files.example.com/private/a.iso").unwrap();
+        assert_eq!(
+            find_credential(&store, &url),
+            Some(&AuthScheme::Bearer {
+                token: "abc".to_owned()
+            })
+        );
+        let url = Url::parse("https://files.example.com/a.iso").unwrap();
+        assert_eq!(
+            find_credential(&store, &url)
+                .unwrap()
+                .header_value()
+                .unwrap(),
+            "Basic dXNlcjpwYXNz"
+        );
+        let url = Url::parse("https://example.org/a.iso").unwrap();
+        assert_eq!(find_credential(&store, &url), None);
+    }
+
+    #[test]
+    fn test_unknown_auth_type_is_rejected() {
+        let toml = "[[credential]]\npattern = \"example.com\"\ntype = \"ntlm\"\n";
+        assert!(DigestCredentialStore::from_toml(toml).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Fallible;
+use url::Url;
+
+use crate::core::{DownloadStats, EventsHandler};
+
+/// How many of a host's latest records its average speed is taken over,
+/// and how many of them are kept.
+const RECENT_RECORDS: usize = 10;
+
+/// Past download speeds per host, one `host timestamp bytes_per_sec` line
+/// per finished download, for picking the fastest of several mirrors.
+/// Only the latest records of each host are kept.
+pub struct BandwidthEstimator {
+    path: PathBuf,
+}
+
+impl BandwidthEstimator {
+    pub fn new(path: PathBuf) -> BandwidthEstimator {
+        BandwidthEstimator { path }
+    }
+
+    /// `bandwidth_history` in `$DUMA_DATA_DIR` if set, or else in the user
+    /// data directory, e.g. `~/.local/share/duma` on Linux.
+    pub fn default_path() -> Option<PathBuf> {
+        env::var_os("DUMA_DATA_DIR")
+            .map(PathBuf::from)
+            .or_else(|| dirs::data_dir().map(|dir| dir.join("duma")))
+            .map(|dir| dir.join("bandwidth_history"))
+    }
+
+    /// Adds a record for `host`, dropping its records that are no longer
+    /// among the latest.
+    pub fn record(&self, host: &str, bytes_per_sec: f64) -> Fallible<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let history = self.read()?;
+        let record = format!("{} {} {}", host, timestamp, bytes_per_sec);
+        let mut own = 0;
+        // the latest records of `host` and all of the others, walking back
+        // from the new one
+        let mut kept: Vec<&str> = std::iter::once(record.as_str())
+            .chain(history.lines().rev())
+            .filter(|line| {
+                if line.split_whitespace().next() != Some(host) {
+                    return true;
+                }
+                own += 1;
+                own <= RECENT_RECORDS
+            })
+            .collect();
+        kept.reverse();
+        // written aside and renamed so a reader never sees half a file
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, kept.join("\n") + "\n")?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    fn read(&self) -> Fallible<String> {
+        match fs::read_to_string(&self.path) {
+            Ok(history) => Ok(history),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The average speed of each host over its latest records, with the
+    /// number of records it is based on.
+    pub fn averages(&self) -> Fallible<HashMap<String, (f64, usize)>> {
+        let history = self.read()?;
+        let mut speeds: HashMap<String, Vec<f64>> = HashMap::new();
+        for line in history.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // a torn last line from an interrupted write is skipped
+            if let [host, _, speed] = fields[..] {
+                if let Ok(speed) = speed.parse::<f64>() {
+                    speeds.entry(host.to_owned()).or_default().push(speed);
+                }
+            }
+        }
+        Ok(speeds
+            .into_iter()
+            .map(|(host, speeds)| {
+                let recent = &speeds[speeds.len().saturating_sub(RECENT_RECORDS)..];
+                let avg = recent.iter().sum::<f64>() / recent.len() as f64;
+                (host, (avg, recent.len()))
+            })
+            .collect())
+    }
+
+    /// The host in `hosts` with the best recent average speed, or `None`
+    /// if none of them has been downloaded from yet.
+    pub fn get_fastest_mirror<'a>(&self, hosts: &[&'a str]) -> Option<&'a str> {
+        let averages = self.averages().ok()?;
+        hosts
+            .iter()
+            .filter_map(|&host| averages.get(host).map(|&(avg, _)| (host, avg)))
+            .fold(None, |best: Option<(&str, f64)>, (host, avg)| match best {
+                Some((_, best_avg)) if best_avg >= avg => best,
+                _ => Some((host, avg)),
+            })
+            .map(|(host, _)| host)
+    }
+
+    pub fn clear(&self) -> Fallible<()> {
+        match fs::remove_file(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+/// Adds the speed of each finished download to a `BandwidthEstimator`.
+pub struct BandwidthRecorder {
+    estimator: BandwidthEstimator,
+}
+
+impl BandwidthRecorder {
+    pub fn new(estimator: BandwidthEstimator) -> BandwidthRecorder {
+        BandwidthRecorder { estimator }
+    }
+}
+
+impl EventsHandler for BandwidthRecorder {
+    fn on_finish_with_stats(&mut self, stats: &DownloadStats) -> Fallible<()> {
+        let host = Url::parse(&stats.url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_owned()));
+        // a resumed download only timed part of the file
+        if let Some(host) = host {
+            if !stats.resumed && stats.avg_speed() > 0.0 {
+                // the history is a nicety, the download itself succeeded
+                let _ = self.estimator.record(&host, stats.avg_speed());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimator(name: &str) -> BandwidthEstimator {
+        let path =
+            std::env::temp_dir().join(format!("duma-bandwidth-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        BandwidthEstimator::new(path)
+    }
+
+    #[test]
+    fn test_fastest_mirror_by_recent_average() {
+        let estimator = estimator("fastest");
+        estimator.record("a.example.com", 100.0).unwrap();
+        estimator.record("a.example.com", 300.0).unwrap();
+        estimator.record("b.example.com", 250.0).unwrap();
+        let averages = estimator.averages().unwrap();
+        assert_eq!(averages["a.example.com"], (200.0, 2));
+        assert_eq!(
+            estimator.get_fastest_mirror(&["a.example.com", "b.example.com", "c.example.com"]),
+            Some("b.example.com")
+        );
+        assert_eq!(estimator.get_fastest_mirror(&["c.example.com"]), None);
+        estimator.clear().unwrap();
+        assert!(estimator.averages().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_average_uses_latest_records() {
+        let estimator = estimator("latest");
+        estimator.record("a.example.com", 1000.0).unwrap();
+        for _ in 0..RECENT_RECORDS {
+            estimator.record("a.example.com", 10.0).unwrap();
+        }
+        assert_eq!(
+            estimator.averages().unwrap()["a.example.com"],
+            (10.0, RECENT_RECORDS)
+        );
+        estimator.clear().unwrap();
+    }
+
+    #[test]
+    fn test_record_keeps_latest_records() {
+        let estimator = estimator("capped");
+        for speed in 0..RECENT_RECORDS + 5 {
+            estimator.record("a.example.com", speed as f64).unwrap();
+            estimator.record("b.example.com", 1.0).unwrap();
+        }
+        let history = fs::read_to_string(&estimator.path).unwrap();
+        assert_eq!(history.lines().count(), 2 * RECENT_RECORDS);
+        assert!(history.lines().next().unwrap().ends_with(" 5"));
+        assert!(history.ends_with(" 1\n"));
+        estimator.clear().unwrap();
+    }
+}
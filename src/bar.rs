@@ -1,16 +1,65 @@
-use indicatif::{ProgressBar, ProgressStyle};
+use std::io::{self, Write};
 
-static PBAR_FMT: &'static str =
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use terminal_size::{terminal_size, Width};
+
+static PBAR_FMT: &str =
     "{msg} {spinner:.green} {percent}% [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} eta: {eta}";
+static ASCII_PBAR_FMT: &str = "{msg} {percent}% [{wide_bar}] {bytes}/{total_bytes}";
+const MAX_NAME_WIDTH: usize = 40;
+
+/// Shortens `name` to at most `max_width` characters by replacing its
+/// beginning with `...`, keeping the file name at the end readable.
+pub fn truncate_display_name(name: &str, max_width: usize) -> String {
+    let len = name.chars().count();
+    if len <= max_width {
+        return name.to_owned();
+    }
+    let keep = max_width.saturating_sub(3);
+    let tail: String = name.chars().skip(len - keep).collect();
+    format!("...{}", tail)
+}
+
+fn max_name_width() -> usize {
+    match terminal_size() {
+        Some((Width(width), _)) => MAX_NAME_WIDTH.min(width as usize / 3),
+        None => MAX_NAME_WIDTH,
+    }
+}
+
+/// The draw target for `--progress-fd`: stdout for 1, otherwise stderr,
+/// which other descriptors have already been duplicated onto.
+pub fn progress_draw_target(fd: i32) -> ProgressDrawTarget {
+    if fd == 1 {
+        ProgressDrawTarget::stdout()
+    } else {
+        ProgressDrawTarget::stderr()
+    }
+}
 
-pub fn create_progress_bar(msg: &str, length: Option<u64>) -> ProgressBar {
+pub fn create_progress_bar(
+    path: &str,
+    length: Option<u64>,
+    ascii_progress: bool,
+    target: ProgressDrawTarget,
+) -> ProgressBar {
     let progbar = match length {
-        Some(len) => ProgressBar::new(len),
-        None => ProgressBar::new_spinner(),
+        Some(len) => ProgressBar::with_draw_target(len, target),
+        None => {
+            let spinner = ProgressBar::new_spinner();
+            spinner.set_draw_target(target);
+            spinner
+        }
     };
 
-    progbar.set_message(msg);
-    if length.is_some() {
+    progbar.set_message(&truncate_display_name(path, max_name_width()));
+    if length.is_some() && ascii_progress {
+        progbar.set_style(
+            ProgressStyle::default_bar()
+                .template(ASCII_PBAR_FMT)
+                .progress_chars("#>-"),
+        );
+    } else if length.is_some() {
         progbar.set_style(
             ProgressStyle::default_bar()
                 .template(PBAR_FMT)
@@ -22,3 +71,116 @@ pub fn create_progress_bar(msg: &str, length: Option<u64>) -> ProgressBar {
 
     progbar
 }
+
+/// Mirrors the download's progress in the terminal's tab or taskbar with
+/// the OSC 9;4 sequences understood by Windows Terminal and ConEmu. The
+/// indicator is cleared when this is dropped, on success or failure.
+pub struct TermProgress<W: Write> {
+    out: W,
+    total: Option<u64>,
+    done: u64,
+    percent: Option<u64>,
+}
+
+impl TermProgress<io::Stderr> {
+    pub fn stderr() -> Self {
+        TermProgress::new(io::stderr())
+    }
+}
+
+impl<W: Write> TermProgress<W> {
+    pub fn new(out: W) -> Self {
+        TermProgress {
+            out,
+            total: None,
+            done: 0,
+            percent: None,
+        }
+    }
+
+    /// Starts the indicator at `done` bytes of `total`, or as a busy
+    /// indicator when the length is unknown.
+    pub fn start(&mut self, total: Option<u64>, done: u64) {
+        self.total = total;
+        self.done = 0;
+        if total.is_none() {
+            self.emit("3;0");
+        }
+        self.inc(done);
+    }
+
+    /// Counts `bytes` more as done, redrawing only when the percentage
+    /// changes.
+    pub fn inc(&mut self, bytes: u64) {
+        self.done += bytes;
+        let total = match self.total {
+            Some(total) => total,
+            None => return,
+        };
+        let percent = (self.done.min(total) * 100)
+            .checked_div(total)
+            .unwrap_or(100);
+        if self.percent != Some(percent) {
+            self.percent = Some(percent);
+            self.emit(&format!("1;{}", percent));
+        }
+    }
+
+    fn emit(&mut self, state: &str) {
+        // best effort: a closed stderr shouldn't fail the download
+        let _ = write!(self.out, "\x1b]9;4;{}\x07", state);
+        let _ = self.out.flush();
+    }
+}
+
+impl<W: Write> Drop for TermProgress<W> {
+    fn drop(&mut self) {
+        self.emit("0;0");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_names_are_kept() {
+        assert_eq!(truncate_display_name("foo.txt", 40), "foo.txt");
+        assert_eq!(truncate_display_name("foo.txt", 7), "foo.txt");
+    }
+
+    #[test]
+    fn test_long_names_keep_their_end() {
+        assert_eq!(
+            truncate_display_name(
+                "/home/user/very/deep/directory/structure/filename.tar.gz",
+                28
+            ),
+            "...structure/filename.tar.gz"
+        );
+        assert_eq!(truncate_display_name("héllo-wörld.txt", 8), "...d.txt");
+    }
+
+    #[test]
+    fn test_term_progress_sequences() {
+        let mut out = Vec::new();
+        {
+            let mut progress = TermProgress::new(&mut out);
+            progress.start(Some(200), 100);
+            progress.inc(1);
+            progress.inc(1);
+            progress.inc(98);
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b]9;4;1;50\x07\x1b]9;4;1;51\x07\x1b]9;4;1;100\x07\x1b]9;4;0;0\x07"
+        );
+
+        let mut out = Vec::new();
+        TermProgress::new(&mut out).start(None, 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b]9;4;3;0\x07\x1b]9;4;0;0\x07"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use failure::{bail, format_err, Fallible};
+use serde::{Deserialize, Serialize};
+
+use crate::core::missing_chunk_offsets;
+use crate::download::DefaultEventsHandler;
+
+/// A byte range of the output file that is already on disk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Human readable download state, the portable counterpart of the `.st`
+/// file: it can be moved to another machine along with the partial file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointData {
+    pub url: String,
+    pub file: String,
+    pub total_bytes: u64,
+    pub chunks_completed: Vec<ChunkRange>,
+}
+
+impl CheckpointData {
+    pub fn new(url: &str, file: &str) -> CheckpointData {
+        CheckpointData {
+            url: url.to_owned(),
+            file: file.to_owned(),
+            total_bytes: 0,
+            chunks_completed: Vec::new(),
+        }
+    }
+
+    pub fn completed_bytes(&self) -> u64 {
+        self.chunks_completed.iter().map(|chunk| chunk.length).sum()
+    }
+
+    /// Records `length` bytes written at `offset`, growing the range it
+    /// continues so pieces of one chunk don't pile up.
+    pub fn add_chunk(&mut self, offset: u64, length: u64) {
+        let continued = self
+            .chunks_completed
+            .iter_mut()
+            .rev()
+            .find(|chunk| chunk.offset + chunk.length == offset);
+        match continued {
+            Some(chunk) => chunk.length += length,
+            None => self.chunks_completed.push(ChunkRange { offset, length }),
+        }
+    }
+
+    /// The `(start, end)` ranges still to fetch, as `Config::chunk_offsets`
+    /// expects them.
+    pub fn chunk_offsets(&self, chunk_size: u64) -> Vec<(u64, u64)> {
+        let downloaded = self
+            .chunks_completed
+            .iter()
+            .map(|chunk| (chunk.length, chunk.offset))
+            .collect();
+        missing_chunk_offsets(downloaded, self.total_bytes, chunk_size)
+    }
+
+    /// Writes the completed ranges to the `.st` state file at `path`, so
+    /// that a later `--continue` knows about them too.
+    pub fn write_state_file(&self, path: &str) -> Fallible<()> {
+        let mut st_file = fs::File::create(path)?;
+        for chunk in &self.chunks_completed {
+            writeln!(st_file, "{}:{}", chunk.length, chunk.offset)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn load_checkpoint(path: &Path) -> Fallible<CheckpointData> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format_err!("failed to read checkpoint {}: {}", path.display(), e))?;
+    let data: CheckpointData = serde_json::from_str(&contents)
+        .map_err(|e| format_err!("invalid checkpoint {}: {}", path.display(), e))?;
+    for chunk in &data.chunks_completed {
+        if chunk.offset + chunk.length > data.total_bytes {
+            bail!(
+                "checkpoint {} has a chunk past the end of the file",
+                path.display()
+            );
+        }
+    }
+    Ok(data)
+}
+
+/// Writes the handler's checkpoint to `path`, replacing the old one in a
+/// single rename so a crash never leaves half a file behind.
+pub fn save_checkpoint(handler: &DefaultEventsHandler, path: &Path) -> Fallible<()> {
+    let data = match handler.checkpoint_data() {
+        Some(data) => data,
+        None => return Ok(()),
+    };
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_string_pretty(data)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_chunk_merges_continuations() {
+        let mut data = CheckpointData::new("http://example.com/foo.txt", "foo.txt");
+        data.add_chunk(0, 100);
+        data.add_chunk(500, 100);
+        data.add_chunk(100, 50);
+        data.add_chunk(600, 10);
+        assert_eq!(
+            data.chunks_completed,
+            vec![
+                ChunkRange {
+                    offset: 0,
+                    length: 150
+                },
+                ChunkRange {
+                    offset: 500,
+                    length: 110
+                },
+            ]
+        );
+        assert_eq!(data.completed_bytes(), 260);
+    }
+
+    #[test]
+    fn test_chunk_offsets_skip_completed_ranges() {
+        let data: CheckpointData = serde_json::from_str(
+            r#"{
+                "url": "http://example.com/foo.txt",
+                "file": "foo.txt",
+                "total_bytes": 1000,
+                "chunks_completed": [
+                    {"offset": 200, "length": 100},
+                    {"offset": 0, "length": 100}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            data.chunk_offsets(300),
+            vec![(100, 199), (300, 599), (600, 899), (900, 999)]
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_chunks_past_the_end() {
+        let path =
+            std::env::temp_dir().join(format!("duma-checkpoint-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"url": "u", "file": "f", "total_bytes": 10,
+                "chunks_completed": [{"offset": 5, "length": 6}]}"#,
+        )
+        .unwrap();
+        assert!(load_checkpoint(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+#[cfg(feature = "clipboard")]
+use std::io::Write;
+#[cfg(feature = "clipboard")]
+use std::process::{Command, Stdio};
+
+#[cfg(not(feature = "clipboard"))]
+use failure::bail;
+use failure::Fallible;
+#[cfg(feature = "clipboard")]
+use failure::{bail, format_err};
+
+use crate::magic::detect_extension;
+
+/// Downloads larger than this are not copied to the clipboard.
+pub const MAX_CLIPBOARD_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Returns the MIME type the clipboard should offer `content` as: text when
+/// it is valid UTF-8, otherwise one of the image types we can sniff.
+pub fn clipboard_mime(content: &[u8]) -> Option<&'static str> {
+    if std::str::from_utf8(content).is_ok() {
+        return Some("text/plain;charset=utf-8");
+    }
+    match detect_extension(content) {
+        Some("png") => Some("image/png"),
+        Some("jpg") => Some("image/jpeg"),
+        Some("gif") => Some("image/gif"),
+        _ => None,
+    }
+}
+
+/// Places `content` on the system clipboard through the platform's
+/// clipboard tool. Fails without a tool or a display to talk to, e.g. on a
+/// headless server.
+#[cfg(feature = "clipboard")]
+pub fn copy_to_clipboard(content: &[u8]) -> Fallible<()> {
+    let mime = match clipboard_mime(content) {
+        Some(mime) => mime,
+        None => bail!("only text and images can be copied to the clipboard"),
+    };
+    let mut last_err = None;
+    for (program, args) in clipboard_commands(mime) {
+        match pipe_to(program, &args, content) {
+            Ok(()) => return Ok(()),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| format_err!("no clipboard tool supports {}", mime)))
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn copy_to_clipboard(_content: &[u8]) -> Fallible<()> {
+    bail!("duma was built without the clipboard feature")
+}
+
+#[cfg(all(feature = "clipboard", target_os = "macos"))]
+fn clipboard_commands(mime: &str) -> Vec<(&'static str, Vec<&str>)> {
+    if mime.starts_with("text/") {
+        vec![("pbcopy", vec![])]
+    } else {
+        vec![]
+    }
+}
+
+#[cfg(all(feature = "clipboard", windows))]
+fn clipboard_commands(mime: &str) -> Vec<(&'static str, Vec<&str>)> {
+    if mime.starts_with("text/") {
+        vec![("clip", vec![])]
+    } else {
+        vec![]
+    }
+}
+
+#[cfg(all(feature = "clipboard", not(any(target_os = "macos", windows))))]
+fn clipboard_commands(mime: &str) -> Vec<(&'static str, Vec<&str>)> {
+    let mut commands = vec![
+        ("wl-copy", vec!["--type", mime]),
+        ("xclip", vec!["-selection", "clipboard", "-t", mime]),
+    ];
+    if mime.starts_with("text/") {
+        commands.push(("xsel", vec!["--clipboard", "--input"]));
+    }
+    commands
+}
+
+#[cfg(feature = "clipboard")]
+fn pipe_to(program: &str, args: &[&str], content: &[u8]) -> Fallible<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format_err!("failed to run {}: {}", program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(content)?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("{} exited with {}", program, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clipboard_mime() {
+        assert_eq!(
+            clipboard_mime("héllo".as_bytes()),
+            Some("text/plain;charset=utf-8")
+        );
+        assert_eq!(
+            clipboard_mime(b"\x89PNG\r\n\x1a\n\xff\x00"),
+            Some("image/png")
+        );
+        assert_eq!(clipboard_mime(b"\x7fELF\xff\xfe"), None);
+    }
+}
@@ -0,0 +1,195 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use failure::{format_err, Fallible};
+use serde::Deserialize;
+
+/// Defaults for command line flags, read from a TOML file.
+///
+/// Keys are the long flag names, e.g. `num_connections = 4` or
+/// `useragent = "MyBot/1.0"`.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    pub useragent: Option<String>,
+    pub timeout: Option<u64>,
+    pub num_connections: Option<usize>,
+    pub max_conns_per_host: Option<usize>,
+    pub chunk_size: Option<String>,
+    pub resume_min_parallel: Option<String>,
+    pub max_time: Option<u64>,
+    pub interface: Option<String>,
+    #[serde(rename = "continue")]
+    pub resume: Option<bool>,
+    pub singlethread: Option<bool>,
+    pub quiet: Option<bool>,
+    pub verbose: Option<bool>,
+    pub retry_connrefused: Option<bool>,
+    pub detect_type: Option<bool>,
+    pub content_md5_verify: Option<bool>,
+    pub to_downloads: Option<bool>,
+    pub no_progress: Option<bool>,
+    pub ascii_progress: Option<bool>,
+    pub compressed: Option<bool>,
+    pub auto_connections: Option<bool>,
+    pub save_to_clipboard: Option<bool>,
+}
+
+impl UserConfig {
+    /// Returns the command line arguments for every setting whose flag
+    /// `is_present` reports as missing, so that flags given on the command
+    /// line take precedence over the file.
+    pub fn default_args<F>(&self, is_present: F) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let options = [
+            ("AGENT", "--useragent", self.useragent.clone()),
+            ("SECONDS", "--timeout", self.timeout.map(|v| v.to_string())),
+            (
+                "NUM_CONNECTIONS",
+                "--num_connections",
+                self.num_connections.map(|v| v.to_string()),
+            ),
+            (
+                "MAX_CONNS_PER_HOST",
+                "--max-conns-per-host",
+                self.max_conns_per_host.map(|v| v.to_string()),
+            ),
+            ("CHUNK_SIZE", "--chunk-size", self.chunk_size.clone()),
+            (
+                "RESUME_MIN_PARALLEL",
+                "--resume-min-parallel",
+                self.resume_min_parallel.clone(),
+            ),
+            (
+                "MAX_TIME",
+                "--max-time",
+                self.max_time.map(|v| v.to_string()),
+            ),
+            ("IFNAME", "--interface", self.interface.clone()),
+        ];
+        let flags = [
+            ("continue", "--continue", self.resume),
+            ("singlethread", "--singlethread", self.singlethread),
+            ("quiet", "--quiet", self.quiet),
+            ("verbose", "--verbose", self.verbose),
+            (
+                "retry_connrefused",
+                "--retry-connrefused",
+                self.retry_connrefused,
+            ),
+            ("detect_type", "--detect-type", self.detect_type),
+            (
+                "content_md5_verify",
+                "--content-md5-verify",
+                self.content_md5_verify,
+            ),
+            ("to_downloads", "--to-downloads", self.to_downloads),
+            ("no_progress", "--no-progress", self.no_progress),
+            ("ascii_progress", "--ascii-progress", self.ascii_progress),
+            ("compressed", "--compressed", self.compressed),
+            (
+                "auto_connections",
+                "--auto-connections",
+                self.auto_connections,
+            ),
+        ];
+
+        let mut args = Vec::new();
+        for (name, flag, value) in options.iter() {
+            if let Some(value) = value {
+                if !is_present(name) {
+                    args.push((*flag).to_owned());
+                    args.push(value.clone());
+                }
+            }
+        }
+        for (name, flag, value) in flags.iter() {
+            if *value == Some(true) && !is_present(name) {
+                args.push((*flag).to_owned());
+            }
+        }
+        args
+    }
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("duma").join("config.toml"))
+}
+
+/// Loads the user configuration from `path`, or from `duma/config.toml` in
+/// the user's config directory when no path is given. A missing default
+/// file is not an error.
+pub fn load_user_config(path: Option<&Path>) -> Fallible<UserConfig> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_config_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(UserConfig::default()),
+        },
+    };
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| format_err!("failed to read config {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format_err!("invalid config {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_user_config() {
+        let conf: UserConfig = toml::from_str(
+            r#"
+            useragent = "MyBot/1.0"
+            timeout = 60
+            num_connections = 4
+            continue = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.useragent.as_deref(), Some("MyBot/1.0"));
+        assert_eq!(conf.timeout, Some(60));
+        assert_eq!(conf.num_connections, Some(4));
+        assert_eq!(conf.resume, Some(true));
+        assert_eq!(conf.quiet, None);
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(toml::from_str::<UserConfig>("threads = 4").is_err());
+    }
+
+    #[test]
+    fn test_config_fills_missing_flags() {
+        let conf = UserConfig {
+            timeout: Some(60),
+            num_connections: Some(4),
+            quiet: Some(true),
+            singlethread: Some(false),
+            ..UserConfig::default()
+        };
+        assert_eq!(
+            conf.default_args(|_| false),
+            vec!["--timeout", "60", "--num_connections", "4", "--quiet"]
+        );
+    }
+
+    #[test]
+    fn test_command_line_takes_precedence() {
+        let conf = UserConfig {
+            useragent: Some("MyBot/1.0".to_owned()),
+            timeout: Some(60),
+            quiet: Some(true),
+            ..UserConfig::default()
+        };
+        let args = conf.default_args(|name| name == "SECONDS" || name == "quiet");
+        assert_eq!(args, vec!["--useragent", "MyBot/1.0"]);
+    }
+
+    #[test]
+    fn test_missing_explicit_config_is_an_error() {
+        assert!(load_user_config(Some(Path::new("/nonexistent/duma.toml"))).is_err());
+    }
+}
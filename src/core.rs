@@ -1,18 +1,54 @@
 use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::error::Error as StdError;
 use std::fmt;
-use std::io::Read;
-use std::sync::mpsc;
-use std::time::Duration;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use failure::{format_err, Fallible};
-use reqwest::blocking::{Client, Request};
+use failure::{bail, format_err, Fallible};
+use reqwest::blocking::{Body, Client, ClientBuilder, Request, RequestBuilder, Response};
 use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{Method, Proxy, StatusCode};
 use url::Url;
 
 use threadpool::ThreadPool;
 
 use ftp::FtpStream;
 
+use crate::dns::DnsServers;
+use crate::gemini::GeminiDownload;
+use crate::interval::IntervalSet;
+use crate::magic;
+use crate::progress::Progress;
+use crate::sftp::SftpDownload;
+use crate::sha256::Sha256;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::utils::interface_address;
+use crate::utils::{decode_percent_encoded_data, parse_content_range};
+
+/// How long the concurrent loop waits for data before checking on failed
+/// chunks and the deadline again.
+const CHUNK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// First delay before a retry, doubled for each retry after it.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+/// How often `Config::wait_for_server` tries to connect again.
+const SERVER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Up to this fraction of each delay is cut at random.
+const RETRY_JITTER: f64 = 0.5;
+/// Worker counts `auto_connections` picks from.
+const PROBE_CONNECTIONS: [usize; 4] = [1, 2, 4, 8];
+/// Bytes fetched from the start of the file for each probed count.
+const PROBE_SIZE: u64 = 1024 * 1024;
+/// Cap on each probe, so a slow link doesn't hold up the real download.
+const PROBE_TIME_LIMIT: Duration = Duration::from_secs(2);
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub user_agent: String,
@@ -21,24 +57,687 @@ pub struct Config {
     pub file: String,
     pub timeout: u64,
     pub concurrent: bool,
+    /// Total retries allowed across all chunks of a download, or `-1` to
+    /// retry for as long as the failures can be retried at all.
     pub max_retries: i32,
+    /// Retries allowed for any one chunk, `-1` for no limit. A chunk that
+    /// runs out is skipped so the others can finish, and the download fails
+    /// with `DownloadError::PartialDownload`.
+    pub max_retries_per_chunk: i32,
     pub num_workers: usize,
     pub bytes_on_disk: Option<u64>,
     pub chunk_offsets: Option<Vec<(u64, u64)>>,
+    /// Fetch only these inclusive byte ranges, leaving the rest of the
+    /// file untouched.
+    pub ranges: Option<Vec<(u64, u64)>>,
     pub chunk_size: u64,
+    pub detect_type: bool,
+    pub interface: Option<String>,
+    /// Addresses to connect to instead of looking their host up, e.g. from
+    /// `--verify-host`. The port of an address is ignored, the url's is used.
+    pub resolve: Vec<(String, SocketAddr)>,
+    /// Look hosts up on these servers instead of asking the system, see
+    /// `crate::dns::DnsServers`.
+    pub dns_servers: Option<Vec<SocketAddr>>,
+    /// Cap on the whole transfer, on top of the per request `timeout`.
+    pub max_time: Option<Duration>,
+    /// Treat refused connections as transient, e.g. while a server restarts.
+    pub retry_connrefused: bool,
+    /// Ask for a gzip encoded response and save it decoded.
+    pub compressed: bool,
+    /// TOML file with credentials for the urls matching its patterns.
+    pub credentials_file: Option<PathBuf>,
+    /// Probe 1, 2, 4 and 8 connections and use the fastest as
+    /// `num_workers`. The probes download up to 4 MiB on top of the file.
+    pub auto_connections: bool,
+    /// Cap on simultaneous chunk connections to one host, shared by every
+    /// download in the process. The effective parallelism is the smaller of
+    /// this and `num_workers`.
+    pub max_conns_per_host: Option<usize>,
+    /// Length learned out of band, e.g. from WebDAV, for responses that
+    /// don't report a `Content-Length` of their own.
+    pub known_length: Option<u64>,
+    /// Try ranged requests even when the server doesn't send
+    /// `Accept-Ranges: bytes`, falling back to a single request if the
+    /// ranges are ignored.
+    pub force_concurrent: bool,
+    /// Send every request through this proxy instead of the one in the
+    /// `http_proxy` style environment variables.
+    pub proxy: Option<String>,
+    /// User and password for a `proxy` that wants `Proxy-Authorization`.
+    pub proxy_auth: Option<(String, String)>,
+    /// Turn off Nagle's algorithm: small writes such as the range requests
+    /// leave at once rather than being batched, at the cost of more packets.
+    pub tcp_no_delay: bool,
+    /// Send TCP keepalive probes after the connection has been idle this
+    /// long, so that a NAT or firewall doesn't silently drop it during a
+    /// long stall, at the cost of a few extra packets.
+    pub tcp_keepalive: Option<Duration>,
+    /// Refuse responses whose header block is larger than this many bytes,
+    /// see `check_header_size`. Checked once the headers are parsed: hyper
+    /// reads them into a buffer of its own first and gives up by itself on
+    /// blocks over about 400 KiB, so a larger limit has no effect.
+    pub header_size_limit: Option<u64>,
+    /// Keep trying a server that refuses the connection for this long,
+    /// once a second, e.g. while it is still starting up.
+    pub wait_for_server: Option<Duration>,
+    /// Cap on chunk connections shared by every download handed the same
+    /// semaphore, whatever host they go to.
+    pub global_connection_limit: Option<Arc<Semaphore>>,
+    /// Have the default handler read back every Nth piece of a concurrent
+    /// download and fetch it again if the output doesn't hold what was
+    /// written.
+    pub verify_interval: Option<u64>,
+    /// Retries shared with other downloads, on top of `max_retries`. Once
+    /// it runs out a download fails at its first retry.
+    pub retry_budget: Option<Arc<RetryBudget>>,
+    /// How much the default handler writes between syncing the output to
+    /// disk, see `crate::io::ResumableWriter`.
+    pub sync_interval_bytes: u64,
+    /// Point the links of a saved HTML page at the files downloaded with
+    /// it, see `crate::rewrite::LinkConverter`. Done by the command line
+    /// client once the downloads are finished.
+    pub convert_links: bool,
+    /// Size to use in place of the server's `Content-Length`, for the
+    /// progress bar, the chunks and the end of the download, when the
+    /// server is known to get it wrong. A body that ends sooner still ends
+    /// the download when the connection closes.
+    pub expected_size: Option<u64>,
+    /// Also fetch the stylesheets, scripts and images a saved HTML page
+    /// needs, see `crate::spider::extract_page_requisites`. Done by the
+    /// command line client once the page is downloaded.
+    pub page_requisites: bool,
+    /// Also follow the links of a saved HTML page to the files on its host,
+    /// this many links deep, see `crate::spider::extract_links`. Done by
+    /// the command line client once the page is downloaded.
+    pub recursive_level: Option<u32>,
+    /// Save the pages and files fetched with the download under this
+    /// directory as `host/path`, see `crate::rewrite::mirror_path`, in
+    /// place of next to it.
+    pub mirror_dir: Option<PathBuf>,
+    /// Anything but `GET` is sent once and its response saved, without
+    /// the concurrent chunks.
+    pub method: Method,
+    pub request_body: Option<RequestBody>,
+}
+
+/// What is sent as the body of the request, see `Config::request_body`.
+#[derive(Debug, Clone)]
+pub enum RequestBody {
+    Bytes(Vec<u8>),
+    /// Streamed from the file, which is opened again for every attempt.
+    File(PathBuf),
+}
+
+impl RequestBody {
+    fn to_body(&self) -> Body {
+        match self {
+            RequestBody::Bytes(bytes) => Body::from(bytes.clone()),
+            RequestBody::File(path) => {
+                let file = UploadFile {
+                    path: path.clone(),
+                    file: None,
+                };
+                match std::fs::metadata(path) {
+                    Ok(meta) => Body::sized(file, meta.len()),
+                    // the first read fails too and reports why
+                    Err(_) => Body::new(file),
+                }
+            }
+        }
+    }
+}
+
+/// Opens the file to upload on the first read, so building a request
+/// can't fail on it.
+struct UploadFile {
+    path: PathBuf,
+    file: Option<std::fs::File>,
+}
+
+impl Read for UploadFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            self.file = Some(std::fs::File::open(&self.path)?);
+        }
+        self.file.as_mut().unwrap().read(buf)
+    }
+}
+
+/// How much the command line client reports while it works.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verbosity {
+    /// No progress or info output. Handlers stay silent even on failure:
+    /// the error reaches the caller, which reports it once.
+    Quiet,
+    Normal,
+    /// Full error chains and the response headers.
+    Verbose,
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, verbose: bool) -> Verbosity {
+        if quiet {
+            Verbosity::Quiet
+        } else if verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
+    }
+}
+
+/// How often a paused download checks whether it may carry on.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DownloadState {
+    Running,
+    /// Reads stop until the state goes back to `Running`, which throttles
+    /// the connections without closing them.
+    Paused,
+    Cancelled,
+}
+
+/// Shared run state of an `HttpDownload`, set from another thread or a
+/// signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct StateHandle {
+    state: Arc<AtomicU8>,
+    status_requested: Arc<AtomicBool>,
+}
+
+impl StateHandle {
+    pub fn state(&self) -> DownloadState {
+        match self.state.load(Ordering::SeqCst) {
+            0 => DownloadState::Running,
+            1 => DownloadState::Paused,
+            _ => DownloadState::Cancelled,
+        }
+    }
+
+    pub fn set_state(&self, state: DownloadState) {
+        self.state.store(state as u8, Ordering::SeqCst);
+    }
+
+    /// Pauses a running download or resumes a paused one. Only atomics are
+    /// touched, so this is safe to call from a signal handler.
+    pub fn toggle_pause(&self) {
+        let (running, paused) = (DownloadState::Running as u8, DownloadState::Paused as u8);
+        if self
+            .state
+            .compare_exchange(running, paused, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            let _ =
+                self.state
+                    .compare_exchange(paused, running, Ordering::SeqCst, Ordering::SeqCst);
+        }
+    }
+
+    /// Asks the download to report its progress through
+    /// `EventsHandler::on_status_query` the next time it checks its state.
+    /// Like `toggle_pause`, this is safe to call from a signal handler.
+    pub fn request_status(&self) {
+        self.status_requested.store(true, Ordering::SeqCst);
+    }
+
+    fn take_status_request(&self) -> bool {
+        self.status_requested.swap(false, Ordering::SeqCst)
+    }
+
+    fn wait_while_paused(&self) {
+        while self.state() == DownloadState::Paused {
+            thread::sleep(PAUSE_POLL_INTERVAL);
+        }
+    }
+}
+
+/// Retries shared by every download handed the same budget, so a few dead
+/// servers can't drag out a whole batch of downloads.
+#[derive(Debug, Default)]
+pub struct RetryBudget(AtomicU64);
+
+impl RetryBudget {
+    pub fn new(retries: u64) -> RetryBudget {
+        RetryBudget(AtomicU64::new(retries))
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Uses up one retry, or returns false if none are left.
+    pub fn take(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+/// Shared flag that stops an `HttpDownload` from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Why a download failed, for callers that need to tell failure classes
+/// apart.
+#[derive(Debug)]
+pub enum DownloadError {
+    /// Connecting to or talking with the server failed.
+    Network(Box<dyn StdError + Send + Sync>),
+    /// Reading or writing local files failed.
+    Io(io::Error),
+    InvalidUrl(String),
+    /// The server answered with a non-success status code.
+    HttpStatus(u16),
+    ChecksumMismatch {
+        /// What was checked, e.g. `Content-MD5` or `SHA-256`.
+        algorithm: &'static str,
+        file: String,
+        expected: String,
+        actual: String,
+    },
+    MaxRetries,
+    /// Chunks that ran out of retries were skipped, leaving `missing` bytes
+    /// of the file unwritten.
+    PartialDownload {
+        missing: u64,
+    },
+    /// The response headers were larger than `Config::header_size_limit`.
+    HeadersTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    /// The download's `CancellationToken` was triggered. The data received
+    /// so far has been handed to the hooks.
+    Cancelled,
+    /// Bytes read back from the output didn't match what was written. A
+    /// handler returns this from `on_concurrent_content` to have the range
+    /// fetched again.
+    ChunkWriteCorrupt {
+        offset: u64,
+        byte_count: u64,
+    },
+    Other(failure::Error),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DownloadError::Network(e) => write!(f, "{}", e),
+            DownloadError::Io(e) => write!(f, "{}", e),
+            DownloadError::InvalidUrl(msg) => write!(f, "invalid url: {}", msg),
+            DownloadError::HttpStatus(code) => write!(f, "server returned HTTP status {}", code),
+            DownloadError::ChecksumMismatch {
+                algorithm,
+                file,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} mismatch for {}: expected {}, got {}",
+                algorithm, file, expected, actual
+            ),
+            DownloadError::MaxRetries => write!(f, "max retries exceeded"),
+            DownloadError::PartialDownload { missing } => write!(
+                f,
+                "download incomplete, {} bytes could not be fetched",
+                missing
+            ),
+            DownloadError::HeadersTooLarge { size, limit } => write!(
+                f,
+                "server sent {} bytes of headers, more than the limit of {}",
+                size, limit
+            ),
+            DownloadError::Cancelled => write!(f, "download cancelled"),
+            DownloadError::ChunkWriteCorrupt { offset, byte_count } => write!(
+                f,
+                "{} bytes written at offset {} read back differently",
+                byte_count, offset
+            ),
+            DownloadError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for DownloadError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            DownloadError::Network(e) => Some(e.as_ref()),
+            DownloadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<failure::Error> for DownloadError {
+    /// Recovers the failure class from an error raised in the fallible
+    /// internals.
+    fn from(err: failure::Error) -> DownloadError {
+        let err = match err.downcast::<DownloadError>() {
+            Ok(e) => return e,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<reqwest::Error>() {
+            Ok(e) => return e.into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<io::Error>() {
+            Ok(e) => return e.into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<url::ParseError>() {
+            Ok(e) => return e.into(),
+            Err(err) => err,
+        };
+        match err.downcast::<ftp::FtpError>() {
+            Ok(e) => DownloadError::Network(Box::new(e)),
+            Err(err) => DownloadError::Other(err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(err: reqwest::Error) -> DownloadError {
+        match err.status() {
+            Some(status) => DownloadError::HttpStatus(status.as_u16()),
+            None => DownloadError::Network(Box::new(err)),
+        }
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(err: io::Error) -> DownloadError {
+        DownloadError::Io(err)
+    }
+}
+
+impl From<url::ParseError> for DownloadError {
+    fn from(err: url::ParseError) -> DownloadError {
+        DownloadError::InvalidUrl(err.to_string())
+    }
+}
+
+/// Summary of a finished transfer, handed to `on_finish_with_stats`.
+#[derive(Debug, Clone)]
+pub struct DownloadStats {
+    pub url: String,
+    pub bytes: u64,
+    pub duration: Duration,
+    pub retries: i32,
+    pub chunks: usize,
+    pub resumed: bool,
+    /// Bytes fetched but thrown away, e.g. from a mirror that lost a race.
+    pub wasted_bytes: u64,
+}
+
+impl DownloadStats {
+    /// Bytes per second over the whole download.
+    pub fn avg_speed(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Builds the HTTP client shared by the probe, main and chunk requests.
+pub fn build_client(conf: &Config) -> Fallible<Client> {
+    let builder = match conf.dns_servers {
+        Some(ref servers) => {
+            let resolver = DnsServers::new(servers.clone(), Duration::from_secs(conf.timeout));
+            // only the async builder takes a resolver
+            ClientBuilder::from(reqwest::ClientBuilder::new().dns_resolver(Arc::new(resolver)))
+        }
+        None => Client::builder(),
+    };
+    let mut builder = builder
+        .gzip(conf.compressed)
+        .tcp_nodelay(conf.tcp_no_delay)
+        .tcp_keepalive(conf.tcp_keepalive);
+    if let Some(ref ifname) = conf.interface {
+        builder = bind_interface(builder, ifname)?;
+    }
+    for (domain, addr) in &conf.resolve {
+        builder = builder.resolve(domain, *addr);
+    }
+    if let Some(ref url) = conf.proxy {
+        let mut proxy = Proxy::all(url.as_str())?;
+        if let Some((ref user, ref pass)) = conf.proxy_auth {
+            proxy = proxy.basic_auth(user, pass);
+        }
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder.build()?)
+}
+
+/// Exponential delays between retries: `base`, `2 * base`, `4 * base` and
+/// so on up to `max`, each shortened by a random part of up to `jitter` so
+/// that chunks failing together don't retry in lockstep.
+///
+/// The random numbers and the sleep are pluggable so the delays can be
+/// checked without waiting for them.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    jitter: f64,
+    attempt: u32,
+    rng: Box<dyn FnMut() -> f64 + Send>,
+    sleep: Box<dyn Fn(Duration) + Send>,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(RETRY_BASE_DELAY, RETRY_MAX_DELAY)
+    }
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff {
+            base,
+            max,
+            jitter: RETRY_JITTER,
+            attempt: 0,
+            rng: Box::new(xorshift_rng()),
+            sleep: Box::new(thread::sleep),
+        }
+    }
+
+    /// Cuts up to `fraction` of each delay, scaled by `rng`, which returns
+    /// numbers in `[0, 1)`.
+    pub fn jitter<R>(mut self, fraction: f64, rng: R) -> Backoff
+    where
+        R: FnMut() -> f64 + Send + 'static,
+    {
+        self.jitter = fraction;
+        self.rng = Box::new(rng);
+        self
+    }
+
+    /// Replaces `thread::sleep` in `wait`.
+    pub fn sleeper<S>(mut self, sleep: S) -> Backoff
+    where
+        S: Fn(Duration) + Send + 'static,
+    {
+        self.sleep = Box::new(sleep);
+        self
+    }
+
+    /// The delay before retry number `attempt`, counting from 0.
+    pub fn delay(&mut self, attempt: u32) -> Duration {
+        let full = self
+            .base
+            .checked_mul(1 << attempt.min(31))
+            .map_or(self.max, |delay| delay.min(self.max));
+        full.mul_f64(1.0 - self.jitter * (self.rng)())
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay(self.attempt);
+        self.attempt = self.attempt.saturating_add(1);
+        delay
+    }
+
+    /// Sleeps for the next delay.
+    pub fn wait(&mut self) {
+        let delay = self.next_delay();
+        (self.sleep)(delay);
+    }
+}
+
+/// Numbers in `[0, 1)` for the jitter, which only needs to differ between
+/// downloads, not to be unpredictable.
+fn xorshift_rng() -> impl FnMut() -> f64 + Send {
+    let mut x = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+        | 1;
+    move || {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Sends the request made by `build`, retrying refused connections when
+/// `conf.retry_connrefused` or `conf.wait_for_server` is set. Only the
+/// former draws on `conf.retry_budget`.
+pub fn send_retrying<F>(conf: &Config, mut build: F) -> Fallible<Response>
+where
+    F: FnMut() -> RequestBuilder,
+{
+    let started = Instant::now();
+    let mut retries = 0;
+    let mut backoff = Backoff::default();
+    loop {
+        match build().send() {
+            Ok(resp) => {
+                check_header_size(&resp, conf.header_size_limit)?;
+                return Ok(resp);
+            }
+            Err(e)
+                if conf.retry_connrefused
+                    && (is_infinite_retry(conf) || retries < conf.max_retries)
+                    && is_connection_refused(&e)
+                    && take_retry(conf) =>
+            {
+                retries += 1;
+                backoff.wait();
+            }
+            Err(e)
+                if conf
+                    .wait_for_server
+                    .is_some_and(|wait| started.elapsed() < wait)
+                    && is_connection_refused(&e) =>
+            {
+                thread::sleep(SERVER_POLL_INTERVAL);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Whether `conf` never gives up for the number of retries, as with
+/// `--tries 0`. Fatal errors and a `retry_budget` still end the download.
+pub fn is_infinite_retry(conf: &Config) -> bool {
+    conf.max_retries < 0
+}
+
+/// Whether `conf.retry_budget`, if any, has a retry left, using it up.
+fn take_retry(conf: &Config) -> bool {
+    conf.retry_budget
+        .as_ref()
+        .is_none_or(|budget| budget.take())
+}
+
+/// Fails with `DownloadError::HeadersTooLarge` when the headers of `resp`,
+/// counted as sent on the wire, exceed `limit`.
+///
+/// The blocking client has no hook into hyper's parser, so this runs on the
+/// parsed headers and can't stop a large block from being read; hyper's own
+/// cap of about 400 KiB bounds that.
+pub fn check_header_size(resp: &Response, limit: Option<u64>) -> Result<(), DownloadError> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    // `name: value\r\n` per header
+    let size = resp
+        .headers()
+        .iter()
+        .map(|(name, val)| (name.as_str().len() + val.len() + 4) as u64)
+        .sum();
+    if size > limit {
+        return Err(DownloadError::HeadersTooLarge { size, limit });
+    }
+    Ok(())
+}
+
+fn is_connection_refused(err: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            return io_err.kind() == io::ErrorKind::ConnectionRefused;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Binds outgoing connections to the address currently assigned to
+/// `ifname`. The address is looked up once, when the client is built, and
+/// the socket isn't tied to the device itself (`SO_BINDTODEVICE` or
+/// `IP_BOUND_IF`): reqwest doesn't let us set socket options, so the
+/// routing table still decides which interface the packets leave by.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn bind_interface(builder: ClientBuilder, ifname: &str) -> Fallible<ClientBuilder> {
+    Ok(builder.local_address(interface_address(ifname)?))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn bind_interface(_builder: ClientBuilder, _ifname: &str) -> Fallible<ClientBuilder> {
+    Err(format_err!(
+        "binding to a network interface is not supported on this platform"
+    ))
 }
 
 #[allow(unused_variables)]
 pub trait EventsHandler {
     fn on_resume_download(&mut self, bytes_on_disk: u64) {}
 
+    fn on_status_line(&mut self, status_line: &str) {}
+
     fn on_headers(&mut self, headers: HeaderMap) {}
 
-    fn on_content(&mut self, content: &[u8]) -> Fallible<()> {
+    fn on_content(&mut self, content: &[u8], progress: &Progress) -> Fallible<()> {
         Ok(())
     }
 
-    fn on_concurrent_content(&mut self, content: (u64, u64, &[u8])) -> Fallible<()> {
+    fn on_concurrent_content(
+        &mut self,
+        content: (u64, u64, &[u8]),
+        progress: &Progress,
+    ) -> Fallible<()> {
         Ok(())
     }
 
@@ -50,16 +749,131 @@ pub trait EventsHandler {
 
     fn on_failure_status(&self, status_code: i32) {}
 
-    fn on_finish(&mut self) {}
+    fn on_finish(&mut self) -> Fallible<()> {
+        Ok(())
+    }
+
+    fn on_finish_with_stats(&mut self, stats: &DownloadStats) -> Fallible<()> {
+        Ok(())
+    }
+
+    fn on_connections_chosen(&mut self, num_workers: usize) {}
+
+    fn on_copied_to_clipboard(&mut self, bytes: u64) {}
+
+    /// A `force_concurrent` download found the server ignoring ranges and
+    /// starts over with a single request.
+    fn on_concurrent_fallback(&mut self) {}
 
     fn on_max_retries(&mut self) {}
 
+    fn on_paused(&mut self) {}
+
+    fn on_resumed(&mut self) {}
+
+    /// Progress was asked for with `StateHandle::request_status`, the
+    /// download itself goes on.
+    fn on_status_query(&mut self) {}
+
+    /// The inclusive `(start, end)` range ran out of
+    /// `Config::max_retries_per_chunk` and is left out of the file.
+    fn on_chunk_skipped(&mut self, offsets: (u64, u64)) {}
+
+    /// The `byte_count` bytes written at `offset` read back differently
+    /// and are fetched again.
+    fn on_chunk_write_corrupt(&mut self, offset: u64, byte_count: u64) {}
+
+    /// The `length` bytes at `offset` were read back from the output as
+    /// written, see `Config::verify_interval`. `hash` is their SHA-256.
+    fn on_data_verified(&mut self, offset: u64, length: u64, hash: [u8; 32]) {}
+
+    /// Whether this handler read the piece it last took in
+    /// `on_concurrent_content` back from its output and found it as
+    /// written. Asked after every piece; `on_data_verified` fires when a
+    /// handler says so.
+    fn last_write_verified(&self) -> bool {
+        false
+    }
+
     fn on_server_supports_resume(&mut self) {}
+
+    /// When the server last changed the file, e.g. from FTP's MDTM.
+    fn on_remote_modified(&mut self, modified: SystemTime) {}
+
+    /// The server answered an `If-Modified-Since` request with 304: the
+    /// copy the caller has is current and nothing is downloaded.
+    fn on_not_modified(&mut self) {}
+
+    fn on_file_renamed(&mut self, old: &str, new: &str) -> Fallible<()> {
+        Ok(())
+    }
+}
+
+/// A transfer over any supported protocol.
+pub trait Downloader {
+    fn download(&mut self) -> Result<(), DownloadError>;
+
+    fn events_hook_dyn(&mut self, hk: Box<dyn EventsHandler>);
+}
+
+/// Picks the downloader for the url's scheme: FTP for `ftp`, SFTP for
+/// `sftp`, Gemini for `gemini`, HTTP for everything else. FTP transfers
+/// only use `conf.max_time`.
+pub fn create_downloader(url: &Url, conf: Config) -> Box<dyn Downloader> {
+    match url.scheme() {
+        "ftp" => {
+            let mut ftp = FtpDownload::new(url.clone());
+            ftp.max_time(conf.max_time);
+            Box::new(ftp)
+        }
+        "sftp" => Box::new(SftpDownload::new(url.clone())),
+        "gemini" => Box::new(GeminiDownload::new(url.clone())),
+        _ => Box::new(HttpDownload::new(url.clone(), conf)),
+    }
+}
+
+/// The port to reach the FTP server of `url` on: the url's own, else
+/// `port`, else the scheme's default. The url crate drops `:21` from
+/// `ftp://` urls, so an explicit default port can't override `port`.
+fn ftp_port(url: &Url, port: Option<u16>) -> Option<u16> {
+    url.port().or(port).or_else(|| url.port_or_known_default())
+}
+
+/// What an FTP server reports about a file, see `FtpDownload::file_info`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FtpFileInfo {
+    pub size: Option<u64>,
+    pub modified: Option<String>,
+    /// `modified` as a time the local file can be set to.
+    pub mtime: Option<SystemTime>,
+}
+
+/// The MDTM time of `fname`, or `None` when the server doesn't say.
+fn ftp_mtime(conn: &mut FtpStream, fname: &str) -> Fallible<Option<(String, SystemTime)>> {
+    let modified = conn.mdtm(fname)?;
+    Ok(modified.and_then(|time| {
+        let secs = u64::try_from(time.timestamp()).ok()?;
+        Some((time.to_string(), UNIX_EPOCH + Duration::from_secs(secs)))
+    }))
+}
+
+/// Asks the server to start the next transfer `offset` bytes into the
+/// file. The ftp crate has no REST command, so it goes straight onto the
+/// control connection.
+fn ftp_restart_at(conn: &mut FtpStream, offset: u64) -> Fallible<()> {
+    let mut control = conn.get_ref();
+    control.write_all(format!("REST {}\r\n", offset).as_bytes())?;
+    conn.read_response(ftp::status::REQUEST_FILE_PENDING)
+        .map_err(|e| format_err!("the server can't resume the transfer: {}", e))?;
+    Ok(())
 }
 
 pub struct FtpDownload {
     url: Url,
     hooks: Vec<RefCell<Box<dyn EventsHandler>>>,
+    max_time: Option<Duration>,
+    port: Option<u16>,
+    resume_from: u64,
 }
 
 impl FtpDownload {
@@ -67,69 +881,169 @@ impl FtpDownload {
         Self {
             url,
             hooks: Vec::new(),
+            max_time: None,
+            port: None,
+            resume_from: 0,
         }
     }
 
-    pub fn download(&mut self) -> Fallible<()> {
+    /// Fetches only the part of the file after the first `offset` bytes,
+    /// which are already on disk.
+    pub fn resume_from(&mut self, offset: u64) -> &mut FtpDownload {
+        self.resume_from = offset;
+        self
+    }
+
+    /// Connects to `port` when the url doesn't name one.
+    pub fn port(&mut self, port: Option<u16>) -> &mut FtpDownload {
+        self.port = port;
+        self
+    }
+
+    /// Aborts the transfer once it has been running for `max_time`.
+    pub fn max_time(&mut self, max_time: Option<Duration>) -> &mut FtpDownload {
+        self.max_time = max_time;
+        self
+    }
+
+    pub fn download(&mut self) -> Result<(), DownloadError> {
+        self.try_download().map_err(DownloadError::from)
+    }
+
+    /// Logs in and changes into the directory of the url's file, returning
+    /// the connection and the file name.
+    fn connect(&self) -> Fallible<(FtpStream, String)> {
         let ftp_server = format!(
             "{}:{}",
             self.url
                 .host_str()
-                .ok_or_else(|| format_err!("failed to parse hostname from url: {}", self.url))?,
-            self.url
-                .port_or_known_default()
-                .ok_or_else(|| format_err!("failed to parse port from url: {}", self.url))?,
+                .ok_or_else(|| invalid_url("failed to parse hostname from url", &self.url))?,
+            ftp_port(&self.url, self.port).ok_or_else(|| invalid_url(
+                "no port in url and no default port for its scheme",
+                &self.url
+            ))?,
         );
         let username = if self.url.username().is_empty() {
             "anonymous"
         } else {
             self.url.username()
         };
-        let password = self.url.password().unwrap_or("anonymous");
+        let password = match self.url.password() {
+            Some(pass) => decode_percent_encoded_data(pass)?,
+            None => "anonymous".to_owned(),
+        };
 
         let mut path_segments: Vec<&str> = self
             .url
             .path_segments()
-            .ok_or_else(|| format_err!("failed to get url path segments: {}", self.url))?
+            .ok_or_else(|| invalid_url("failed to get url path segments", &self.url))?
             .collect();
         let ftp_fname = path_segments
             .pop()
-            .ok_or_else(|| format_err!("got empty path segments from url: {}", self.url))?;
+            .ok_or_else(|| invalid_url("got empty path segments from url", &self.url))?;
 
         let mut conn = FtpStream::connect(ftp_server)?;
-        conn.login(username, password)?;
+        conn.login(username, &password)?;
         for path in &path_segments {
             conn.cwd(path)?;
         }
-        let ct_len = conn.size(ftp_fname)?;
-        let mut reader = conn.get(ftp_fname)?;
+        Ok((conn, ftp_fname.to_owned()))
+    }
+
+    /// Queries the file's size (SIZE) and modification time (MDTM) without
+    /// downloading it.
+    pub fn file_info(&self) -> Result<FtpFileInfo, DownloadError> {
+        let (mut conn, ftp_fname) = self.connect()?;
+        let size = conn.size(&ftp_fname).map_err(failure::Error::from)?;
+        let modified = ftp_mtime(&mut conn, &ftp_fname)?;
+        let _ = conn.quit();
+        let (modified, mtime) = modified.unzip();
+        Ok(FtpFileInfo {
+            size: size.map(|x| x as u64),
+            modified,
+            mtime,
+        })
+    }
 
+    fn try_download(&mut self) -> Fallible<()> {
+        let started = Instant::now();
+        let deadline = self.max_time.map(|limit| started + limit);
+        let (mut conn, ftp_fname) = self.connect()?;
+        let ct_len = conn.size(&ftp_fname)?.map(|x| x as u64);
+        // not every server has MDTM, and the file is fine without it
+        if let Ok(Some((_, mtime))) = ftp_mtime(&mut conn, &ftp_fname) {
+            for hook in &self.hooks {
+                hook.borrow_mut().on_remote_modified(mtime);
+            }
+        }
+        let offset = self.resume_from;
+        if offset > 0 {
+            match ct_len {
+                Some(len) if offset > len => {
+                    bail!(
+                        "the local file is larger than the remote one ({} bytes)",
+                        len
+                    )
+                }
+                Some(_) => {}
+                None => bail!("cannot resume without knowing the size of the file"),
+            }
+            for hook in &self.hooks {
+                hook.borrow_mut().on_server_supports_resume();
+                hook.borrow_mut().on_resume_download(offset);
+            }
+        }
         for hook in &self.hooks {
-            let ct_len = ct_len.map(|x| x as u64);
             hook.borrow_mut().on_ftp_content_length(ct_len);
         }
 
+        let mut bytes = 0u64;
+        if offset > 0 && ct_len == Some(offset) {
+            // already complete, there is nothing left to fetch
+            return self.finish(started, bytes, true);
+        }
+        if offset > 0 {
+            ftp_restart_at(&mut conn, offset)?;
+        }
+        let mut reader = conn.get(&ftp_fname)?;
+        let mut progress = Progress::new(ct_len.map(|len| len - offset));
         loop {
+            check_deadline(deadline, self.max_time)?;
             let mut buffer = vec![0; 2048usize];
             let bcount = reader.read(&mut buffer[..])?;
+            bytes += bcount as u64;
+            progress.bytes_downloaded = bytes;
             buffer.truncate(bcount);
             if !buffer.is_empty() {
-                self.send_content(buffer.as_slice())?;
+                self.send_content(buffer.as_slice(), &progress)?;
             } else {
                 break;
             }
         }
+        self.finish(started, bytes, offset > 0)
+    }
 
+    fn finish(&self, started: Instant, bytes: u64, resumed: bool) -> Fallible<()> {
+        let stats = DownloadStats {
+            url: self.url.to_string(),
+            bytes,
+            duration: started.elapsed(),
+            retries: 0,
+            chunks: 1,
+            resumed,
+            wasted_bytes: 0,
+        };
         for hook in &self.hooks {
-            hook.borrow_mut().on_finish();
+            hook.borrow_mut().on_finish()?;
+            hook.borrow_mut().on_finish_with_stats(&stats)?;
         }
 
         Ok(())
     }
 
-    fn send_content(&self, contents: &[u8]) -> Fallible<()> {
+    fn send_content(&self, contents: &[u8], progress: &Progress) -> Fallible<()> {
         for hk in &self.hooks {
-            hk.borrow_mut().on_content(contents)?;
+            hk.borrow_mut().on_content(contents, progress)?;
         }
         Ok(())
     }
@@ -139,12 +1053,40 @@ impl FtpDownload {
     }
 }
 
+impl Downloader for FtpDownload {
+    fn download(&mut self) -> Result<(), DownloadError> {
+        FtpDownload::download(self)
+    }
+
+    fn events_hook_dyn(&mut self, hk: Box<dyn EventsHandler>) {
+        self.hooks.push(RefCell::new(hk));
+    }
+}
+
 pub struct HttpDownload {
     url: Url,
     hooks: Vec<RefCell<Box<dyn EventsHandler>>>,
     conf: Config,
     retries: i32,
+    /// Retries of each chunk, keyed by its end offset since the start
+    /// moves forward as the chunk's bytes arrive. A piece fetched again
+    /// counts against the chunk it is part of.
+    chunk_retries: HashMap<u64, i32>,
     client: Client,
+    /// Whether `client` came from `with_client` rather than the config.
+    client_given: bool,
+    bytes_received: u64,
+    chunk_count: usize,
+    sniff_buf: Vec<u8>,
+    type_sniffed: bool,
+    /// `Config::expected_size` as it applies to the current response.
+    expected_len: Option<u64>,
+    /// How far the body being read has got, for the events handlers.
+    progress: Progress,
+    deadline: Option<Instant>,
+    cancel: CancellationToken,
+    state: StateHandle,
+    backoff: Backoff,
 }
 
 impl fmt::Debug for HttpDownload {
@@ -160,36 +1102,182 @@ impl HttpDownload {
             hooks: Vec::new(),
             conf,
             retries: 0,
+            chunk_retries: HashMap::new(),
             client: Client::new(),
+            client_given: false,
+            bytes_received: 0,
+            chunk_count: 0,
+            sniff_buf: Vec::new(),
+            type_sniffed: false,
+            expected_len: None,
+            progress: Progress::new(None),
+            deadline: None,
+            cancel: CancellationToken::new(),
+            state: StateHandle::default(),
+            backoff: Backoff::default(),
         }
     }
 
-    pub fn download(&mut self) -> Fallible<()> {
-        let resp = self
-            .client
-            .get(self.url.as_ref())
-            .timeout(Duration::from_secs(self.conf.timeout))
-            .headers(self.conf.headers.clone())
-            .header(
-                header::USER_AGENT,
-                HeaderValue::from_str(&self.conf.user_agent)?,
-            )
-            .send()?;
-        let headers = resp.headers();
+    /// Like `new`, but sends every request, the chunks' included, through
+    /// `client` instead of one built from `conf`. The client is used as it
+    /// is, so the proxy, interface and compression settings of `conf` are
+    /// not applied to it.
+    pub fn with_client(url: Url, conf: Config, client: Client) -> HttpDownload {
+        HttpDownload {
+            client,
+            client_given: true,
+            ..HttpDownload::new(url, conf)
+        }
+    }
+
+    /// Replaces the delays between chunk retries.
+    pub fn backoff(&mut self, backoff: Backoff) -> &mut HttpDownload {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Pauses, resumes or cancels the download, see `state_handle` to do
+    /// so while it runs.
+    pub fn set_state(&self, state: DownloadState) {
+        self.state.set_state(state);
+    }
 
+    /// Returns a handle that changes this download's state from another
+    /// thread.
+    pub fn state_handle(&self) -> StateHandle {
+        self.state.clone()
+    }
+
+    /// Returns a handle that cancels this download when triggered.
+    pub fn cancel_handle(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Shares `token` with this download, e.g. to cancel several at once.
+    pub fn with_cancellation(&mut self, token: CancellationToken) -> &mut HttpDownload {
+        self.cancel = token;
+        self
+    }
+
+    /// Returns a new download of just `range` of the same url, e.g. to
+    /// share a large file between processes or machines. It has no events
+    /// handlers; its `on_concurrent_content` offsets are those of the
+    /// whole file. Joining the pieces afterwards is up to the caller, see
+    /// `utils::merge_partial_files`.
+    ///
+    /// The server has to support byte ranges, otherwise the whole file is
+    /// fetched in one request.
+    pub fn split_download(&self, range: (u64, u64)) -> HttpDownload {
+        let mut conf = self.conf.clone();
+        conf.headers.remove(header::RANGE);
+        conf.resume = false;
+        conf.concurrent = true;
+        conf.auto_connections = false;
+        conf.ranges = None;
+        conf.bytes_on_disk = None;
+        conf.chunk_offsets = Some(vec![range]);
+        if self.client_given {
+            HttpDownload::with_client(self.url.clone(), conf, self.client.clone())
+        } else {
+            HttpDownload::new(self.url.clone(), conf)
+        }
+    }
+
+    pub fn download(&mut self) -> Result<(), DownloadError> {
+        self.try_download().map_err(DownloadError::from)
+    }
+
+    fn try_download(&mut self) -> Fallible<()> {
+        let started = Instant::now();
+        self.deadline = self.conf.max_time.map(|limit| started + limit);
+        if !self.client_given {
+            self.client = build_client(&self.conf)?;
+        }
+        let user_agent = HeaderValue::from_str(&self.conf.user_agent)?;
+        let resp = send_retrying(&self.conf, || {
+            let builder = self
+                .client
+                .request(self.conf.method.clone(), self.url.as_ref())
+                .timeout(Duration::from_secs(self.conf.timeout))
+                .headers(self.conf.headers.clone())
+                .header(header::USER_AGENT, user_agent.clone());
+            match self.conf.request_body {
+                Some(ref body) => builder.body(body.to_body()),
+                None => builder,
+            }
+        })?;
+        let status = resp.status();
+        let status_line = format!("{:?} {}", resp.version(), status);
+        let mut headers = resp.headers().clone();
+        if let Some(len) = self.conf.known_length {
+            headers
+                .entry(header::CONTENT_LENGTH)
+                .or_insert_with(|| HeaderValue::from(len));
+        }
+        // other methods aren't sent twice, this response is the download
+        let sent_once = if self.conf.method == Method::GET {
+            drop(resp);
+            None
+        } else {
+            Some(resp)
+        };
+        if status == StatusCode::NOT_MODIFIED
+            && self.conf.headers.contains_key(header::IF_MODIFIED_SINCE)
+        {
+            for hook in &self.hooks {
+                hook.borrow_mut().on_not_modified();
+            }
+            return Ok(());
+        }
+        if status.is_success() {
+            for hook in &self.hooks {
+                hook.borrow().on_success_status();
+            }
+        } else {
+            for hook in &self.hooks {
+                hook.borrow().on_failure_status(i32::from(status.as_u16()));
+            }
+            // resuming a file that is already complete
+            if status == StatusCode::RANGE_NOT_SATISFIABLE && self.conf.resume {
+                return Ok(());
+            }
+            return Err(DownloadError::HttpStatus(status.as_u16()).into());
+        }
         let server_supports_bytes = match headers.get(header::ACCEPT_RANGES) {
             Some(val) => val == "bytes",
             None => false,
         };
 
-        if server_supports_bytes && self.conf.headers.contains_key(header::RANGE) {
+        let resumed = server_supports_bytes && self.conf.headers.contains_key(header::RANGE);
+        if resumed {
             if self.conf.concurrent {
                 self.conf.headers.remove(header::RANGE);
+                // the chunks are planned against the whole file, not the
+                // remainder the probe asked for
+                let total = headers
+                    .get(header::CONTENT_RANGE)
+                    .and_then(|val| val.to_str().ok())
+                    .and_then(parse_content_range)
+                    .map(|(_, _, total)| total);
+                if let Some(total) = total {
+                    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(total));
+                }
             }
             for hook in &self.hooks {
                 hook.borrow_mut().on_server_supports_resume();
             }
         }
+        if let Some(size) = self.conf.expected_size {
+            // a resumed single request only sends the rest of the file
+            let offset = if resumed && !self.conf.concurrent {
+                self.conf.bytes_on_disk.unwrap_or(0)
+            } else {
+                0
+            };
+            let len = size.saturating_sub(offset);
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+            self.expected_len = Some(len);
+        }
 
         let req = self
             .client
@@ -199,19 +1287,57 @@ impl HttpDownload {
             .build()?;
 
         for hk in &self.hooks {
+            hk.borrow_mut().on_status_line(&status_line);
             hk.borrow_mut().on_headers(headers.clone());
         }
-        if server_supports_bytes
+        if let Some(mut resp) = sent_once {
+            self.read_body(&mut resp)?;
+        } else if self.conf.ranges.is_some() {
+            if !server_supports_bytes {
+                bail!("server does not support byte ranges: {}", self.url);
+            }
+            self.ranges_download(req)?;
+        } else if (server_supports_bytes || self.conf.force_concurrent)
             && self.conf.concurrent
             && headers.contains_key(header::CONTENT_LENGTH)
         {
-            self.concurrent_download(req, headers.get(header::CONTENT_LENGTH).unwrap())?;
+            let fallback_req = req.try_clone();
+            let result =
+                self.concurrent_download(req, headers.get(header::CONTENT_LENGTH).unwrap());
+            match (result, fallback_req) {
+                // nothing was written yet, so the file can simply be
+                // fetched again in one piece
+                (Err(e), Some(req))
+                    if !server_supports_bytes
+                        && e.downcast_ref::<RangeIgnored>().is_some()
+                        && self.bytes_received == 0 =>
+                {
+                    for hk in &self.hooks {
+                        hk.borrow_mut().on_concurrent_fallback();
+                    }
+                    self.singlethread_download(req)?;
+                }
+                (result, _) => result?,
+            }
         } else {
             self.singlethread_download(req)?;
         }
+        if self.conf.detect_type && !self.type_sniffed {
+            self.correct_file_type()?;
+        }
 
+        let stats = DownloadStats {
+            url: self.url.to_string(),
+            bytes: self.bytes_received,
+            duration: started.elapsed(),
+            retries: self.retries,
+            chunks: self.chunk_count,
+            resumed,
+            wasted_bytes: 0,
+        };
         for hook in &self.hooks {
-            hook.borrow_mut().on_finish();
+            hook.borrow_mut().on_finish()?;
+            hook.borrow_mut().on_finish_with_stats(&stats)?;
         }
 
         Ok(())
@@ -224,14 +1350,27 @@ impl HttpDownload {
 
     fn singlethread_download(&mut self, req: Request) -> Fallible<()> {
         let mut resp = self.client.execute(req)?;
-        let ct_len = if let Some(val) = resp.headers().get(header::CONTENT_LENGTH) {
+        self.read_body(&mut resp)
+    }
+
+    fn read_body(&mut self, resp: &mut Response) -> Fallible<()> {
+        self.chunk_count = 1;
+        let ct_len = if let Some(len) = self.expected_len {
+            Some(len as usize)
+        } else if let Some(val) = resp.headers().get(header::CONTENT_LENGTH) {
             Some(val.to_str()?.parse::<usize>()?)
         } else {
             None
         };
+        self.progress = Progress::new(ct_len.map(|len| len as u64));
         let mut cnt = 0;
         loop {
-            let mut buffer = vec![0; self.conf.chunk_size as usize];
+            self.check_interrupted()?;
+            // an expected size may end before the body does
+            let buf_len = ct_len.map_or(self.conf.chunk_size as usize, |len| {
+                (len - cnt).min(self.conf.chunk_size as usize)
+            });
+            let mut buffer = vec![0; buf_len];
             let bcount = resp.read(&mut buffer[..])?;
             cnt += bcount;
             buffer.truncate(bcount);
@@ -248,102 +1387,565 @@ impl HttpDownload {
     }
 
     pub fn concurrent_download(&mut self, req: Request, ct_val: &HeaderValue) -> Fallible<()> {
-        let (data_tx, data_rx) = mpsc::channel();
-        let (errors_tx, errors_rx) = mpsc::channel();
         let ct_len = ct_val.to_str()?.parse::<u64>()?;
         let chunk_offsets = self
             .conf
             .chunk_offsets
             .clone()
-            .unwrap_or_else(|| self.get_chunk_offsets(ct_len, self.conf.chunk_size));
+            .unwrap_or_else(|| get_chunk_offsets(ct_len, self.conf.chunk_size));
+        if self.conf.auto_connections {
+            self.conf.num_workers = self.choose_connections(&req, ct_len);
+            for hk in &self.hooks {
+                hk.borrow_mut().on_connections_chosen(self.conf.num_workers);
+            }
+        }
+        // whatever the offsets leave out is already there, or not wanted
+        let count = self.conf.bytes_on_disk.unwrap_or_else(|| {
+            ct_len.saturating_sub(
+                chunk_offsets
+                    .iter()
+                    .map(|(start, end)| end - start + 1)
+                    .sum(),
+            )
+        });
+        self.fetch_chunks(req, chunk_offsets, count, ct_len)
+    }
+
+    /// Returns the count in `PROBE_CONNECTIONS` that fetched the start of
+    /// the file fastest, preferring fewer connections on a tie.
+    fn choose_connections(&self, req: &Request, ct_len: u64) -> usize {
+        let probe_len = ct_len.min(PROBE_SIZE);
+        let mut best = (PROBE_CONNECTIONS[0], 0.0);
+        let limit = self.conf.max_conns_per_host.unwrap_or(usize::MAX);
+        // counts above the per host cap couldn't be used anyway
+        for &connections in PROBE_CONNECTIONS.iter().filter(|&&n| n <= limit) {
+            let speed = probe_throughput(&self.client, req, probe_len, connections);
+            if speed > best.1 {
+                best = (connections, speed);
+            }
+        }
+        best.0
+    }
+
+    /// Fetches the user supplied ranges, finishing once all of their bytes
+    /// have arrived rather than at the full content length.
+    fn ranges_download(&mut self, req: Request) -> Fallible<()> {
+        let ranges = self.conf.ranges.clone().unwrap_or_default();
+        let total = ranges.iter().map(|(start, end)| end - start + 1).sum();
+        self.fetch_chunks(req, ranges, 0, total)
+    }
+
+    fn fetch_chunks(
+        &mut self,
+        req: Request,
+        chunk_offsets: Vec<(u64, u64)>,
+        mut count: u64,
+        total: u64,
+    ) -> Fallible<()> {
+        let (data_tx, data_rx) = mpsc::channel();
+        let (errors_tx, errors_rx) = mpsc::channel();
+        self.chunk_count = chunk_offsets.len();
+        self.progress = Progress::new(Some(total - count));
         let worker_pool = ThreadPool::new(self.conf.num_workers);
+        let chunk_ends: BTreeSet<u64> = chunk_offsets.iter().map(|&(_, end)| end).collect();
         for offsets in chunk_offsets {
-            let data_tx = data_tx.clone();
-            let errors_tx = errors_tx.clone();
-            let req = req.try_clone().unwrap();
-            worker_pool.execute(move || download_chunk(req, offsets, data_tx.clone(), errors_tx))
+            self.spawn_chunk(
+                &worker_pool,
+                &req,
+                offsets,
+                &data_tx,
+                &errors_tx,
+                Duration::from_secs(0),
+            );
         }
 
-        let mut count = self.conf.bytes_on_disk.unwrap_or(0);
+        let mut skipped = 0u64;
         loop {
-            if count == ct_len {
+            if count + skipped == total {
                 break;
             }
-            let (byte_count, offset, buf) = data_rx.recv()?;
+            self.check_interrupted()?;
+            while let Ok(failure) = errors_rx.try_recv() {
+                match failure {
+                    ChunkFailure::Retry(offsets) => {
+                        if !is_infinite_retry(&self.conf) && self.retries > self.conf.max_retries {
+                            for hk in &self.hooks {
+                                hk.borrow_mut().on_max_retries();
+                            }
+                            return Err(DownloadError::MaxRetries.into());
+                        }
+                        // the end of the chunk the range is in
+                        let chunk_end = chunk_ends.range(offsets.1..).next().copied();
+                        let chunk_retries = self
+                            .chunk_retries
+                            .entry(chunk_end.unwrap_or(offsets.1))
+                            .or_insert(0);
+                        if self.conf.max_retries_per_chunk >= 0
+                            && *chunk_retries >= self.conf.max_retries_per_chunk
+                        {
+                            skipped += offsets.1 - offsets.0 + 1;
+                            for hk in &self.hooks {
+                                hk.borrow_mut().on_chunk_skipped(offsets);
+                            }
+                            continue;
+                        }
+                        if !take_retry(&self.conf) {
+                            for hk in &self.hooks {
+                                hk.borrow_mut().on_max_retries();
+                            }
+                            return Err(DownloadError::MaxRetries.into());
+                        }
+                        let delay = self.backoff.delay(*chunk_retries as u32);
+                        *chunk_retries = chunk_retries.saturating_add(1);
+                        self.retries = self.retries.saturating_add(1);
+                        self.spawn_chunk(&worker_pool, &req, offsets, &data_tx, &errors_tx, delay);
+                    }
+                    ChunkFailure::Fatal(e) => return Err(e),
+                }
+            }
+            let wait = match self.deadline {
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(CHUNK_POLL_INTERVAL),
+                None => CHUNK_POLL_INTERVAL,
+            };
+            let (byte_count, offset, buf) = match data_rx.recv_timeout(wait) {
+                Ok(data) => data,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(e) => return Err(e.into()),
+            };
             count += byte_count;
-            for hk in &self.hooks {
-                hk.borrow_mut()
-                    .on_concurrent_content((byte_count, offset, &buf))?;
+            self.bytes_received += byte_count;
+            self.progress.bytes_downloaded += byte_count;
+            if offset == self.sniff_buf.len() as u64 {
+                self.sniff_content_type(&buf)?;
             }
-            match errors_rx.recv_timeout(Duration::from_micros(1)) {
-                Err(_) => {}
-                Ok(offsets) => {
-                    if self.retries > self.conf.max_retries {
+            match self.send_concurrent_content(byte_count, offset, &buf) {
+                Ok(()) => self.data_verified(offset, &buf[..byte_count as usize]),
+                Err(e) => match e.downcast_ref::<DownloadError>() {
+                    Some(&DownloadError::ChunkWriteCorrupt { offset, byte_count }) => {
+                        count -= byte_count;
+                        self.bytes_received -= byte_count;
+                        self.progress.bytes_downloaded -= byte_count;
                         for hk in &self.hooks {
-                            hk.borrow_mut().on_max_retries();
+                            hk.borrow_mut().on_chunk_write_corrupt(offset, byte_count);
                         }
+                        // goes through the retry limits like a failed request
+                        let _ =
+                            errors_tx.send(ChunkFailure::Retry((offset, offset + byte_count - 1)));
                     }
-                    self.retries += 1;
-                    let data_tx = data_tx.clone();
-                    let errors_tx = errors_tx.clone();
-                    let req = req.try_clone().unwrap();
-                    worker_pool.execute(move || download_chunk(req, offsets, data_tx, errors_tx))
-                }
+                    _ => return Err(e),
+                },
             }
         }
+        if skipped > 0 {
+            return Err(DownloadError::PartialDownload { missing: skipped }.into());
+        }
         Ok(())
     }
 
-    fn get_chunk_offsets(&self, ct_len: u64, chunk_size: u64) -> Vec<(u64, u64)> {
-        let no_of_chunks = ct_len / chunk_size;
-        let mut sizes = Vec::new();
+    fn spawn_chunk(
+        &self,
+        worker_pool: &ThreadPool,
+        req: &Request,
+        offsets: (u64, u64),
+        data_tx: &mpsc::Sender<(u64, u64, Vec<u8>)>,
+        errors_tx: &mpsc::Sender<ChunkFailure>,
+        delay: Duration,
+    ) {
+        let data_tx = data_tx.clone();
+        let errors_tx = errors_tx.clone();
+        let req = req.try_clone().unwrap();
+        let client = self.client.clone();
+        let settings = ChunkSettings {
+            retry_connrefused: self.conf.retry_connrefused,
+            max_conns_per_host: self.conf.max_conns_per_host,
+            global_connection_limit: self.conf.global_connection_limit.clone(),
+            state: self.state.clone(),
+            delay,
+            header_size_limit: self.conf.header_size_limit,
+        };
+        worker_pool
+            .execute(move || download_chunk(&client, req, offsets, data_tx, errors_tx, &settings))
+    }
 
-        for chunk in 0..no_of_chunks {
-            let bound = if chunk == no_of_chunks - 1 {
-                ct_len
-            } else {
-                ((chunk + 1) * chunk_size) - 1
-            };
-            sizes.push((chunk * chunk_size, bound));
+    fn check_interrupted(&self) -> Fallible<()> {
+        if self.state.take_status_request() {
+            for hk in &self.hooks {
+                hk.borrow_mut().on_status_query();
+            }
+        }
+        if self.state.state() == DownloadState::Paused {
+            for hk in &self.hooks {
+                hk.borrow_mut().on_paused();
+            }
+            self.state.wait_while_paused();
+            for hk in &self.hooks {
+                hk.borrow_mut().on_resumed();
+            }
         }
-        if sizes.is_empty() {
-            sizes.push((0, ct_len));
+        if self.cancel.is_cancelled() || self.state.state() == DownloadState::Cancelled {
+            return Err(DownloadError::Cancelled.into());
         }
+        check_deadline(self.deadline, self.conf.max_time)
+    }
 
-        sizes
+    /// Fires `on_data_verified` for a piece the handlers took, if one of
+    /// them read it back.
+    fn data_verified(&self, offset: u64, buf: &[u8]) {
+        if !self
+            .hooks
+            .iter()
+            .any(|hk| hk.borrow().last_write_verified())
+        {
+            return;
+        }
+        let mut sha = Sha256::new();
+        sha.update(buf);
+        let hash = sha.finish();
+        for hk in &self.hooks {
+            hk.borrow_mut()
+                .on_data_verified(offset, buf.len() as u64, hash);
+        }
+    }
+
+    fn send_concurrent_content(&self, byte_count: u64, offset: u64, buf: &[u8]) -> Fallible<()> {
+        for hk in &self.hooks {
+            hk.borrow_mut()
+                .on_concurrent_content((byte_count, offset, buf), &self.progress)?;
+        }
+        Ok(())
     }
 
     fn send_content(&mut self, contents: &[u8]) -> Fallible<()> {
+        self.bytes_received += contents.len() as u64;
+        self.progress.bytes_downloaded += contents.len() as u64;
+        self.sniff_content_type(contents)?;
         for hk in &self.hooks {
-            hk.borrow_mut().on_content(contents)?;
+            hk.borrow_mut().on_content(contents, &self.progress)?;
         }
 
         Ok(())
     }
+
+    fn sniff_content_type(&mut self, contents: &[u8]) -> Fallible<()> {
+        if !self.conf.detect_type || self.type_sniffed {
+            return Ok(());
+        }
+        let needed = magic::SNIFF_LEN - self.sniff_buf.len();
+        self.sniff_buf
+            .extend_from_slice(&contents[..needed.min(contents.len())]);
+        if self.sniff_buf.len() == magic::SNIFF_LEN {
+            self.correct_file_type()?;
+        }
+        Ok(())
+    }
+
+    fn correct_file_type(&mut self) -> Fallible<()> {
+        self.type_sniffed = true;
+        let new_name = magic::detect_extension(&self.sniff_buf)
+            .and_then(|ext| magic::corrected_filename(&self.conf.file, ext));
+        if let Some(new_name) = new_name {
+            for hk in &self.hooks {
+                hk.borrow_mut()
+                    .on_file_renamed(&self.conf.file, &new_name)?;
+            }
+            self.conf.file = new_name;
+        }
+        Ok(())
+    }
+}
+
+impl Downloader for HttpDownload {
+    fn download(&mut self) -> Result<(), DownloadError> {
+        HttpDownload::download(self)
+    }
+
+    fn events_hook_dyn(&mut self, hk: Box<dyn EventsHandler>) {
+        self.hooks.push(RefCell::new(hk));
+    }
+}
+
+fn invalid_url(msg: &str, url: &Url) -> DownloadError {
+    DownloadError::InvalidUrl(format!("{}: {}", msg, url))
+}
+
+/// A chunk request was answered with the whole file instead of its range.
+#[derive(Debug)]
+struct RangeIgnored(String);
+
+impl fmt::Display for RangeIgnored {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "server ignored the byte range {}", self.0)
+    }
+}
+
+impl StdError for RangeIgnored {}
+
+/// What a chunk worker reports back when its range could not be fetched.
+enum ChunkFailure {
+    /// Fetch the remaining `(start, end)` bytes again.
+    Retry((u64, u64)),
+    Fatal(failure::Error),
+}
+
+fn check_deadline(deadline: Option<Instant>, max_time: Option<Duration>) -> Fallible<()> {
+    match (deadline, max_time) {
+        (Some(deadline), Some(max_time)) if Instant::now() >= deadline => bail!(
+            "maximum download time of {}s exceeded",
+            max_time.as_secs_f64()
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Splits `ct_len` bytes into inclusive `(start, end)` byte ranges of
+/// `chunk_size`, the last range absorbing any remainder.
+pub(crate) fn get_chunk_offsets(ct_len: u64, chunk_size: u64) -> Vec<(u64, u64)> {
+    let no_of_chunks = ct_len / chunk_size;
+    let mut sizes = Vec::new();
+
+    for chunk in 0..no_of_chunks {
+        let bound = if chunk == no_of_chunks - 1 {
+            ct_len - 1
+        } else {
+            ((chunk + 1) * chunk_size) - 1
+        };
+        sizes.push((chunk * chunk_size, bound));
+    }
+    if sizes.is_empty() && ct_len > 0 {
+        sizes.push((0, ct_len - 1));
+    }
+
+    sizes
+}
+
+/// Returns the ranges of a `ct_len` byte file not covered by `downloaded`,
+/// given as `(byte_count, offset)` pairs in any order and possibly
+/// overlapping, splitting the tail in chunks.
+pub(crate) fn missing_chunk_offsets(
+    downloaded: Vec<(u64, u64)>,
+    ct_len: u64,
+    chunk_size: u64,
+) -> Vec<(u64, u64)> {
+    let mut done = IntervalSet::new();
+    for (bc, offset) in downloaded {
+        done.add(offset, offset + bc);
+    }
+    let mut chunks = vec![];
+    for (start, end) in done.complement(ct_len) {
+        if end < ct_len {
+            chunks.push((start, end - 1));
+            continue;
+        }
+        let mut i = start;
+        while ct_len - i > chunk_size {
+            chunks.push((i, i + chunk_size - 1));
+            i += chunk_size;
+        }
+        chunks.push((i, ct_len - 1));
+    }
+    chunks
+}
+
+/// Fetches the first `probe_len` bytes split over `connections` ranges and
+/// returns the throughput in bytes per second. The data is discarded.
+fn probe_throughput(client: &Client, req: &Request, probe_len: u64, connections: usize) -> f64 {
+    let (data_tx, data_rx) = mpsc::channel();
+    let (errors_tx, errors_rx) = mpsc::channel();
+    let piece = (probe_len / connections as u64).max(1);
+    let worker_pool = ThreadPool::new(connections);
+    let started = Instant::now();
+    for offsets in get_chunk_offsets(probe_len, piece) {
+        let (client, data_tx, errors_tx) = (client.clone(), data_tx.clone(), errors_tx.clone());
+        let req = match req.try_clone() {
+            Some(req) => req,
+            None => return 0.0,
+        };
+        worker_pool.execute(move || {
+            download_chunk(
+                &client,
+                req,
+                offsets,
+                data_tx,
+                errors_tx,
+                &ChunkSettings::default(),
+            )
+        });
+    }
+    drop(data_tx);
+
+    let deadline = started + PROBE_TIME_LIMIT;
+    let mut received = 0;
+    // a failed range just ends the probe early, leaving a lower speed
+    while received < probe_len && errors_rx.try_recv().is_err() {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        match data_rx.recv_timeout(wait) {
+            Ok((byte_count, _, _)) => received += byte_count,
+            Err(_) => break,
+        }
+    }
+    received as f64 / started.elapsed().as_secs_f64()
+}
+
+/// Connections in use per `host:port`, for `Config::max_conns_per_host`.
+#[derive(Default)]
+struct HostSlots {
+    in_use: Mutex<HashMap<String, usize>>,
+    freed: Condvar,
+}
+
+/// A connection slot for one host, given back when dropped.
+struct HostSlot {
+    host: String,
+}
+
+impl HostSlots {
+    fn global() -> &'static HostSlots {
+        static SLOTS: OnceLock<HostSlots> = OnceLock::new();
+        SLOTS.get_or_init(HostSlots::default)
+    }
+
+    /// Waits until fewer than `limit` connections to `host` are in use.
+    fn acquire(&'static self, host: &str, limit: usize) -> HostSlot {
+        let mut in_use = self.in_use.lock().unwrap();
+        while in_use.get(host).copied().unwrap_or(0) >= limit.max(1) {
+            in_use = self.freed.wait(in_use).unwrap();
+        }
+        *in_use.entry(host.to_owned()).or_insert(0) += 1;
+        HostSlot {
+            host: host.to_owned(),
+        }
+    }
+}
+
+impl Drop for HostSlot {
+    fn drop(&mut self) {
+        let slots = HostSlots::global();
+        let mut in_use = slots.in_use.lock().unwrap();
+        if let Some(count) = in_use.get_mut(&self.host) {
+            *count -= 1;
+            if *count == 0 {
+                in_use.remove(&self.host);
+            }
+        }
+        slots.freed.notify_all();
+    }
+}
+
+/// A counting semaphore for `Config::global_connection_limit`.
+#[derive(Debug)]
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A permit taken from a `Semaphore`, given back when dropped.
+pub struct SemaphorePermit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: Mutex::new(permits),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Waits until a permit is free and takes it.
+    pub fn acquire(self: &Arc<Self>) -> SemaphorePermit {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.freed.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphorePermit {
+            semaphore: self.clone(),
+        }
+    }
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        *self.semaphore.permits.lock().unwrap() += 1;
+        self.semaphore.freed.notify_one();
+    }
+}
+
+/// What the chunk workers of one download share.
+#[derive(Default)]
+struct ChunkSettings {
+    retry_connrefused: bool,
+    max_conns_per_host: Option<usize>,
+    global_connection_limit: Option<Arc<Semaphore>>,
+    state: StateHandle,
+    /// Backoff before a retried chunk is requested again.
+    delay: Duration,
+    header_size_limit: Option<u64>,
 }
 
 fn download_chunk(
+    client: &Client,
     req: Request,
     offsets: (u64, u64),
     sender: mpsc::Sender<(u64, u64, Vec<u8>)>,
-    errors: mpsc::Sender<(u64, u64)>,
+    errors: mpsc::Sender<ChunkFailure>,
+    settings: &ChunkSettings,
 ) {
     fn inner(
+        client: &Client,
         mut req: Request,
         offsets: (u64, u64),
         sender: mpsc::Sender<(u64, u64, Vec<u8>)>,
         start_offset: &mut u64,
+        settings: &ChunkSettings,
     ) -> Fallible<()> {
+        // held until the whole range has been read
+        let _slot = match (settings.max_conns_per_host, req.url().host_str()) {
+            (Some(limit), Some(host)) => {
+                let host = format!(
+                    "{}:{}",
+                    host,
+                    req.url().port_or_known_default().unwrap_or(0)
+                );
+                Some(HostSlots::global().acquire(&host, limit))
+            }
+            _ => None,
+        };
+        let _permit = settings
+            .global_connection_limit
+            .as_ref()
+            .map(|limit| limit.acquire());
         let byte_range = format!("bytes={}-{}", offsets.0, offsets.1);
         let headers = req.headers_mut();
         headers.insert(header::RANGE, HeaderValue::from_str(&byte_range)?);
         headers.insert(header::ACCEPT, HeaderValue::from_str("*/*")?);
         headers.insert(header::CONNECTION, HeaderValue::from_str("keep-alive")?);
-        let mut resp = Client::new().execute(req)?;
+        let mut resp = client.execute(req)?;
+        check_header_size(&resp, settings.header_size_limit)?;
+        // retrying won't make a server that ignores ranges honour them
+        if resp.status() == StatusCode::OK {
+            return Err(RangeIgnored(byte_range).into());
+        }
+        // a server that ignores the range would have us write the wrong
+        // bytes at this offset
+        let content_range = resp
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|val| val.to_str().ok())
+            .and_then(parse_content_range);
+        match content_range {
+            Some((start, _, _)) if start == offsets.0 => {}
+            _ => bail!("server did not honour range {}", byte_range),
+        }
         let chunk_sz = offsets.1 - offsets.0;
         let mut cnt = 0u64;
         loop {
-            let mut buf = vec![0; chunk_sz as usize];
+            // not reading lets the connection's window fill up, so a pause
+            // frees the bandwidth too
+            settings.state.wait_while_paused();
+            let mut buf = vec![0; (chunk_sz + 1) as usize];
             let byte_count = resp.read(&mut buf[..])?;
             cnt += byte_count as u64;
             buf.truncate(byte_count);
@@ -360,12 +1962,237 @@ fn download_chunk(
 
         Ok(())
     }
+    thread::sleep(settings.delay);
     let mut start_offset = offsets.0;
     let end_offset = offsets.1;
-    match inner(req, offsets, sender, &mut start_offset) {
-        Ok(_) => {}
-        Err(_) => match errors.send((start_offset, end_offset)) {
-            _ => {}
-        },
+    if let Err(e) = inner(client, req, offsets, sender, &mut start_offset, settings) {
+        let refused = e
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| is_connection_refused(e));
+        let failure = if e.downcast_ref::<RangeIgnored>().is_some() {
+            ChunkFailure::Fatal(e)
+        } else if !refused || settings.retry_connrefused {
+            ChunkFailure::Retry((start_offset, end_offset))
+        } else {
+            ChunkFailure::Fatal(e)
+        };
+        let _ = errors.send(failure);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_semaphore_caps_holders() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let active = Arc::new(Mutex::new((0, 0)));
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let semaphore = semaphore.clone();
+                let active = active.clone();
+                thread::spawn(move || {
+                    let _permit = semaphore.acquire();
+                    {
+                        let mut active = active.lock().unwrap();
+                        active.0 += 1;
+                        active.1 = active.1.max(active.0);
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                    active.lock().unwrap().0 -= 1;
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let max = active.lock().unwrap().1;
+        assert!((1..=2).contains(&max), "saw {} holders at once", max);
+        assert_eq!(*semaphore.permits.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(500))
+            .jitter(0.0, || 0.0);
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay()).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 500, 500]
+                .iter()
+                .map(|&ms| Duration::from_millis(ms))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_backoff_jitter_and_sleeper() {
+        let slept = Arc::new(Mutex::new(Vec::new()));
+        let record = slept.clone();
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(5))
+            .jitter(0.5, || 0.5)
+            .sleeper(move |delay| record.lock().unwrap().push(delay));
+        backoff.wait();
+        backoff.wait();
+        backoff.wait();
+        assert_eq!(
+            *slept.lock().unwrap(),
+            vec![
+                Duration::from_millis(75),
+                Duration::from_millis(150),
+                Duration::from_millis(300)
+            ]
+        );
+        // far past the cap without overflowing
+        assert_eq!(backoff.delay(100), Duration::from_millis(3750));
+    }
+
+    #[test]
+    fn test_build_client_with_socket_options() {
+        let conf = Config {
+            user_agent: "duma-test".to_owned(),
+            resume: false,
+            headers: HeaderMap::new(),
+            file: "foo.txt".to_owned(),
+            timeout: 30,
+            concurrent: false,
+            max_retries: 0,
+            max_retries_per_chunk: 0,
+            num_workers: 1,
+            bytes_on_disk: None,
+            chunk_offsets: None,
+            ranges: None,
+            chunk_size: 512,
+            detect_type: false,
+            interface: None,
+            resolve: Vec::new(),
+            dns_servers: None,
+            max_time: None,
+            retry_connrefused: false,
+            compressed: false,
+            credentials_file: None,
+            auto_connections: false,
+            max_conns_per_host: None,
+            known_length: None,
+            force_concurrent: false,
+            proxy: None,
+            proxy_auth: None,
+            tcp_no_delay: true,
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            header_size_limit: None,
+            wait_for_server: None,
+            global_connection_limit: None,
+            verify_interval: None,
+            retry_budget: None,
+            sync_interval_bytes: crate::io::DEFAULT_SYNC_INTERVAL_BYTES,
+            convert_links: false,
+            expected_size: None,
+            page_requisites: false,
+            recursive_level: None,
+            mirror_dir: None,
+            method: Method::GET,
+            request_body: None,
+        };
+        assert!(build_client(&conf).is_ok());
+    }
+
+    #[test]
+    fn test_ftp_port_precedence() {
+        let url = Url::parse("ftp://example.com:2121/foo.txt").unwrap();
+        assert_eq!(ftp_port(&url, Some(990)), Some(2121));
+        let url = Url::parse("ftp://example.com/foo.txt").unwrap();
+        assert_eq!(ftp_port(&url, Some(990)), Some(990));
+        assert_eq!(ftp_port(&url, None), Some(21));
+        let url = Url::parse("unknown://example.com/foo.txt").unwrap();
+        assert_eq!(ftp_port(&url, None), None);
+    }
+
+    #[test]
+    fn test_toggle_pause() {
+        let state = StateHandle::default();
+        state.toggle_pause();
+        assert_eq!(state.state(), DownloadState::Paused);
+        state.toggle_pause();
+        assert_eq!(state.state(), DownloadState::Running);
+        // a cancelled download stays cancelled
+        state.set_state(DownloadState::Cancelled);
+        state.toggle_pause();
+        assert_eq!(state.state(), DownloadState::Cancelled);
+    }
+
+    #[test]
+    fn test_download_error_from_typed_errors() {
+        let err: DownloadError = io::Error::new(io::ErrorKind::NotFound, "gone").into();
+        assert!(matches!(err, DownloadError::Io(_)));
+        let err: DownloadError = Url::parse("http://").unwrap_err().into();
+        assert!(matches!(err, DownloadError::InvalidUrl(_)));
+    }
+
+    #[test]
+    fn test_download_error_survives_fallible() {
+        let err: failure::Error = DownloadError::HttpStatus(404).into();
+        assert!(matches!(
+            DownloadError::from(err),
+            DownloadError::HttpStatus(404)
+        ));
+        let err: failure::Error = io::Error::other("disk").into();
+        assert!(matches!(DownloadError::from(err), DownloadError::Io(_)));
+        let err = failure::format_err!("something else");
+        match DownloadError::from(err) {
+            DownloadError::Other(e) => assert_eq!(e.to_string(), "something else"),
+            e => panic!("unexpected variant: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_chunk_offsets_smaller_than_chunk_size() {
+        assert_eq!(get_chunk_offsets(100, 512), vec![(0, 99)]);
+        assert_eq!(get_chunk_offsets(1, 512), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn test_chunk_offsets_empty() {
+        assert_eq!(get_chunk_offsets(0, 512), vec![]);
+    }
+
+    #[test]
+    fn test_chunk_offsets_exact_multiple() {
+        assert_eq!(get_chunk_offsets(512, 512), vec![(0, 511)]);
+        assert_eq!(get_chunk_offsets(1024, 512), vec![(0, 511), (512, 1023)]);
+    }
+
+    #[test]
+    fn test_chunk_offsets_remainder_joins_last_chunk() {
+        assert_eq!(get_chunk_offsets(1029, 512), vec![(0, 511), (512, 1028)]);
+        assert_eq!(get_chunk_offsets(513, 512), vec![(0, 512)]);
+    }
+
+    #[test]
+    fn test_missing_chunk_offsets() {
+        // out of order and overlapping pieces
+        let downloaded = vec![(100, 500), (200, 0), (50, 150), (100, 450)];
+        assert_eq!(
+            missing_chunk_offsets(downloaded, 2000, 512),
+            vec![(200, 449), (600, 1111), (1112, 1623), (1624, 1999)]
+        );
+        assert_eq!(missing_chunk_offsets(vec![(2000, 0)], 2000, 512), vec![]);
+        assert_eq!(missing_chunk_offsets(vec![], 100, 512), vec![(0, 99)]);
+    }
+
+    #[test]
+    fn test_chunk_offsets_cover_content() {
+        for ct_len in 1..2000 {
+            for &chunk_size in &[1, 7, 64, 512, 4096] {
+                let offsets = get_chunk_offsets(ct_len, chunk_size);
+                assert_eq!(offsets.first().unwrap().0, 0);
+                assert_eq!(offsets.last().unwrap().1, ct_len - 1);
+                for pair in offsets.windows(2) {
+                    assert_eq!(pair[0].1 + 1, pair[1].0);
+                }
+                let total: u64 = offsets.iter().map(|(start, end)| end - start + 1).sum();
+                assert_eq!(total, ct_len);
+            }
+        }
     }
 }
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future;
+use std::io::{Read, Write};
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::{bail, format_err, Fallible};
+use hyper::client::connect::dns::Name;
+use native_tls::TlsConnector;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+
+const DNS_PORT: u16 = 53;
+/// Servers on this port are asked over DNS over TLS (RFC 7858).
+const DOT_PORT: u16 = 853;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+/// Large enough for any answer sent over UDP without EDNS.
+const MAX_UDP_RESPONSE: usize = 512;
+
+/// Parses the `--dns-servers` list, e.g. `1.1.1.1,8.8.8.8` or
+/// `127.0.0.1:5353,[::1]:53`. Servers without a port use port 53, those
+/// on port 853 are asked over TLS.
+pub fn parse_dns_servers(list: &str) -> Fallible<Vec<SocketAddr>> {
+    list.split(',')
+        .map(|server| {
+            let server = server.trim();
+            server
+                .parse::<SocketAddr>()
+                .or_else(|_| {
+                    server
+                        .parse::<IpAddr>()
+                        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+                })
+                .map_err(|_| format_err!("invalid dns server: {:?}", server))
+        })
+        .collect()
+}
+
+/// Parses a `--verify-host` pin such as `example.com:80:127.0.0.1` or
+/// `example.com:80:[::1]` into the host, port and address to use for it.
+pub fn parse_host_pin(pin: &str) -> Fallible<(String, u16, IpAddr)> {
+    let invalid = || format_err!("invalid host pin, expected HOST:PORT:IP: {}", pin);
+    let mut parts = pin.splitn(3, ':');
+    let host = parts
+        .next()
+        .filter(|host| !host.is_empty())
+        .ok_or_else(invalid)?;
+    let port = parts
+        .next()
+        .and_then(|port| port.parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+    let addr = parts
+        .next()
+        .map(|addr| addr.trim_start_matches('[').trim_end_matches(']'))
+        .and_then(|addr| addr.parse::<IpAddr>().ok())
+        .ok_or_else(invalid)?;
+    Ok((host.to_owned(), port, addr))
+}
+
+/// Looks `host` up on `servers` in turn, asking for an IPv4 address first
+/// and an IPv6 one if there is none.
+pub fn resolve(host: &str, servers: &[SocketAddr], timeout: Duration) -> Fallible<IpAddr> {
+    let mut last_err = None;
+    'servers: for server in servers {
+        for &qtype in &[TYPE_A, TYPE_AAAA] {
+            match query(host, qtype, *server, timeout) {
+                Ok(Some(addr)) => return Ok(addr),
+                Ok(None) => {}
+                Err(e) => {
+                    last_err = Some(format_err!("dns server {}: {}", server, e));
+                    continue 'servers;
+                }
+            }
+        }
+        // a working server saying there is no address is the final word
+        bail!("could not resolve {}: no address found", host);
+    }
+    match last_err {
+        Some(e) => bail!("could not resolve {}: {}", host, e),
+        None => bail!("could not resolve {}: no dns servers given", host),
+    }
+}
+
+/// The resolver of a client made with `--dns-servers`, so that every host
+/// it connects to, including those redirected to, is looked up on them.
+/// Answers are kept for the life of the client.
+pub struct DnsServers {
+    servers: Vec<SocketAddr>,
+    timeout: Duration,
+    cache: Mutex<HashMap<String, IpAddr>>,
+}
+
+impl DnsServers {
+    pub fn new(servers: Vec<SocketAddr>, timeout: Duration) -> DnsServers {
+        DnsServers {
+            servers,
+            timeout,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lookup(&self, host: &str) -> Fallible<IpAddr> {
+        if let Some(&addr) = self.cache.lock().unwrap().get(host) {
+            return Ok(addr);
+        }
+        let addr = resolve(host, &self.servers, self.timeout)?;
+        self.cache.lock().unwrap().insert(host.to_owned(), addr);
+        Ok(addr)
+    }
+}
+
+impl Resolve for DnsServers {
+    fn resolve(&self, name: Name) -> Resolving {
+        // the lookup blocks, but the blocking client gives each client a
+        // runtime of its own, so only its other connections wait
+        let addrs = self
+            .lookup(name.as_str())
+            .map(|addr| Box::new(iter::once(SocketAddr::new(addr, 0))) as Addrs)
+            .map_err(|e| e.to_string().into());
+        Box::pin(future::ready(addrs))
+    }
+}
+
+/// The first `qtype` address `server` answers with for `host`.
+fn query(
+    host: &str,
+    qtype: u16,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Fallible<Option<IpAddr>> {
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos() as u16);
+    let msg = build_query(id, host, qtype)?;
+    let answer = if server.port() == DOT_PORT {
+        exchange_tls(&msg, server, timeout)?
+    } else {
+        exchange_udp(&msg, server, timeout)?
+    };
+    parse_response(&answer, id, qtype)
+}
+
+fn exchange_udp(msg: &[u8], server: SocketAddr, timeout: Duration) -> Fallible<Vec<u8>> {
+    let local: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+    socket.send(msg)?;
+    let mut buf = [0u8; MAX_UDP_RESPONSE];
+    let len = socket.recv(&mut buf)?;
+    Ok(buf[..len].to_vec())
+}
+
+fn exchange_tls(msg: &[u8], server: SocketAddr, timeout: Duration) -> Fallible<Vec<u8>> {
+    let tcp = TcpStream::connect_timeout(&server, timeout)?;
+    tcp.set_read_timeout(Some(timeout))?;
+    tcp.set_write_timeout(Some(timeout))?;
+    // public resolvers such as 1.1.1.1 have certificates for their address
+    let ip = server.ip().to_string();
+    let mut stream = TlsConnector::new()?
+        .connect(&ip, tcp)
+        .map_err(|e| format_err!("TLS handshake failed: {}", e))?;
+    exchange_framed(&mut stream, msg)
+}
+
+/// Sends `msg` and reads the answer, each behind the two byte length
+/// DNS messages carry over TCP and TLS.
+fn exchange_framed<S: Read + Write>(stream: &mut S, msg: &[u8]) -> Fallible<Vec<u8>> {
+    let mut framed = u16::try_from(msg.len())?.to_be_bytes().to_vec();
+    framed.extend_from_slice(msg);
+    stream.write_all(&framed)?;
+    stream.flush()?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut answer = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut answer)?;
+    Ok(answer)
+}
+
+/// A recursive query for the `qtype` records of `host`.
+fn build_query(id: u16, host: &str, qtype: u16) -> Fallible<Vec<u8>> {
+    let mut msg = Vec::with_capacity(host.len() + 18);
+    msg.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid host name: {}", host);
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(msg)
+}
+
+/// The first `qtype` address in the answer to query `id`, skipping other
+/// records such as the CNAMEs leading up to it.
+fn parse_response(msg: &[u8], id: u16, qtype: u16) -> Fallible<Option<IpAddr>> {
+    let truncated = || format_err!("truncated dns response");
+    if msg.len() < 12 || msg[..2] != id.to_be_bytes() || msg[2] & 0x80 == 0 {
+        bail!("not an answer to the query");
+    }
+    match msg[3] & 0x0f {
+        0 => {}
+        3 => return Ok(None),
+        rcode => bail!("server failure (rcode {})", rcode),
+    }
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+    let ancount = u16::from_be_bytes([msg[6], msg[7]]);
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(msg, pos).ok_or_else(truncated)? + 4;
+    }
+    for _ in 0..ancount {
+        pos = skip_name(msg, pos).ok_or_else(truncated)?;
+        let fixed = msg.get(pos..pos + 10).ok_or_else(truncated)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        pos += 10;
+        let rdata = msg.get(pos..pos + rdlength).ok_or_else(truncated)?;
+        pos += rdlength;
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) if qtype == TYPE_A => {
+                return Ok(Some(IpAddr::from([rdata[0], rdata[1], rdata[2], rdata[3]])));
+            }
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                return Ok(Some(IpAddr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// The position just past the possibly compressed name at `pos`.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            // a pointer to a name elsewhere ends this one
+            _ if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            _ => pos += len + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dns_servers() {
+        assert_eq!(
+            parse_dns_servers("1.1.1.1, 127.0.0.1:5353,[::1]:53").unwrap(),
+            vec![
+                "1.1.1.1:53".parse().unwrap(),
+                "127.0.0.1:5353".parse().unwrap(),
+                "[::1]:53".parse().unwrap(),
+            ]
+        );
+        assert_eq!(
+            parse_dns_servers("::1").unwrap(),
+            vec!["[::1]:53".parse().unwrap()]
+        );
+        assert!(parse_dns_servers("").is_err());
+        assert!(parse_dns_servers("dns.example.com").is_err());
+        assert!(parse_dns_servers("1.1.1.1,").is_err());
+    }
+
+    #[test]
+    fn test_parse_host_pin() {
+        assert_eq!(
+            parse_host_pin("example.com:443:10.0.0.7").unwrap(),
+            ("example.com".to_owned(), 443, IpAddr::from([10, 0, 0, 7]))
+        );
+        assert_eq!(
+            parse_host_pin("example.com:80:[::1]").unwrap(),
+            (
+                "example.com".to_owned(),
+                80,
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            )
+        );
+        assert_eq!(
+            parse_host_pin("example.com:80:::1").unwrap().2,
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+        assert!(parse_host_pin("example.com:80").is_err());
+        assert!(parse_host_pin(":80:10.0.0.7").is_err());
+        assert!(parse_host_pin("example.com:http:10.0.0.7").is_err());
+        assert!(parse_host_pin("example.com:80:gateway").is_err());
+    }
+
+    #[test]
+    fn test_build_query() {
+        assert_eq!(
+            build_query(0x1234, "a.bc.", TYPE_A).unwrap(),
+            vec![
+                0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 2, b'b', b'c', 0, 0, 1, 0,
+                1
+            ]
+        );
+        assert!(build_query(1, "a..b", TYPE_A).is_err());
+    }
+
+    fn answer(id: u16, rcode: u8, records: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut msg = build_query(id, "files.test", TYPE_A).unwrap();
+        msg[2] |= 0x80;
+        msg[3] |= rcode;
+        msg[7] = records.len() as u8;
+        for (rtype, rdata) in records {
+            // a pointer back to the question's name
+            msg.extend_from_slice(&[0xc0, 12]);
+            msg.extend_from_slice(&rtype.to_be_bytes());
+            msg.extend_from_slice(&[0, 1, 0, 0, 0, 60, 0, rdata.len() as u8]);
+            msg.extend_from_slice(rdata);
+        }
+        msg
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let cname: &[u8] = &[3, b'c', b'd', b'n', 0];
+        let msg = answer(7, 0, &[(5, cname), (TYPE_A, &[127, 0, 0, 1])]);
+        assert_eq!(
+            parse_response(&msg, 7, TYPE_A).unwrap(),
+            Some(IpAddr::from([127, 0, 0, 1]))
+        );
+        assert_eq!(parse_response(&msg, 7, TYPE_AAAA).unwrap(), None);
+        let v6 = Ipv6Addr::LOCALHOST.octets();
+        let msg = answer(7, 0, &[(TYPE_AAAA, &v6)]);
+        assert_eq!(
+            parse_response(&msg, 7, TYPE_AAAA).unwrap(),
+            Some(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        );
+        // no such domain
+        assert_eq!(parse_response(&answer(7, 3, &[]), 7, TYPE_A).unwrap(), None);
+        assert!(parse_response(&answer(7, 2, &[]), 7, TYPE_A).is_err());
+        assert!(parse_response(&answer(8, 0, &[]), 7, TYPE_A).is_err());
+        let msg = answer(7, 0, &[(TYPE_A, &[127, 0, 0, 1])]);
+        assert!(parse_response(&msg[..msg.len() - 2], 7, TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_dns_servers_keep_answers() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        // answers one query, then goes away
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0u8; MAX_UDP_RESPONSE];
+            let (_, peer) = socket.recv_from(&mut buf).unwrap();
+            let id = u16::from_be_bytes([buf[0], buf[1]]);
+            let reply = answer(id, 0, &[(TYPE_A, &[127, 0, 0, 9])]);
+            socket.send_to(&reply, peer).unwrap();
+        });
+        let resolver = DnsServers::new(vec![server], Duration::from_secs(2));
+        let addr = IpAddr::from([127, 0, 0, 9]);
+        assert_eq!(resolver.lookup("files.test").unwrap(), addr);
+        handle.join().unwrap();
+        assert_eq!(resolver.lookup("files.test").unwrap(), addr);
+        assert!(DnsServers::new(vec![server], Duration::from_millis(100))
+            .lookup("files.test")
+            .is_err());
+    }
+
+    /// A stream that records what is written and replays `input`.
+    struct Pipe {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_exchange_framed() {
+        let query = build_query(7, "files.test", TYPE_A).unwrap();
+        let reply = answer(7, 0, &[(TYPE_A, &[127, 0, 0, 1])]);
+        let mut input = (reply.len() as u16).to_be_bytes().to_vec();
+        input.extend_from_slice(&reply);
+        let mut pipe = Pipe {
+            input: std::io::Cursor::new(input),
+            output: Vec::new(),
+        };
+        assert_eq!(exchange_framed(&mut pipe, &query).unwrap(), reply);
+        assert_eq!(pipe.output[..2], (query.len() as u16).to_be_bytes());
+        assert_eq!(pipe.output[2..], query[..]);
+
+        // the answer stops short of its length
+        let mut pipe = Pipe {
+            input: std::io::Cursor::new(vec![0, 40, 0, 7]),
+            output: Vec::new(),
+        };
+        assert!(exchange_framed(&mut pipe, &query).is_err());
+    }
+}
@@ -1,29 +1,83 @@
-use std::fs;
-use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use chrono::{DateTime, Utc};
 use clap::ArgMatches;
-use console::style;
-use failure::{format_err, Fallible};
-use indicatif::{HumanBytes, ProgressBar};
+use console::{style, Term};
+use failure::{bail, format_err, Fallible};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar};
 use reqwest::blocking::Client;
 use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{Method, StatusCode};
 
-use url::Url;
+use url::{Host, Position, Url};
 
-use crate::bar::create_progress_bar;
-use crate::core::{Config, EventsHandler, FtpDownload, HttpDownload};
-use crate::utils::{decode_percent_encoded_data, get_file_handle};
+use crate::aria2::load_control_file;
+use crate::auth::{find_credential, AuthScheme, DigestCredentialStore};
+use crate::bandwidth::{BandwidthEstimator, BandwidthRecorder};
+use crate::bar::{create_progress_bar, progress_draw_target, TermProgress};
+use crate::checkpoint::{load_checkpoint, save_checkpoint, CheckpointData};
+use crate::clipboard::{copy_to_clipboard, MAX_CLIPBOARD_SIZE};
+use crate::core::{
+    build_client, missing_chunk_offsets, send_retrying, Config, DownloadError, DownloadStats,
+    EventsHandler, FtpDownload, HttpDownload, RequestBody, RetryBudget, Semaphore, Verbosity,
+};
+use crate::dns::{parse_dns_servers, parse_host_pin};
+use crate::gemini::GeminiDownload;
+use crate::handler::FdProgressHandler;
+use crate::io::{read_synced_offset, ResumableWriter, DEFAULT_SYNC_INTERVAL_BYTES};
+use crate::progress::Progress;
+use crate::rewrite::{local_name, mirror_path, LinkConverter};
+use crate::robots::Robots;
+use crate::s3::signed_url;
+use crate::sftp::SftpDownload;
+use crate::sha256::{parse_hex_digest, to_hex, Sha256};
+use crate::sha512::Sha512;
+use crate::spider::{
+    extract_links, extract_page_requisites, is_below_page, is_same_host, ExtensionFilter,
+};
+use crate::storage::{StdoutStorage, Storage, VecStorage};
+use crate::utils::{
+    decode_form_encoded, decode_percent_encoded_data, default_download_dir, download_dir,
+    get_file_handle, http_date, mime_extension, normalize_filename, open_progress_fd,
+    parse_http_date, parse_mode, parse_range, parse_ranges, parse_size, parse_user_agents,
+    pause_on_sigusr1, plain_file_name, preallocate, redirect_stderr, set_file_mode, set_sparse,
+    state_file, state_suffix, status_on_sigusr2, NamingStrategy,
+};
+use crate::webdav::propfind;
 
-fn request_headers_from_server(url: &Url, timeout: u64, ua: &str) -> Fallible<HeaderMap> {
-    let resp = Client::new()
-        .get(url.as_ref())
-        .timeout(Duration::from_secs(timeout))
-        .header(header::USER_AGENT, HeaderValue::from_str(ua)?)
-        .header(header::ACCEPT, HeaderValue::from_str("*/*")?)
-        .send()?;
-    Ok(resp.headers().clone())
+const MIN_CHUNK_SIZE: u64 = 4096;
+const MAX_CHUNK_SIZE: u64 = 256 * 1024 * 1024;
+/// Below hyper's own limit on the header block, so that this one is the
+/// one that applies.
+const DEFAULT_HEADER_SIZE_LIMIT: u64 = 256 * 1024;
+const DEFAULT_MAX_CONNECTIONS_TOTAL: usize = 32;
+const DEFAULT_RECURSIVE_LEVEL: u32 = 5;
+/// How many links deep `--mirror` goes without `--level`.
+const DEFAULT_MIRROR_LEVEL: u32 = 20;
+
+fn request_headers_from_server(
+    client: &Client,
+    url: &Url,
+    conf: &Config,
+) -> Fallible<(StatusCode, HeaderMap)> {
+    let user_agent = HeaderValue::from_str(&conf.user_agent)?;
+    let resp = send_retrying(conf, || {
+        client
+            .get(url.as_ref())
+            .timeout(Duration::from_secs(conf.timeout))
+            .headers(conf.headers.clone())
+            .header(header::USER_AGENT, user_agent.clone())
+            .header(header::ACCEPT, HeaderValue::from_static("*/*"))
+    })?;
+    Ok((resp.status(), resp.headers().clone()))
 }
 
 fn print_headers(headers: HeaderMap) {
@@ -36,12 +90,59 @@ fn print_headers(headers: HeaderMap) {
     }
 }
 
+/// Sends a real HEAD request and prints the status line and headers, for
+/// servers that answer HEAD differently from the GET probe of `--headers`.
+fn print_head_response(client: &Client, url: &Url, conf: &Config) -> Fallible<()> {
+    let user_agent = HeaderValue::from_str(&conf.user_agent)?;
+    let resp = send_retrying(conf, || {
+        client
+            .head(url.as_ref())
+            .timeout(Duration::from_secs(conf.timeout))
+            .headers(conf.headers.clone())
+            .header(header::USER_AGENT, user_agent.clone())
+            .header(header::ACCEPT, HeaderValue::from_static("*/*"))
+    })?;
+    println!("{:?} {}", resp.version(), resp.status());
+    print_headers(resp.headers().clone());
+    if !resp.status().is_success() {
+        return Err(DownloadError::HttpStatus(resp.status().as_u16()).into());
+    }
+    Ok(())
+}
+
+/// Looks up the `--digest-auth-file` credential for `url`, unless the url
+/// carries a user name of its own.
+fn credential_header(url: &Url, conf: &Config) -> Fallible<Option<HeaderValue>> {
+    let path = match conf.credentials_file {
+        Some(ref path) if url.username().is_empty() => path,
+        _ => return Ok(None),
+    };
+    let store = DigestCredentialStore::load(path)?;
+    find_credential(&store, url)
+        .map(AuthScheme::header_value)
+        .transpose()
+}
+
+fn print_ftp_file_info(url: Url, port: Option<u16>) -> Fallible<()> {
+    let info = FtpDownload::new(url).port(port).file_info()?;
+    let size = info.size.map(|size| size.to_string());
+    for (name, val) in [("size", size), ("modified", info.modified)].iter() {
+        println!(
+            "{}: {}",
+            style(name).red(),
+            style(val.as_deref().unwrap_or("unknown")).green()
+        );
+    }
+    Ok(())
+}
+
 fn get_resume_chunk_offsets(
     fname: &str,
+    state_suffix: &str,
     ct_len: u64,
     chunk_size: u64,
 ) -> Fallible<Vec<(u64, u64)>> {
-    let st_fname = format!("{}.st", fname);
+    let st_fname = state_file(fname, state_suffix, "st");
     let input = fs::File::open(st_fname)?;
     let buf = BufReader::new(input);
     let mut downloaded = vec![];
@@ -51,29 +152,64 @@ fn get_resume_chunk_offsets(
         let n = (l[0].parse::<u64>()?, l[1].parse::<u64>()?);
         downloaded.push(n);
     }
-    downloaded.sort_by_key(|a| a.1);
-    let mut chunks = vec![];
+    Ok(missing_chunk_offsets(downloaded, ct_len, chunk_size))
+}
+
+/// The `-O` argument, or the downloads folder with `--to-downloads`.
+fn output_name(args: &ArgMatches) -> Fallible<Option<String>> {
+    if let Some(fname) = args.value_of("FILE") {
+        return Ok(Some(fname.to_owned()));
+    }
+    if args.is_present("to_downloads") {
+        let dir = download_dir(default_download_dir)?;
+        return Ok(Some(dir.to_string_lossy().into_owned()));
+    }
+    Ok(None)
+}
 
-    let mut i: u64 = 0;
-    for (bc, offset) in downloaded {
-        if i == offset {
-            i = offset + bc;
-        } else {
-            chunks.push((i, offset - 1));
-            i = offset + bc;
+/// `gen_filename` for the command line, with `--normalize-filename` applied
+/// to names that `-O` doesn't give in full, or the place of `url` in the
+/// `--mirror` directory.
+fn output_filename(
+    url: &Url,
+    out: Option<&str>,
+    headers: Option<&HeaderMap>,
+    args: &ArgMatches,
+) -> Fallible<String> {
+    if let Some(dir) = args.value_of("MIRROR") {
+        let path = Path::new(dir).join(mirror_path(url));
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
         }
+        return Ok(path.to_string_lossy().into_owned());
     }
-
-    while (ct_len - i) > chunk_size {
-        chunks.push((i, i + chunk_size - 1));
-        i += chunk_size;
+    let fname = gen_filename(url, out, headers, naming_strategy(args)?)?;
+    let given = out.is_some_and(|out| !is_output_dir(out));
+    if !args.is_present("NORMALIZE_FILENAME") || given {
+        return Ok(fname);
     }
-    chunks.push((i, ct_len));
-
-    Ok(chunks)
+    let lowercase = args.value_of("NORMALIZE_FILENAME") == Some("lower");
+    let path = Path::new(&fname);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = normalize_filename(&name, lowercase);
+    // the -O directory is left as it is
+    Ok(
+        match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) => dir.join(name).to_string_lossy().into_owned(),
+            None => name,
+        },
+    )
 }
 
-fn gen_filename(url: &Url, fname: Option<&str>, headers: Option<&HeaderMap>) -> String {
+fn gen_filename(
+    url: &Url,
+    fname: Option<&str>,
+    headers: Option<&HeaderMap>,
+    strategy: NamingStrategy,
+) -> Fallible<String> {
     let content_disposition = headers
         .and_then(|hdrs| hdrs.get(header::CONTENT_DISPOSITION))
         .and_then(|val| {
@@ -87,49 +223,101 @@ fn gen_filename(url: &Url, fname: Option<&str>, headers: Option<&HeaderMap>) ->
         .and_then(|val| {
             let x = val
                 .rsplit(';')
-                .nth(0)
+                .next()
                 .unwrap_or("")
                 .rsplit('=')
-                .nth(0)
+                .next()
                 .unwrap_or("")
                 .trim_start_matches('"')
                 .trim_end_matches('"');
-            if !x.is_empty() {
-                Some(x.to_string())
-            } else {
-                None
-            }
+            plain_file_name(x).map(|x| x.to_string())
         });
-    match fname {
-        Some(name) => name.to_owned(),
-        None => match content_disposition {
-            Some(val) => val,
-            None => {
-                let name = &url.path().split('/').last().unwrap_or("");
-                if !name.is_empty() {
-                    match decode_percent_encoded_data(name) {
-                        Ok(val) => val,
-                        _ => name.to_string(),
-                    }
-                } else {
-                    "index.html".to_owned()
-                }
+    let url_name = || {
+        let name = &url.path().split('/').next_back().unwrap_or("");
+        if !name.is_empty() {
+            let decoded = decode_percent_encoded_data(name);
+            let decoded = decoded.as_deref().unwrap_or(name);
+            plain_file_name(decoded).unwrap_or("index.html").to_owned()
+        } else if let Some(name) = query_filename(url) {
+            name
+        } else {
+            // nothing to go on but the type, e.g. for `/api/export/`
+            let ext = headers
+                .and_then(|hdrs| hdrs.get(header::CONTENT_TYPE))
+                .and_then(|val| val.to_str().ok())
+                .and_then(mime_extension);
+            match ext {
+                Some(ext) => format!("download.{}", ext),
+                None => "index.html".to_owned(),
             }
+        }
+    };
+    let derived_name = || strategy.name(url, content_disposition, url_name, Utc::now());
+    Ok(match fname {
+        // an output directory keeps the name we would have picked anyway
+        Some(dir) if is_output_dir(dir) => Path::new(dir)
+            .join(derived_name()?)
+            .to_string_lossy()
+            .into_owned(),
+        Some(name) => name.to_owned(),
+        None => derived_name()?,
+    })
+}
+
+/// The `filename` query parameter of urls like `/download?filename=a+b.pdf`,
+/// without any directories in it.
+fn query_filename(url: &Url) -> Option<String> {
+    let val = url
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("filename="))?;
+    let name = decode_form_encoded(val).ok()?;
+    plain_file_name(&name).map(|name| name.to_owned())
+}
+
+/// The `--proxy-auth USER:PASS` credentials, or the same from the
+/// `PROXY_AUTH` environment variable so they stay out of the process list.
+fn proxy_auth(args: &ArgMatches) -> Fallible<Option<(String, String)>> {
+    let val = match args.value_of("PROXY_AUTH") {
+        Some(val) => val.to_owned(),
+        None => match env::var("PROXY_AUTH") {
+            Ok(val) => val,
+            Err(_) => return Ok(None),
         },
+    };
+    if !args.is_present("PROXY") {
+        bail!("proxy credentials need a --proxy to send them to");
+    }
+    // the value itself is never echoed, it holds the password
+    match val.split_once(':') {
+        Some((user, pass)) if !user.is_empty() => Ok(Some((user.to_owned(), pass.to_owned()))),
+        _ => bail!("proxy credentials must look like USER:PASS"),
+    }
+}
+
+/// The `--output-naming` strategy, `url-last` by default.
+fn naming_strategy(args: &ArgMatches) -> Fallible<NamingStrategy> {
+    match args.value_of("OUTPUT_NAMING") {
+        Some(val) => val.parse(),
+        None => Ok(NamingStrategy::UrlLast),
     }
 }
 
-fn calc_bytes_on_disk(fname: &str) -> Fallible<Option<u64>> {
+fn is_output_dir(name: &str) -> bool {
+    name.ends_with('/') || name.ends_with(MAIN_SEPARATOR) || Path::new(name).is_dir()
+}
+
+fn calc_bytes_on_disk(fname: &str, state_suffix: Option<&str>) -> Fallible<Option<u64>> {
     // use state file if present
-    let st_fname = format!("{}.st", fname);
-    if Path::new(&st_fname).exists() {
+    let st_fname = state_suffix.map(|suffix| state_file(fname, suffix, "st"));
+    if let Some(st_fname) = st_fname.filter(|st_fname| Path::new(st_fname).exists()) {
         let input = fs::File::open(st_fname)?;
         let buf = BufReader::new(input);
         let mut byte_count: u64 = 0;
         for line in buf.lines() {
             let num_of_bytes = line?
                 .split(':')
-                .nth(0)
+                .next()
                 .ok_or_else(|| format_err!("failed to split state file line"))?
                 .parse::<u64>()?;
             byte_count += num_of_bytes;
@@ -142,10 +330,166 @@ fn calc_bytes_on_disk(fname: &str) -> Fallible<Option<u64>> {
     }
 }
 
-fn prep_headers(fname: &str, resume: bool, user_agent: &str) -> Fallible<HeaderMap> {
-    let bytes_on_disk = calc_bytes_on_disk(fname)?;
+/// Cuts a single connection download back to the offset its writer last
+/// synced to disk, dropping a tail that a crash may have left as garbage.
+/// Concurrent downloads keep their record in the `st` state file instead.
+fn trim_to_synced(fname: &str, state_suffix: &str, verbosity: Verbosity) -> Fallible<()> {
+    let synced = match read_synced_offset(Path::new(&state_file(fname, state_suffix, "synced"))) {
+        Some(offset) => offset,
+        None => return Ok(()),
+    };
+    let len = match fs::metadata(fname) {
+        Ok(meta) => meta.len(),
+        Err(_) => return Ok(()),
+    };
+    if len > synced {
+        if verbosity != Verbosity::Quiet {
+            eprintln!(
+                "{}",
+                style(format!(
+                    "warning: dropping the last {} of {}, they may not have reached the disk",
+                    HumanBytes(len - synced),
+                    fname
+                ))
+                .yellow()
+            );
+        }
+        OpenOptions::new()
+            .write(true)
+            .open(fname)?
+            .set_len(synced)?;
+    }
+    Ok(())
+}
+
+/// The remote file a download was started against, kept in
+/// the `target` state file of the download so that `--continue` can tell whether it changed.
+#[derive(Debug, Clone, PartialEq)]
+struct ResumeTarget {
+    length: u64,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl ResumeTarget {
+    fn from_headers(length: u64, headers: &HeaderMap) -> ResumeTarget {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|val: &HeaderValue| val.to_str().ok())
+                .map(|val| val.to_owned())
+        };
+        ResumeTarget {
+            length,
+            etag: value(header::ETAG),
+            last_modified: value(header::LAST_MODIFIED),
+        }
+    }
+
+    fn load(fname: &str, state_suffix: &str) -> Fallible<Option<ResumeTarget>> {
+        let path = state_file(fname, state_suffix, "target");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut target = ResumeTarget {
+            length: 0,
+            etag: None,
+            last_modified: None,
+        };
+        for line in contents.lines() {
+            match line.split_once(':') {
+                Some(("length", val)) => target.length = val.parse()?,
+                Some(("etag", val)) => target.etag = Some(val.to_owned()),
+                Some(("last-modified", val)) => target.last_modified = Some(val.to_owned()),
+                _ => bail!("invalid line in {}: {}", path, line),
+            }
+        }
+        Ok(Some(target))
+    }
+
+    fn save(&self, fname: &str, state_suffix: &str) -> Fallible<()> {
+        let mut contents = format!("length:{}\n", self.length);
+        if let Some(ref etag) = self.etag {
+            contents.push_str(&format!("etag:{}\n", etag));
+        }
+        if let Some(ref modified) = self.last_modified {
+            contents.push_str(&format!("last-modified:{}\n", modified));
+        }
+        fs::write(state_file(fname, state_suffix, "target"), contents)?;
+        Ok(())
+    }
+}
+
+/// Explains why `bytes_on_disk` can't be resumed against `remote`, given
+/// what was `recorded` when the download started. `None` means resume.
+fn restart_reason(
+    bytes_on_disk: u64,
+    remote: &ResumeTarget,
+    recorded: Option<&ResumeTarget>,
+) -> Option<String> {
+    if remote.length < bytes_on_disk {
+        return Some(format!(
+            "the remote file ({} bytes) is smaller than the local one ({} bytes)",
+            remote.length, bytes_on_disk
+        ));
+    }
+    let recorded = recorded?;
+    if recorded.length != remote.length {
+        return Some(format!(
+            "the remote file changed size from {} to {} bytes",
+            recorded.length, remote.length
+        ));
+    }
+    let changed = |old: &Option<String>, new: &Option<String>| match (old, new) {
+        (Some(old), Some(new)) => old != new,
+        _ => false,
+    };
+    if changed(&recorded.etag, &remote.etag) {
+        return Some("the remote file's ETag changed".to_owned());
+    }
+    if changed(&recorded.last_modified, &remote.last_modified) {
+        return Some("the remote file's Last-Modified date changed".to_owned());
+    }
+    None
+}
+
+/// Adds the headers a browser sends when following a link to `url`, for
+/// servers that turn other clients away. Headers already set win.
+fn add_browser_headers(headers: &mut HeaderMap, url: &Url) -> Fallible<()> {
+    let preset = [
+        (header::ORIGIN, url.origin().ascii_serialization()),
+        (header::ACCEPT_LANGUAGE, "en-US,en;q=0.9".to_owned()),
+        (header::UPGRADE_INSECURE_REQUESTS, "1".to_owned()),
+    ];
+    for (name, val) in preset.iter() {
+        headers.entry(name).or_insert(val.parse()?);
+    }
+    let fetch_metadata = [
+        ("sec-fetch-dest", "document"),
+        ("sec-fetch-mode", "navigate"),
+        ("sec-fetch-site", "none"),
+        ("sec-fetch-user", "?1"),
+    ];
+    for &(name, val) in fetch_metadata.iter() {
+        headers.entry(name).or_insert(HeaderValue::from_static(val));
+    }
+    Ok(())
+}
+
+fn prep_headers(
+    fname: &str,
+    state_suffix: &str,
+    resume: bool,
+    range: Option<(u64, Option<u64>)>,
+    user_agent: &str,
+) -> Fallible<HeaderMap> {
+    let bytes_on_disk = calc_bytes_on_disk(fname, Some(state_suffix))?;
     let mut headers = HeaderMap::new();
-    if let Some(bcount) = bytes_on_disk {
+    if let Some(range) = range {
+        headers.insert(header::RANGE, format_range(range).parse()?);
+    } else if let Some(bcount) = bytes_on_disk {
         if resume {
             let byte_range = format!("bytes={}-", bcount);
             headers.insert(header::RANGE, byte_range.parse()?);
@@ -157,241 +501,2418 @@ fn prep_headers(fname: &str, resume: bool, user_agent: &str) -> Fallible<HeaderM
     Ok(headers)
 }
 
-pub fn ftp_download(url: Url, quiet_mode: bool, filename: Option<&str>) -> Fallible<()> {
-    let fname = gen_filename(&url, filename, None);
+/// Formats a `--range` as the value of a `Range` header.
+fn format_range(range: (u64, Option<u64>)) -> String {
+    match range {
+        (start, Some(end)) => format!("bytes={}-{}", start, end),
+        (start, None) => format!("bytes={}-", start),
+    }
+}
 
-    let mut client = FtpDownload::new(url.clone());
-    let events_handler = DefaultEventsHandler::new(&fname, false, false, quiet_mode)?;
-    client.events_hook(events_handler).download()?;
-    Ok(())
+/// Opens the `--progress-to-fd` descriptor, if one was given.
+fn progress_fd(args: &ArgMatches) -> Fallible<Option<fs::File>> {
+    match args.value_of("PROGRESS_FD") {
+        Some(fd) => Ok(Some(open_progress_fd(fd.parse::<i32>()?)?)),
+        None => Ok(None),
+    }
 }
 
-pub fn http_download(url: Url, args: &ArgMatches, version: &str) -> Fallible<()> {
-    let resume_download = args.is_present("continue");
-    let concurrent_download = !args.is_present("singlethread");
-    let user_agent = args
-        .value_of("AGENT")
-        .unwrap_or(&format!("Duma/{}", version))
-        .to_owned();
-    let timeout = if let Some(secs) = args.value_of("SECONDS") {
-        secs.parse::<u64>()?
-    } else {
-        30u64
-    };
-    let num_workers = if let Some(num) = args.value_of("NUM_CONNECTIONS") {
-        num.parse::<usize>()?
-    } else {
-        8usize
+/// Reads `--progress-fd`, pointing stderr at descriptors above 2 so that
+/// the bar can be drawn there.
+fn progress_bar_fd(args: &ArgMatches, fname: &str) -> Fallible<i32> {
+    let fd = match args.value_of("PROGRESS_BAR_FD") {
+        Some(fd) => fd.parse::<i32>()?,
+        None => return Ok(2),
     };
-    let headers = request_headers_from_server(&url, timeout, &user_agent)?;
-    let fname = gen_filename(&url, args.value_of("FILE"), Some(&headers));
+    match fd {
+        1 if fname == "-" => bail!("--progress-fd 1 would mix the bar into the output on stdout"),
+        1 | 2 => {}
+        fd if fd > 2 => redirect_stderr(fd)?,
+        fd => bail!("invalid progress file descriptor: {}", fd),
+    }
+    Ok(fd)
+}
 
-    // early exit if headers flag is present
-    if args.is_present("headers") {
-        print_headers(headers);
-        return Ok(());
+/// Reads the `--max-time` argument, in seconds.
+fn parse_max_time(args: &ArgMatches) -> Fallible<Option<Duration>> {
+    match args.value_of("MAX_TIME") {
+        Some(secs) => Ok(Some(Duration::from_secs(secs.parse::<u64>()?))),
+        None => Ok(None),
     }
-    let ct_len = if let Some(val) = headers.get("Content-Length") {
-        val.to_str()?.parse::<u64>().unwrap_or(0)
-    } else {
-        0u64
-    };
+}
 
-    let headers = prep_headers(&fname, resume_download, &user_agent)?;
+/// The `-U` user agent, or one picked at random from `--user-agent-file`
+/// so that repeated downloads don't all look alike.
+fn user_agent(args: &ArgMatches, version: &str) -> Fallible<String> {
+    if let Some(path) = args.value_of("USER_AGENT_FILE") {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format_err!("failed to read user agents {}: {}", path, e))?;
+        let agents = parse_user_agents(&contents)?;
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos() as usize);
+        return Ok(agents[(seed ^ process::id() as usize) % agents.len()].clone());
+    }
+    Ok(args
+        .value_of("AGENT")
+        .map_or_else(|| format!("Duma/{}", version), str::to_owned))
+}
 
-    let state_file_exists = Path::new(&format!("{}.st", fname)).exists();
-    let chunk_size = 512_000u64;
+/// Whether to send `--term-progress` sequences, which only make sense on
+/// a terminal.
+fn term_progress(args: &ArgMatches) -> bool {
+    args.is_present("term_progress") && Term::stderr().is_term()
+}
 
-    let chunk_offsets =
-        if state_file_exists && resume_download && concurrent_download && ct_len != 0 {
-            Some(get_resume_chunk_offsets(&fname, ct_len, chunk_size)?)
-        } else {
-            None
-        };
+/// The `Config::max_retries` for `--tries` and `--retry-forever`. Like in
+/// wget, N tries are N - 1 retries and 0 tries means no limit.
+fn max_retries(args: &ArgMatches) -> Fallible<i32> {
+    if args.is_present("retry_forever") {
+        return Ok(-1);
+    }
+    match args
+        .value_of("TRIES")
+        .map(|val| val.parse::<i32>())
+        .transpose()?
+    {
+        None => Ok(100),
+        Some(0) => Ok(-1),
+        Some(tries) if tries > 0 => Ok(tries - 1),
+        Some(tries) => bail!("invalid number of tries: {}", tries),
+    }
+}
 
-    let bytes_on_disk = if resume_download {
-        calc_bytes_on_disk(&fname)?
-    } else {
-        None
+/// The method of `--request`, PUT by default with `--upload-file` and GET
+/// otherwise, and the body to send with it.
+fn request_method(args: &ArgMatches) -> Fallible<(Method, Option<RequestBody>)> {
+    let upload = args.value_of("UPLOAD_FILE");
+    let method = match (args.value_of("METHOD"), upload) {
+        (Some(method), _) => method.parse::<Method>()?,
+        (None, Some(_)) => Method::PUT,
+        (None, None) => Method::GET,
     };
+    if upload.is_some() && method != Method::PUT && method != Method::PATCH {
+        bail!("--upload-file needs --request PUT or PATCH");
+    }
+    if method != Method::GET {
+        let unsupported = ["continue", "RANGE", "RANGES", "RESUME_CHECKPOINT"];
+        if unsupported.iter().any(|arg| args.is_present(arg)) {
+            bail!(
+                "--request {} can't be combined with --continue, --range, --ranges or --resume-from-checkpoint",
+                method
+            );
+        }
+    }
+    let body = upload.map(|path| RequestBody::File(PathBuf::from(path)));
+    Ok((method, body))
+}
 
-    let conf = Config {
-        user_agent: user_agent.clone(),
-        resume: resume_download,
-        headers,
-        file: fname.clone(),
-        timeout,
-        concurrent: concurrent_download,
-        max_retries: 100,
-        num_workers,
-        bytes_on_disk,
-        chunk_offsets,
-        chunk_size,
-    };
+/// The `--dns-servers` lists joined, or `None` to use the system resolver.
+fn dns_servers(args: &ArgMatches) -> Fallible<Option<Vec<SocketAddr>>> {
+    match args.values_of("DNS_SERVERS") {
+        Some(lists) => Ok(Some(
+            lists
+                .map(parse_dns_servers)
+                .collect::<Fallible<Vec<_>>>()?
+                .concat(),
+        )),
+        None => Ok(None),
+    }
+}
 
-    let mut client = HttpDownload::new(url.clone(), conf.clone());
-    let quiet_mode = args.is_present("quiet");
-    let events_handler =
-        DefaultEventsHandler::new(&fname, resume_download, concurrent_download, quiet_mode)?;
-    client.events_hook(events_handler).download()?;
-    Ok(())
+/// The address `--verify-host` gives for the host and port of `url`, or
+/// `None` to look the host up.
+fn pinned_address(url: &Url, args: &ArgMatches) -> Fallible<Option<IpAddr>> {
+    let pins = args
+        .values_of("VERIFY_HOST")
+        .into_iter()
+        .flatten()
+        .map(parse_host_pin)
+        .collect::<Fallible<Vec<_>>>()?;
+    let domain = match url.host() {
+        Some(Host::Domain(domain)) => domain,
+        _ => return Ok(None),
+    };
+    let port = url.port_or_known_default();
+    Ok(pins
+        .iter()
+        .find(|(host, pin_port, _)| host.eq_ignore_ascii_case(domain) && Some(*pin_port) == port)
+        .map(|&(_, _, addr)| addr))
 }
 
-pub struct DefaultEventsHandler {
-    prog_bar: Option<ProgressBar>,
-    bytes_on_disk: Option<u64>,
-    fname: String,
-    file: BufWriter<fs::File>,
-    st_file: Option<BufWriter<fs::File>>,
-    server_supports_resume: bool,
-    quiet_mode: bool,
+/// The `--output-permissions` mode, which only Unix systems have.
+fn output_permissions(args: &ArgMatches) -> Fallible<Option<u32>> {
+    let mode = args
+        .value_of("OUTPUT_PERMISSIONS")
+        .map(parse_mode)
+        .transpose()?;
+    if mode.is_some() && cfg!(not(unix)) {
+        eprintln!(
+            "{}",
+            style("warning: --output-permissions has no effect on this platform").yellow()
+        );
+        return Ok(None);
+    }
+    Ok(mode)
 }
 
-impl DefaultEventsHandler {
-    pub fn new(
-        fname: &str,
-        resume: bool,
-        concurrent: bool,
-        quiet_mode: bool,
-    ) -> Fallible<DefaultEventsHandler> {
-        let st_file = if concurrent {
-            Some(BufWriter::new(get_file_handle(
-                &format!("{}.st", fname),
-                resume,
-                true,
-            )?))
-        } else {
-            None
-        };
-        Ok(DefaultEventsHandler {
-            prog_bar: None,
-            bytes_on_disk: calc_bytes_on_disk(fname)?,
-            fname: fname.to_owned(),
-            file: BufWriter::new(get_file_handle(fname, resume, !concurrent)?),
-            st_file,
-            server_supports_resume: false,
-            quiet_mode,
+/// The `--ftp-port` argument.
+fn ftp_port(args: &ArgMatches) -> Fallible<Option<u16>> {
+    args.value_of("FTP_PORT")
+        .map(|port| {
+            port.parse::<u16>()
+                .map_err(|_| format_err!("invalid --ftp-port: {}", port))
         })
-    }
+        .transpose()
+}
 
-    fn create_prog_bar(&mut self, length: Option<u64>) {
-        let byte_count = if self.server_supports_resume {
-            self.bytes_on_disk
-        } else {
-            None
-        };
-        if let Some(len) = length {
-            let exact = style(len).green();
-            let human_readable = style(format!("{}", HumanBytes(len))).red();
+pub fn ftp_download(
+    url: Url,
+    args: &ArgMatches,
+    verbosity: Verbosity,
+) -> Result<(), DownloadError> {
+    run_ftp_download(url, args, verbosity).map_err(DownloadError::from)
+}
 
-            println!("Length: {} ({})", exact, human_readable);
-        } else {
-            println!("Length: {}", style("unknown").red());
-        }
+/// The handler for FTP, SFTP and Gemini transfers, which stream the file in one
+/// piece. Only FTP can `resume`, appending to the file on disk.
+fn streaming_events_handler(
+    fname: &str,
+    args: &ArgMatches,
+    verbosity: Verbosity,
+    resume: bool,
+) -> Fallible<DefaultEventsHandler> {
+    let bar_fd = progress_bar_fd(args, fname)?;
+    Ok(DefaultEventsHandler::new(
+        fname,
+        None,
+        resume,
+        false,
+        verbosity,
+        false,
+        args.value_of("STATS_FILE"),
+    )?
+    .show_progress(!args.is_present("no_progress"))
+    .ascii_progress(args.is_present("ascii_progress"))
+    .progress_fd(bar_fd)
+    .term_progress(term_progress(args))
+    .save_to_clipboard(args.is_present("save_to_clipboard"))
+    .expected_sha256(args.value_of("SHA256").map(parse_hex_digest).transpose()?)
+    .output_hash(output_hash(args))
+    .output_permissions(output_permissions(args)?))
+}
 
-        let prog_bar = create_progress_bar(&self.fname, length);
-        if let Some(count) = byte_count {
-            prog_bar.inc(count);
+fn run_ftp_download(url: Url, args: &ArgMatches, verbosity: Verbosity) -> Fallible<()> {
+    if args.is_present("head") {
+        return print_ftp_file_info(url, ftp_port(args)?);
+    }
+    let fname = output_filename(&url, output_name(args)?.as_deref(), None, args)?;
+
+    let port = ftp_port(args)?;
+    if args.is_present("timestamping") {
+        let info = FtpDownload::new(url.clone()).port(port).file_info()?;
+        if is_local_current(Path::new(&fname), info.size, info.mtime) {
+            if verbosity != Verbosity::Quiet {
+                println!("{} is up to date, not retrieving", style(&fname).green());
+            }
+            return Ok(());
         }
-        self.prog_bar = Some(prog_bar);
     }
+    let resume_from = if args.is_present("continue") {
+        fs::metadata(&fname).map_or(0, |meta| meta.len())
+    } else {
+        0
+    };
+
+    let mut client = FtpDownload::new(url.clone());
+    let events_handler = streaming_events_handler(&fname, args, verbosity, resume_from > 0)?;
+    client
+        .max_time(parse_max_time(args)?)
+        .port(port)
+        .resume_from(resume_from);
+    if let Some(fd) = progress_fd(args)? {
+        client.events_hook(FdProgressHandler::new(fd));
+    }
+    let result = client.events_hook(events_handler).download();
+    clean_up_on_error(result, &fname, None, error_cleanup(args))?;
+    Ok(())
 }
 
-impl EventsHandler for DefaultEventsHandler {
-    fn on_headers(&mut self, headers: HeaderMap) {
-        if self.quiet_mode {
-            return;
+/// The `If-Modified-Since` value for `--if-modified-since`: the given date
+/// or else the modification time of the file the url would be saved to.
+/// Without either there is nothing to compare with, and no header is sent.
+fn if_modified_since(url: &Url, args: &ArgMatches) -> Fallible<Option<HeaderValue>> {
+    if !args.is_present("IF_MODIFIED_SINCE") {
+        return Ok(None);
+    }
+    let since = match args.value_of("IF_MODIFIED_SINCE") {
+        Some(date) => parse_http_date(date)?,
+        None => {
+            let fname = output_filename(url, output_name(args)?.as_deref(), None, args)?;
+            match fs::metadata(&fname).and_then(|meta| meta.modified()) {
+                Ok(modified) => DateTime::<Utc>::from(modified),
+                Err(_) => return Ok(None),
+            }
         }
-        let ct_type = if let Some(val) = headers.get(header::CONTENT_TYPE) {
-            val.to_str().unwrap_or("")
-        } else {
-            ""
-        };
-        println!("Type: {}", style(ct_type).green());
+    };
+    Ok(Some(HeaderValue::from_str(&http_date(since))?))
+}
 
-        println!("Saving to: {}", style(&self.fname).green());
-        if let Some(val) = headers.get(header::CONTENT_LENGTH) {
-            self.create_prog_bar(val.to_str().unwrap_or("").parse::<u64>().ok());
-        } else {
-            println!(
-                "{}",
-                style("Got no content-length. Progress bar skipped.").red()
-            );
-        }
+/// Whether the file at `path` has the remote `size` and is at least as new
+/// as the remote `mtime`, so there is no need to fetch it again.
+fn is_local_current(path: &Path, size: Option<u64>, mtime: Option<SystemTime>) -> bool {
+    let (meta, mtime) = match (fs::metadata(path), mtime) {
+        (Ok(meta), Some(mtime)) => (meta, mtime),
+        _ => return false,
+    };
+    let same_size = size.is_none_or(|size| size == meta.len());
+    same_size && meta.modified().is_ok_and(|local| local >= mtime)
+}
+
+pub fn sftp_download(
+    url: Url,
+    args: &ArgMatches,
+    verbosity: Verbosity,
+) -> Result<(), DownloadError> {
+    run_sftp_download(url, args, verbosity).map_err(DownloadError::from)
+}
+
+fn run_sftp_download(url: Url, args: &ArgMatches, verbosity: Verbosity) -> Fallible<()> {
+    let fname = output_filename(&url, output_name(args)?.as_deref(), None, args)?;
+    let mut client = SftpDownload::new(url.clone());
+    let events_handler = streaming_events_handler(&fname, args, verbosity, false)?;
+    if let Some(fd) = progress_fd(args)? {
+        client.events_hook(FdProgressHandler::new(fd));
     }
+    let result = client.events_hook(events_handler).download();
+    clean_up_on_error(result, &fname, None, error_cleanup(args))?;
+    Ok(())
+}
 
-    fn on_ftp_content_length(&mut self, ct_len: Option<u64>) {
-        if !self.quiet_mode {
-            self.create_prog_bar(ct_len);
-        }
+pub fn gemini_download(
+    url: Url,
+    args: &ArgMatches,
+    verbosity: Verbosity,
+) -> Result<(), DownloadError> {
+    run_gemini_download(url, args, verbosity).map_err(DownloadError::from)
+}
+
+fn run_gemini_download(url: Url, args: &ArgMatches, verbosity: Verbosity) -> Fallible<()> {
+    let mut client = GeminiDownload::new(url.clone());
+    // the MIME type names files like `gemini://host/`
+    let response = client.fetch_header()?;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(response.mime_type())?,
+    );
+    let fname = output_filename(&url, output_name(args)?.as_deref(), Some(&headers), args)?;
+    let events_handler = streaming_events_handler(&fname, args, verbosity, false)?;
+    if let Some(fd) = progress_fd(args)? {
+        client.events_hook(FdProgressHandler::new(fd));
     }
+    let result = client.events_hook(events_handler).download();
+    clean_up_on_error(result, &fname, None, error_cleanup(args))?;
+    Ok(())
+}
 
-    fn on_server_supports_resume(&mut self) {
-        self.server_supports_resume = true;
+/// Downloads `url` into memory, e.g. for small resources that don't
+/// deserve a file. The whole body is held at once, so a 1 GB download
+/// takes 1 GB of memory; use a file for anything large.
+///
+/// Nothing is read from or written to disk: resuming is turned off and no
+/// state file is kept. Concurrent chunks are assembled in the buffer.
+pub fn download_to_vec(url: Url, mut conf: Config) -> Fallible<Vec<u8>> {
+    conf.resume = false;
+    conf.bytes_on_disk = None;
+    conf.chunk_offsets = None;
+    let storage = VecStorage::default();
+    HttpDownload::new(url, conf)
+        .events_hook(StorageHandler(storage.clone()))
+        .download()?;
+    Ok(storage.take())
+}
+
+/// Downloads `url` into the file `path`, creating the directories it is in,
+/// with the same settings as `download_to_vec`. Nothing is left at `path`
+/// when the download fails.
+fn download_to_file(url: Url, mut conf: Config, path: &Path) -> Fallible<()> {
+    conf.resume = false;
+    conf.bytes_on_disk = None;
+    conf.chunk_offsets = None;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = fs::File::create(path)?;
+    let result = HttpDownload::new(url, conf)
+        .events_hook(StorageHandler(file))
+        .download();
+    if let Err(e) = result {
+        let _ = fs::remove_file(path);
+        return Err(e.into());
     }
+    Ok(())
+}
 
-    fn on_content(&mut self, content: &[u8]) -> Fallible<()> {
-        let byte_count = content.len() as u64;
-        self.file.write_all(content)?;
-        if let Some(ref mut b) = self.prog_bar {
-            b.inc(byte_count);
-        }
+/// Writes the content to its storage and does nothing else.
+struct StorageHandler<S: Storage>(S);
 
+impl<S: Storage> EventsHandler for StorageHandler<S> {
+    fn on_content(&mut self, content: &[u8], _progress: &Progress) -> Fallible<()> {
+        self.0.write_all(content)?;
         Ok(())
     }
 
-    fn on_concurrent_content(&mut self, content: (u64, u64, &[u8])) -> Fallible<()> {
+    fn on_concurrent_content(
+        &mut self,
+        content: (u64, u64, &[u8]),
+        _progress: &Progress,
+    ) -> Fallible<()> {
         let (byte_count, offset, buf) = content;
-        self.file.seek(SeekFrom::Start(offset))?;
-        self.file.write_all(buf)?;
-        self.file.flush()?;
-        if let Some(ref mut b) = self.prog_bar {
-            b.inc(byte_count);
-        }
-        if let Some(ref mut file) = self.st_file {
-            writeln!(file, "{}:{}", byte_count, offset)?;
-            file.flush()?;
-        }
+        self.0.seek(SeekFrom::Start(offset))?;
+        self.0.write_all(&buf[..byte_count as usize])?;
         Ok(())
     }
+}
 
-    fn on_resume_download(&mut self, bytes_on_disk: u64) {
-        self.bytes_on_disk = Some(bytes_on_disk);
+/// Prints the recorded average speed of each host, fastest first.
+pub fn show_bandwidth_report() -> Result<(), DownloadError> {
+    let estimator = bandwidth_estimator()?;
+    let mut averages: Vec<_> = estimator.averages()?.into_iter().collect();
+    averages.sort_by(|a, b| (b.1).0.total_cmp(&(a.1).0));
+    for (host, (avg, count)) in averages {
+        println!(
+            "{}\t{}/s\t({} downloads)",
+            host,
+            HumanBytes(avg as u64),
+            count
+        );
     }
+    Ok(())
+}
 
-    fn on_finish(&mut self) {
-        if let Some(ref mut b) = self.prog_bar {
+pub fn clear_bandwidth_history() -> Result<(), DownloadError> {
+    Ok(bandwidth_estimator()?.clear()?)
+}
+
+fn bandwidth_estimator() -> Fallible<BandwidthEstimator> {
+    if !cfg!(feature = "bandwidth-db") {
+        bail!("duma was built without the bandwidth-db feature");
+    }
+    BandwidthEstimator::default_path()
+        .map(BandwidthEstimator::new)
+        .ok_or_else(|| format_err!("could not determine the user data directory"))
+}
+
+pub fn http_download(
+    url: Url,
+    args: &ArgMatches,
+    verbosity: Verbosity,
+    version: &str,
+) -> Result<(), DownloadError> {
+    run_http_download(url, None, args, verbosity, version).map_err(DownloadError::from)
+}
+
+/// Downloads `s3://bucket/key` urls through a presigned url, so ranged
+/// and concurrent requests work as they do over HTTP.
+pub fn s3_download(
+    url: Url,
+    args: &ArgMatches,
+    verbosity: Verbosity,
+    version: &str,
+) -> Result<(), DownloadError> {
+    run_s3_download(url, args, verbosity, version).map_err(DownloadError::from)
+}
+
+fn run_s3_download(
+    url: Url,
+    args: &ArgMatches,
+    verbosity: Verbosity,
+    version: &str,
+) -> Fallible<()> {
+    let endpoint = args.value_of("ENDPOINT_URL").map(Url::parse).transpose()?;
+    let signed = signed_url(&url, endpoint.as_ref())?;
+    run_http_download(url, Some(signed), args, verbosity, version)
+}
+
+/// Requests go to `signed` when given, while `url` still names the
+/// file and its state.
+fn run_http_download(
+    url: Url,
+    signed: Option<Url>,
+    args: &ArgMatches,
+    verbosity: Verbosity,
+    version: &str,
+) -> Fallible<()> {
+    let checkpoint = args
+        .value_of("RESUME_CHECKPOINT")
+        .map(|path| load_checkpoint(Path::new(path)))
+        .transpose()?;
+    let mut resume_download = args.is_present("continue") || checkpoint.is_some();
+    let (method, request_body) = request_method(args)?;
+    // byte ranges of an encoded body can't be written at decoded offsets,
+    // and only a GET can be split into ranges
+    let save_headers = save_headers_mode(args);
+    let mut concurrent_download = !args.is_present("singlethread")
+        && !args.is_present("compressed")
+        && save_headers != Some(SaveHeaders::Prepend)
+        && method == Method::GET;
+    let user_agent = user_agent(args, version)?;
+    let timeout = if let Some(secs) = args.value_of("SECONDS") {
+        secs.parse::<u64>()?
+    } else {
+        30u64
+    };
+    let num_workers = if let Some(num) = args.value_of("NUM_CONNECTIONS") {
+        num.parse::<usize>()?
+    } else {
+        8usize
+    };
+    let chunk_size = if let Some(size) = args.value_of("CHUNK_SIZE") {
+        let size = parse_size(size)?;
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size) {
+            bail!(
+                "chunk size must be between {} and {} bytes",
+                MIN_CHUNK_SIZE,
+                MAX_CHUNK_SIZE
+            );
+        }
+        // the other reasons for a single stream don't come from the user
+        if args.is_present("singlethread") && verbosity != Verbosity::Quiet {
+            eprintln!(
+                "{}",
+                style("warning: --chunk-size has no effect in single-thread mode").yellow()
+            );
+        }
+        size
+    } else {
+        512_000u64
+    };
+    let max_retries = max_retries(args)?;
+    let mut conf = Config {
+        user_agent: user_agent.clone(),
+        resume: resume_download,
+        headers: HeaderMap::new(),
+        file: String::new(),
+        timeout,
+        concurrent: concurrent_download,
+        max_retries,
+        max_retries_per_chunk: args
+            .value_of("MAX_RETRIES_PER_CHUNK")
+            .map(|val| val.parse::<i32>())
+            .transpose()?
+            // retrying forever shouldn't give up on any one chunk either
+            .unwrap_or(if max_retries < 0 { -1 } else { 100 }),
+        num_workers,
+        bytes_on_disk: None,
+        chunk_offsets: None,
+        ranges: None,
+        chunk_size,
+        detect_type: args.is_present("detect_type"),
+        interface: args.value_of("IFNAME").map(|val| val.to_owned()),
+        resolve: Vec::new(),
+        dns_servers: dns_servers(args)?,
+        max_time: parse_max_time(args)?,
+        retry_connrefused: args.is_present("retry_connrefused"),
+        compressed: args.is_present("compressed"),
+        credentials_file: args.value_of("CREDENTIALS_FILE").map(PathBuf::from),
+        auto_connections: args.is_present("auto_connections"),
+        known_length: None,
+        force_concurrent: args.is_present("force_concurrent"),
+        proxy: args.value_of("PROXY").map(|val| val.to_owned()),
+        proxy_auth: proxy_auth(args)?,
+        tcp_no_delay: args.is_present("tcp_no_delay"),
+        tcp_keepalive: tcp_keepalive(args)?,
+        header_size_limit: Some(match args.value_of("HEADER_SIZE_LIMIT") {
+            Some(size) => parse_size(size)?,
+            None => DEFAULT_HEADER_SIZE_LIMIT,
+        }),
+        global_connection_limit: Some(Arc::new(Semaphore::new(
+            args.value_of("MAX_CONNECTIONS_TOTAL")
+                .map(|val| val.parse::<usize>())
+                .transpose()?
+                .unwrap_or(DEFAULT_MAX_CONNECTIONS_TOTAL)
+                .max(1),
+        ))),
+        wait_for_server: args
+            .value_of("WAIT_FOR_SERVER")
+            .map(|secs| secs.parse::<u64>().map(Duration::from_secs))
+            .transpose()?,
+        max_conns_per_host: args
+            .value_of("MAX_CONNS_PER_HOST")
+            .map(|val| val.parse::<usize>())
+            .transpose()?,
+        verify_interval: args
+            .value_of("CHUNK_VERIFY_INTERVAL")
+            .map(|val| val.parse::<u64>())
+            .transpose()?,
+        retry_budget: args
+            .value_of("TOTAL_RETRY_BUDGET")
+            .map(|val| val.parse::<u64>().map(|n| Arc::new(RetryBudget::new(n))))
+            .transpose()?,
+        sync_interval_bytes: DEFAULT_SYNC_INTERVAL_BYTES,
+        convert_links: args.is_present("convert_links") || mirroring(args),
+        expected_size: args.value_of("EXPECTED_SIZE").map(parse_size).transpose()?,
+        page_requisites: args.is_present("page_requisites") || mirroring(args),
+        recursive_level: recursive_level(args)?,
+        mirror_dir: args.value_of("MIRROR").map(PathBuf::from),
+        method,
+        request_body,
+    };
+    // the original url still names the file and matches credentials
+    let request_url = signed.unwrap_or_else(|| url.clone());
+    if let Some(addr) = pinned_address(&request_url, args)? {
+        if let Some(host) = request_url.host_str() {
+            // the client connects to the url's own port whatever this says
+            conf.resolve
+                .push((host.to_owned(), SocketAddr::new(addr, 0)));
+        }
+    }
+    // requisites may be on other hosts, so they get none of the page's
+    // own headers
+    let requisites_conf = if conf.page_requisites || conf.recursive_level.is_some() {
+        Some(conf.clone())
+    } else {
+        None
+    };
+    let authorization = credential_header(&url, &conf)?;
+    if let Some(ref auth) = authorization {
+        conf.headers.insert(header::AUTHORIZATION, auth.clone());
+    }
+    let browser_headers = args.is_present("browser_headers");
+    if browser_headers {
+        add_browser_headers(&mut conf.headers, &url)?;
+    }
+    let if_modified_since = if_modified_since(&url, args)?;
+    if let Some(ref since) = if_modified_since {
+        conf.headers
+            .insert(header::IF_MODIFIED_SINCE, since.clone());
+    }
+    if args.is_present("head") {
+        return print_head_response(&build_client(&conf)?, &request_url, &conf);
+    }
+    let client = build_client(&conf)?;
+    // other methods are sent once, by the download itself
+    let (status, mut headers) = if conf.method == Method::GET {
+        request_headers_from_server(&client, &request_url, &conf)?
+    } else {
+        (StatusCode::OK, HeaderMap::new())
+    };
+    if args.is_present("webdav") {
+        let props = propfind(&client, &request_url, &conf)?;
+        conf.known_length = props.content_length;
+        if let Some(len) = props.content_length {
+            headers
+                .entry(header::CONTENT_LENGTH)
+                .or_insert_with(|| HeaderValue::from(len));
+        }
+        if let Some(modified) = props.last_modified {
+            headers
+                .entry(header::LAST_MODIFIED)
+                .or_insert(HeaderValue::from_str(&modified)?);
+        }
+    }
+    if let Some(size) = conf.expected_size {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+    }
+    let fname = match (output_name(args)?, &checkpoint) {
+        (None, Some(cp)) => cp.file.clone(),
+        (out, _) => output_filename(&url, out.as_deref(), Some(&headers), args)?,
+    };
+    if status == StatusCode::NOT_MODIFIED && if_modified_since.is_some() {
+        if verbosity != Verbosity::Quiet {
+            println!(
+                "{} not modified on the server, not retrieving",
+                style(&fname).green()
+            );
+        }
+        return Ok(());
+    }
+    let state_suffix = state_suffix(url.as_str(), args.value_of("TMP_SUFFIX"))?;
+    let bar_fd = progress_bar_fd(args, &fname)?;
+    // stdout can only be written front to back, once
+    let to_stdout = fname == "-";
+    if to_stdout {
+        let unsupported = [
+            "continue",
+            "RANGES",
+            "RESUME_CHECKPOINT",
+            "SAVE_CHECKPOINT",
+            "SHA256",
+            "OUTPUT_HASH",
+            "detect_type",
+            "save_to_clipboard",
+        ];
+        if unsupported.iter().any(|arg| args.is_present(arg)) {
+            bail!("-O - can't be combined with --continue, --ranges, checkpoints, --sha256, --output-hash, --detect-type or --save-to-clipboard");
+        }
+        concurrent_download = false;
+    }
+
+    // early exit if headers flag is present
+    if args.is_present("headers") {
+        print_headers(headers);
+        return Ok(());
+    }
+    let ct_len = if let Some(val) = headers.get("Content-Length") {
+        val.to_str()?.parse::<u64>().unwrap_or(0)
+    } else {
+        0u64
+    };
+
+    let range = args.value_of("RANGE").map(parse_range).transpose()?;
+    if let Some((start, _)) = range {
+        let supports_bytes = headers
+            .get(header::ACCEPT_RANGES)
+            .is_some_and(|val| val == "bytes");
+        if !supports_bytes {
+            bail!("server does not support byte ranges: {}", url);
+        }
+        if ct_len != 0 && start >= ct_len {
+            bail!(
+                "byte range starts at {} but the file is {} bytes",
+                start,
+                ct_len
+            );
+        }
+        // the output holds just the requested bytes, written in order
+        concurrent_download = false;
+    }
+
+    if let Some(ranges) = args.value_of("RANGES") {
+        let ranges = parse_ranges(ranges)?;
+        if ct_len == 0 {
+            bail!("--ranges needs the server to report a content length");
+        }
+        if let Some(&(_, end)) = ranges.iter().find(|&&(_, end)| end >= ct_len) {
+            bail!(
+                "byte range ends at {} but the file is {} bytes",
+                end,
+                ct_len
+            );
+        }
+        // the gaps between the ranges stay as holes in the pre-sized file
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&fname)?
+            .set_len(ct_len)?;
+        concurrent_download = true;
+        conf.ranges = Some(ranges);
+    }
+
+    if let Some(ref cp) = checkpoint {
+        if cp.total_bytes != ct_len {
+            bail!(
+                "checkpoint is for a {} byte file but the server reports {} bytes",
+                cp.total_bytes,
+                ct_len
+            );
+        }
+        if !Path::new(&fname).exists() {
+            bail!("checkpoint file {} does not exist", fname);
+        }
+        // seed the state file so a later --continue knows these ranges
+        cp.write_state_file(&state_file(&fname, &state_suffix, "st"))?;
+    }
+
+    // a download aria2 left off is taken over through the state file,
+    // which is what duma keeps track of from then on
+    let control_file = format!("{}.aria2", fname);
+    let st_fname = state_file(&fname, &state_suffix, "st");
+    if args.is_present("aria2_compat")
+        && Path::new(&control_file).exists()
+        && !Path::new(&st_fname).exists()
+    {
+        let cp = load_control_file(Path::new(&control_file))?.to_checkpoint(url.as_str(), &fname);
+        if cp.total_bytes != ct_len {
+            bail!(
+                "{} is for a {} byte file but the server reports {} bytes",
+                control_file,
+                cp.total_bytes,
+                ct_len
+            );
+        }
+        if !Path::new(&fname).exists() {
+            bail!("{} has no file {} next to it", control_file, fname);
+        }
+        cp.write_state_file(&st_fname)?;
+    }
+
+    if resume_download && !Path::new(&state_file(&fname, &state_suffix, "st")).exists() {
+        trim_to_synced(&fname, &state_suffix, verbosity)?;
+    }
+
+    if ct_len != 0 && conf.ranges.is_none() && range.is_none() && !to_stdout {
+        let remote = ResumeTarget::from_headers(ct_len, &headers);
+        if resume_download && checkpoint.is_none() {
+            if let Some(bcount) = calc_bytes_on_disk(&fname, Some(&state_suffix))? {
+                let recorded = ResumeTarget::load(&fname, &state_suffix)?;
+                if let Some(reason) = restart_reason(bcount, &remote, recorded.as_ref()) {
+                    if verbosity != Verbosity::Quiet {
+                        eprintln!(
+                            "{}",
+                            style(format!("warning: restarting download, {}", reason)).yellow()
+                        );
+                    }
+                    fs::File::create(&fname)?;
+                    let _ = fs::remove_file(state_file(&fname, &state_suffix, "st"));
+                    resume_download = false;
+                    conf.resume = false;
+                }
+            }
+        }
+        remote.save(&fname, &state_suffix)?;
+    }
+
+    let mut headers = prep_headers(&fname, &state_suffix, resume_download, range, &user_agent)?;
+    if let Some(auth) = authorization {
+        headers.insert(header::AUTHORIZATION, auth);
+    }
+    if browser_headers {
+        add_browser_headers(&mut headers, &url)?;
+    }
+    if let Some(since) = if_modified_since {
+        headers.insert(header::IF_MODIFIED_SINCE, since);
+    }
+
+    let state_file_exists = Path::new(&state_file(&fname, &state_suffix, "st")).exists();
+    let resume_min_parallel = if let Some(bytes) = args.value_of("RESUME_MIN_PARALLEL") {
+        parse_size(bytes)?
+    } else {
+        chunk_size
+    };
+
+    let mut bytes_on_disk = if resume_download {
+        calc_bytes_on_disk(&fname, Some(&state_suffix))?
+    } else {
+        None
+    };
+
+    // a contiguous partial file with only a small tail left is finished
+    // with a single ranged request instead of spinning up the workers.
+    if let Some(bcount) = bytes_on_disk {
+        if !state_file_exists && ct_len > bcount && ct_len - bcount < resume_min_parallel {
+            concurrent_download = false;
+        }
+    }
+
+    let mut chunk_offsets = if resume_download && concurrent_download && ct_len != 0 {
+        // a file fetched by a single thread is one run of bytes from the
+        // start, so it gets a state file saying that much
+        if let (false, Some(bcount)) = (state_file_exists, bytes_on_disk) {
+            fs::write(
+                state_file(&fname, &state_suffix, "st"),
+                format!("{}:0\n", bcount),
+            )?;
+        }
+        if state_file_exists || bytes_on_disk.is_some() {
+            Some(get_resume_chunk_offsets(
+                &fname,
+                &state_suffix,
+                ct_len,
+                chunk_size,
+            )?)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    // the file size says nothing about which of its bytes are valid
+    if let Some(ref cp) = checkpoint {
+        bytes_on_disk = Some(cp.completed_bytes());
+        chunk_offsets = Some(cp.chunk_offsets(chunk_size));
+    }
+
+    conf.headers = headers;
+    conf.file = fname.clone();
+    conf.concurrent = concurrent_download;
+    conf.bytes_on_disk = bytes_on_disk;
+    conf.chunk_offsets = chunk_offsets;
+
+    // sized up front, chunks landing out of order don't keep growing the
+    // file and fragmenting it
+    if args.is_present("preallocate") && concurrent_download && ct_len != 0 {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&fname)?;
+        preallocate(&file, ct_len)?;
+    } else if concurrent_download && !args.is_present("no_sparse") && fname != "-" {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&fname)?;
+        // FAT and some network shares can't, the file is then just not sparse
+        if let Err(e) = set_sparse(&file) {
+            if verbosity == Verbosity::Verbose {
+                eprintln!("could not make {} sparse: {}", fname, e);
+            }
+        }
+    }
+
+    let save_checkpoint = args.value_of("SAVE_CHECKPOINT").map(|path| {
+        let data = checkpoint
+            .clone()
+            .unwrap_or_else(|| CheckpointData::new(url.as_str(), &fname));
+        (PathBuf::from(path), data)
+    });
+
+    let verify_interval = conf.verify_interval;
+    let sync_interval_bytes = conf.sync_interval_bytes;
+    let convert_links = conf.convert_links;
+    let mut client = HttpDownload::new(request_url, conf);
+    let verify_content_md5 = args.is_present("content_md5_verify");
+    let events_handler = DefaultEventsHandler::new(
+        &fname,
+        Some(&state_suffix),
+        resume_download,
+        concurrent_download,
+        verbosity,
+        verify_content_md5,
+        args.value_of("STATS_FILE"),
+    )?
+    .show_progress(!args.is_present("no_progress"))
+    .ascii_progress(args.is_present("ascii_progress"))
+    .progress_fd(bar_fd)
+    .term_progress(term_progress(args))
+    .range(range)
+    .save_headers(save_headers)
+    .save_to_clipboard(args.is_present("save_to_clipboard"))
+    .expected_sha256(args.value_of("SHA256").map(parse_hex_digest).transpose()?)
+    .output_hash(output_hash(args))
+    .output_permissions(output_permissions(args)?)
+    .verify_interval(verify_interval)
+    .sync_interval(sync_interval_bytes)
+    .checkpoint(save_checkpoint);
+    if let Some(fd) = progress_fd(args)? {
+        client.events_hook(FdProgressHandler::new(fd));
+    }
+    if cfg!(feature = "bandwidth-db") {
+        if let Some(path) = BandwidthEstimator::default_path() {
+            client.events_hook(BandwidthRecorder::new(BandwidthEstimator::new(path)));
+        }
+    }
+    pause_on_sigusr1(client.state_handle())?;
+    status_on_sigusr2(client.state_handle())?;
+    let result = client.events_hook(events_handler).download();
+    // the handler records its resume point as it goes away
+    drop(client);
+    clean_up_on_error(result, &fname, Some(&state_suffix), error_cleanup(args))?;
+    let mut page = url;
+    page.set_fragment(None);
+    let mut saved = HashMap::from([(page.to_string(), PathBuf::from(&fname))]);
+    if let Some(conf) = requisites_conf {
+        let no_parent = args.is_present("no_parent") || mirroring(args);
+        // the links followed include the requisites
+        let fetched = if conf.recursive_level.is_some() {
+            let filter = ExtensionFilter::new(args.value_of("ACCEPT"), args.value_of("REJECT"));
+            let robots = if args.is_present("no_robots") {
+                Robots::default()
+            } else {
+                fetch_robots(&page, &conf)
+            };
+            fetch_recursive(&fname, &page, conf, &filter, &robots, no_parent, verbosity)?
+        } else {
+            fetch_page_requisites(&fname, &page, conf, no_parent, verbosity)?
+        };
+        for (url, path) in fetched {
+            saved.insert(url.to_string(), path);
+        }
+    }
+    if convert_links {
+        for (url, path) in &saved {
+            let url = Url::parse(url)?;
+            convert_page_links(&path.to_string_lossy(), &url, &saved, verbosity)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_html_file(fname: &str) -> bool {
+    Path::new(fname)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"))
+}
+
+/// The directory the file `fname` is in.
+fn parent_dir(fname: &str) -> &Path {
+    Path::new(fname)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+}
+
+/// Where a file the page saved as `fname` links to is saved: at its place
+/// in the `--mirror` directory, or next to the page.
+fn linked_file_path(url: &Url, fname: &str, conf: &Config) -> PathBuf {
+    match &conf.mirror_dir {
+        Some(dir) => dir.join(mirror_path(url)),
+        None => parent_dir(fname).join(local_name(url)),
+    }
+}
+
+/// Saves the stylesheets, scripts and images of the page saved as `fname`
+/// next to it, for `--page-requisites`, and returns the urls that were
+/// saved with their files. A requisite that fails is reported and left
+/// out.
+fn fetch_page_requisites(
+    fname: &str,
+    page: &Url,
+    conf: Config,
+    no_parent: bool,
+    verbosity: Verbosity,
+) -> Fallible<Vec<(Url, PathBuf)>> {
+    if !is_html_file(fname) {
+        return Ok(Vec::new());
+    }
+    let html = fs::read(fname)?;
+    let conf = linked_file_conf(conf);
+    let mut saved = Vec::new();
+    for url in extract_page_requisites(&String::from_utf8_lossy(&html), page) {
+        if no_parent && !is_below_page(&url, page) {
+            continue;
+        }
+        let path = linked_file_path(&url, fname, &conf);
+        match download_to_file(url.clone(), conf.clone(), &path) {
+            Ok(()) => {
+                if verbosity != Verbosity::Quiet {
+                    println!("{} saved as {}", url, style(path.display()).green());
+                }
+                saved.push((url, path));
+            }
+            Err(e) => warn_not_fetched(&url, &e, verbosity),
+        }
+    }
+    Ok(saved)
+}
+
+/// Saves the files the page saved as `fname` links to on its host next to
+/// it, for `--recursive`, and goes on with the links of the HTML pages
+/// among them up to `Config::recursive_level` links away. Returns the urls
+/// that were saved with their files. Pages the filter leaves out are still
+/// read for their links, those `robots` disallows are skipped, and a file
+/// that fails is reported and left out.
+fn fetch_recursive(
+    fname: &str,
+    page: &Url,
+    conf: Config,
+    filter: &ExtensionFilter,
+    robots: &Robots,
+    no_parent: bool,
+    verbosity: Verbosity,
+) -> Fallible<Vec<(Url, PathBuf)>> {
+    if !is_html_file(fname) {
+        return Ok(Vec::new());
+    }
+    let level = conf.recursive_level.unwrap_or(DEFAULT_RECURSIVE_LEVEL);
+    let conf = linked_file_conf(conf);
+    let mut seen = HashSet::from([page.clone()]);
+    let mut pages = VecDeque::from([(page.clone(), PageBody::Saved(fname.into()), 0)]);
+    let mut saved = Vec::new();
+    while let Some((base, body, depth)) = pages.pop_front() {
+        let html = body.read()?;
+        for url in extract_links(&String::from_utf8_lossy(&html), &base) {
+            if !is_same_host(&url, page)
+                || (no_parent && !is_below_page(&url, page))
+                || !seen.insert(url.clone())
+            {
+                continue;
+            }
+            let path_and_query = &url[Position::BeforePath..Position::AfterQuery];
+            if !robots.allows(path_and_query) {
+                if verbosity == Verbosity::Verbose {
+                    eprintln!("{} is disallowed by robots.txt, skipping", url);
+                }
+                continue;
+            }
+            let name = local_name(&url);
+            let keep = filter.allows(&url);
+            let follow = depth + 1 < level && is_html_file(&name);
+            if !keep && !follow {
+                continue;
+            }
+            let path = linked_file_path(&url, fname, &conf);
+            let result = if keep {
+                download_to_file(url.clone(), conf.clone(), &path)
+                    .map(|()| PageBody::Saved(path.clone()))
+            } else {
+                download_to_vec(url.clone(), conf.clone()).map(PageBody::Fetched)
+            };
+            match result {
+                Ok(body) => {
+                    if keep {
+                        if verbosity != Verbosity::Quiet {
+                            println!("{} saved as {}", url, style(path.display()).green());
+                        }
+                        saved.push((url.clone(), path));
+                    }
+                    if follow {
+                        pages.push_back((url, body, depth + 1));
+                    }
+                }
+                Err(e) => warn_not_fetched(&url, &e, verbosity),
+            }
+        }
+    }
+    Ok(saved)
+}
+
+/// A page `fetch_recursive` has yet to read the links of: saved to a file,
+/// or only fetched into memory when the filter leaves it out.
+enum PageBody {
+    Saved(PathBuf),
+    Fetched(Vec<u8>),
+}
+
+impl PageBody {
+    fn read(self) -> io::Result<Vec<u8>> {
+        match self {
+            PageBody::Saved(path) => fs::read(path),
+            PageBody::Fetched(data) => Ok(data),
+        }
+    }
+}
+
+/// The `robots.txt` rules of the host of `page` for the user agent of
+/// `conf`. A host without a readable `robots.txt` has none.
+fn fetch_robots(page: &Url, conf: &Config) -> Robots {
+    let agent = conf.user_agent.split('/').next().unwrap_or("");
+    page.join("/robots.txt")
+        .map_err(failure::Error::from)
+        .and_then(|url| download_to_vec(url, linked_file_conf(conf.clone())))
+        .map_or_else(
+            |_| Robots::default(),
+            |text| Robots::parse(&String::from_utf8_lossy(&text), agent),
+        )
+}
+
+/// The settings to fetch the files a page links to with: plain `GET`s of
+/// the whole file.
+fn linked_file_conf(mut conf: Config) -> Config {
+    conf.ranges = None;
+    conf.expected_size = None;
+    conf.page_requisites = false;
+    conf.recursive_level = None;
+    conf.method = Method::GET;
+    conf.request_body = None;
+    conf.convert_links = false;
+    conf
+}
+
+fn warn_not_fetched(url: &Url, e: &failure::Error, verbosity: Verbosity) {
+    if verbosity != Verbosity::Quiet {
+        eprintln!(
+            "{}",
+            style(format!("warning: could not fetch {}: {}", url, e)).yellow()
+        );
+    }
+}
+
+/// `--tcp-keepalive`, in seconds.
+fn tcp_keepalive(args: &ArgMatches) -> Fallible<Option<Duration>> {
+    let secs = match args.value_of("TCP_KEEPALIVE") {
+        Some(secs) => secs.parse::<u64>()?,
+        None => return Ok(None),
+    };
+    if secs == 0 {
+        bail!("--tcp-keepalive must be at least 1");
+    }
+    Ok(Some(Duration::from_secs(secs)))
+}
+
+/// `--level`, or the default for `--recursive`.
+fn recursive_level(args: &ArgMatches) -> Fallible<Option<u32>> {
+    if !args.is_present("recursive") && !mirroring(args) {
+        return Ok(None);
+    }
+    let default = if mirroring(args) {
+        DEFAULT_MIRROR_LEVEL
+    } else {
+        DEFAULT_RECURSIVE_LEVEL
+    };
+    let level = args
+        .value_of("LEVEL")
+        .map(|level| level.parse::<u32>())
+        .transpose()?
+        .unwrap_or(default);
+    if level == 0 {
+        bail!("--level must be at least 1");
+    }
+    Ok(Some(level))
+}
+
+/// Whether `--mirror` asked for `--recursive`, `--no-parent`,
+/// `--page-requisites` and `--convert-links` all at once.
+fn mirroring(args: &ArgMatches) -> bool {
+    args.is_present("MIRROR")
+}
+
+/// Rewrites the links of the page saved as `fname` that lead to any of the
+/// `saved` urls, for `--convert-links`. Only HTML files are touched.
+fn convert_page_links(
+    fname: &str,
+    page: &Url,
+    saved: &HashMap<String, PathBuf>,
+    verbosity: Verbosity,
+) -> Fallible<()> {
+    if !is_html_file(fname) {
+        return Ok(());
+    }
+    let html = match String::from_utf8(fs::read(fname)?) {
+        Ok(html) => html,
+        Err(_) => {
+            if verbosity != Verbosity::Quiet {
+                eprintln!(
+                    "{}",
+                    style(format!(
+                        "warning: {} is not UTF-8, links not converted",
+                        fname
+                    ))
+                    .yellow()
+                );
+            }
+            return Ok(());
+        }
+    };
+    let converted = LinkConverter::new(page.clone()).convert(&html, parent_dir(fname), saved);
+    if converted != html {
+        fs::write(fname, converted)?;
+    }
+    Ok(())
+}
+
+/// Where `--save-headers` puts the response headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SaveHeaders {
+    /// In `{fname}.headers`, next to the download.
+    Sidecar,
+    /// At the start of the downloaded file, like wget does.
+    Prepend,
+}
+
+/// Digest written next to the download by `--output-hash`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    Md5,
+}
+
+impl HashAlgorithm {
+    /// The sidecar file extension, also the name of the GNU tool that
+    /// checks it, e.g. `sha256sum -c foo.txt.sha256`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Md5 => "md5",
+        }
+    }
+
+    pub fn digest_file(self, path: &str) -> io::Result<Vec<u8>> {
+        let mut input = fs::File::open(path)?;
+        let mut sha256 = Sha256::new();
+        let mut sha512 = Sha512::new();
+        let mut md5 = md5::Context::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let bcount = input.read(&mut buffer[..])?;
+            if bcount == 0 {
+                break;
+            }
+            match self {
+                HashAlgorithm::Sha256 => sha256.update(&buffer[..bcount]),
+                HashAlgorithm::Sha512 => sha512.update(&buffer[..bcount]),
+                HashAlgorithm::Md5 => md5.consume(&buffer[..bcount]),
+            }
+        }
+        Ok(match self {
+            HashAlgorithm::Sha256 => sha256.finish().to_vec(),
+            HashAlgorithm::Sha512 => sha512.finish().to_vec(),
+            HashAlgorithm::Md5 => md5.compute().0.to_vec(),
+        })
+    }
+}
+
+fn output_hash(args: &ArgMatches) -> Option<HashAlgorithm> {
+    if !args.is_present("OUTPUT_HASH") {
+        return None;
+    }
+    match args.value_of("OUTPUT_HASH") {
+        Some("sha512") => Some(HashAlgorithm::Sha512),
+        Some("md5") => Some(HashAlgorithm::Md5),
+        _ => Some(HashAlgorithm::Sha256),
+    }
+}
+
+/// What to do with the output of a failed download.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ErrorCleanup {
+    Keep,
+    Delete,
+}
+
+/// `--keep-on-error` or `--delete-on-error`, `None` for neither.
+fn error_cleanup(args: &ArgMatches) -> Option<ErrorCleanup> {
+    if args.is_present("keep_on_error") {
+        Some(ErrorCleanup::Keep)
+    } else if args.is_present("delete_on_error") {
+        Some(ErrorCleanup::Delete)
+    } else {
+        None
+    }
+}
+
+/// Removes the output file and state files of a failed download as
+/// `cleanup` asks. Without a choice, a partial file is kept for
+/// `--continue` while one that failed its checksum is removed.
+fn clean_up_on_error(
+    result: Result<(), DownloadError>,
+    fname: &str,
+    state_suffix: Option<&str>,
+    cleanup: Option<ErrorCleanup>,
+) -> Result<(), DownloadError> {
+    if let Err(ref err) = result {
+        let delete = match cleanup {
+            Some(ErrorCleanup::Keep) => false,
+            Some(ErrorCleanup::Delete) => true,
+            None => matches!(err, DownloadError::ChecksumMismatch { .. }),
+        };
+        if delete && fname != "-" {
+            let _ = fs::remove_file(fname);
+            if let Some(suffix) = state_suffix {
+                let _ = fs::remove_file(state_file(fname, suffix, "st"));
+                let _ = fs::remove_file(state_file(fname, suffix, "target"));
+                let _ = fs::remove_file(state_file(fname, suffix, "synced"));
+            }
+        }
+    }
+    result
+}
+
+fn save_headers_mode(args: &ArgMatches) -> Option<SaveHeaders> {
+    if !args.is_present("SAVE_HEADERS") {
+        return None;
+    }
+    match args.value_of("SAVE_HEADERS") {
+        Some("prepend") => Some(SaveHeaders::Prepend),
+        _ => Some(SaveHeaders::Sidecar),
+    }
+}
+
+pub struct DefaultEventsHandler {
+    prog_bar: Option<ProgressBar>,
+    bytes_on_disk: Option<u64>,
+    fname: String,
+    file: Box<dyn Storage>,
+    /// Another handle on `file` when it is on disk.
+    writer: Option<ResumableWriter>,
+    state_suffix: Option<String>,
+    st_file: Option<BufWriter<fs::File>>,
+    server_supports_resume: bool,
+    verbosity: Verbosity,
+    show_progress: bool,
+    ascii_progress: bool,
+    verify_content_md5: bool,
+    content_md5: Option<[u8; 16]>,
+    md5_ctx: Option<md5::Context>,
+    stats_file: Option<String>,
+    save_headers: Option<SaveHeaders>,
+    status_line: String,
+    /// Header block waiting to be written by the next fallible event.
+    pending_headers: Option<String>,
+    save_to_clipboard: bool,
+    expected_sha256: Option<[u8; 32]>,
+    output_hash: Option<HashAlgorithm>,
+    checkpoint: Option<(PathBuf, CheckpointData)>,
+    progress_fd: i32,
+    range: Option<(u64, Option<u64>)>,
+    multi_progress: Option<Arc<MultiProgress>>,
+    term_progress: bool,
+    term_bar: Option<TermProgress<io::Stderr>>,
+    /// Mode the finished file is given, from `--output-permissions` or
+    /// else the `X-Content-Mode` response header.
+    output_permissions: Option<u32>,
+    /// The server's modification time, given to the finished file.
+    remote_modified: Option<SystemTime>,
+    /// Read back every Nth concurrent piece, see `verify_interval`.
+    verify_interval: Option<u64>,
+    chunk_counter: u64,
+    /// Whether the last piece was read back and matched.
+    last_verified: bool,
+    /// What `on_status_query` reports: the bytes of this session, the
+    /// expected length and when the content started.
+    received: u64,
+    total: Option<u64>,
+    started: Option<Instant>,
+}
+
+impl DefaultEventsHandler {
+    /// `state_suffix` names the `.st` and `.target` files of a resumable
+    /// download, see `utils::state_suffix`.
+    pub fn new(
+        fname: &str,
+        state_suffix: Option<&str>,
+        resume: bool,
+        concurrent: bool,
+        verbosity: Verbosity,
+        verify_content_md5: bool,
+        stats_file: Option<&str>,
+    ) -> Fallible<DefaultEventsHandler> {
+        let st_file = match state_suffix {
+            Some(suffix) if concurrent => Some(BufWriter::new(get_file_handle(
+                &state_file(fname, suffix, "st"),
+                resume,
+                true,
+            )?)),
+            _ => None,
+        };
+        let writer = if fname == "-" {
+            None
+        } else {
+            let file = get_file_handle(fname, resume, !concurrent)?;
+            let writer = ResumableWriter::new(file, resume && !concurrent)?;
+            // concurrent chunks are recorded in the `st` state file instead
+            if let (Some(suffix), false) = (state_suffix, concurrent) {
+                writer.record_to(PathBuf::from(state_file(fname, suffix, "synced")));
+            }
+            Some(writer)
+        };
+        Ok(DefaultEventsHandler {
+            prog_bar: None,
+            bytes_on_disk: calc_bytes_on_disk(fname, state_suffix)?,
+            fname: fname.to_owned(),
+            file: match writer {
+                Some(ref writer) => Box::new(writer.clone()),
+                None => Box::new(StdoutStorage::default()),
+            },
+            writer,
+            state_suffix: state_suffix.map(|suffix| suffix.to_owned()),
+            st_file,
+            server_supports_resume: false,
+            verbosity,
+            show_progress: true,
+            ascii_progress: false,
+            verify_content_md5,
+            content_md5: None,
+            md5_ctx: None,
+            stats_file: stats_file.map(|val| val.to_owned()),
+            save_headers: None,
+            status_line: String::new(),
+            pending_headers: None,
+            save_to_clipboard: false,
+            expected_sha256: None,
+            output_hash: None,
+            checkpoint: None,
+            progress_fd: 2,
+            range: None,
+            multi_progress: None,
+            term_progress: false,
+            term_bar: None,
+            output_permissions: None,
+            remote_modified: None,
+            verify_interval: None,
+            chunk_counter: 0,
+            last_verified: false,
+            received: 0,
+            total: None,
+            started: None,
+        })
+    }
+
+    /// Hides the progress bar while still printing the download details.
+    pub fn show_progress(mut self, show: bool) -> DefaultEventsHandler {
+        self.show_progress = show;
+        self
+    }
+
+    /// Draws the progress bar with plain ASCII characters.
+    pub fn ascii_progress(mut self, ascii: bool) -> DefaultEventsHandler {
+        self.ascii_progress = ascii;
+        self
+    }
+
+    /// Prints a line about the download, on stderr when the content itself
+    /// is streamed to stdout.
+    fn info<T: std::fmt::Display>(&self, msg: T) {
+        if self.fname == "-" {
+            eprintln!("{}", msg);
+        } else {
+            println!("{}", msg);
+        }
+    }
+
+    /// Prints `msg` above a visible progress bar, or as a plain line.
+    fn progress_message<T: Into<String>>(&self, msg: T) {
+        if self.verbosity == Verbosity::Quiet {
+            return;
+        }
+        let msg = msg.into();
+        match self.prog_bar {
+            Some(ref bar) if !bar.is_hidden() => bar.println(msg),
+            _ => self.info(msg),
+        }
+    }
+
+    /// Notes the `--range` being fetched. The partial content is not a
+    /// resumed download, so nothing on disk is counted towards it.
+    pub fn range(mut self, range: Option<(u64, Option<u64>)>) -> DefaultEventsHandler {
+        if range.is_some() {
+            self.bytes_on_disk = None;
+        }
+        self.range = range;
+        self
+    }
+
+    /// Adds the progress bar to `multi` so that handlers of downloads
+    /// running side by side don't draw over each other. The caller keeps
+    /// `multi` drawing with `MultiProgress::join` until they are done.
+    pub fn multi_progress(mut self, multi: Option<Arc<MultiProgress>>) -> DefaultEventsHandler {
+        self.multi_progress = multi;
+        self
+    }
+
+    /// Also shows the progress in the terminal's tab or taskbar, see
+    /// `TermProgress`. Only worth enabling when stderr is a terminal.
+    pub fn term_progress(mut self, enabled: bool) -> DefaultEventsHandler {
+        self.term_progress = enabled;
+        self
+    }
+
+    /// Draws the progress bar on stdout for 1, otherwise on stderr.
+    pub fn progress_fd(mut self, fd: i32) -> DefaultEventsHandler {
+        self.progress_fd = fd;
+        self
+    }
+
+    /// Keeps the status line and response headers, see `SaveHeaders`.
+    /// Prepending needs a single threaded download.
+    pub fn save_headers(mut self, mode: Option<SaveHeaders>) -> DefaultEventsHandler {
+        self.save_headers = mode;
+        self
+    }
+
+    /// Copies small text and image downloads to the clipboard once they
+    /// are complete.
+    pub fn save_to_clipboard(mut self, save: bool) -> DefaultEventsHandler {
+        self.save_to_clipboard = save;
+        self
+    }
+
+    /// Checks the finished file against a SHA256 digest. The file is read
+    /// back once in `on_finish`, which costs a second pass over the data but
+    /// works whatever order concurrent chunks arrived in.
+    pub fn expected_sha256(mut self, digest: Option<[u8; 32]>) -> DefaultEventsHandler {
+        self.expected_sha256 = digest;
+        self
+    }
+
+    /// Writes the finished file's digest to a `.sha256`, `.sha512` or `.md5`
+    /// sidecar.
+    pub fn output_hash(mut self, algo: Option<HashAlgorithm>) -> DefaultEventsHandler {
+        self.output_hash = algo;
+        self
+    }
+
+    /// Sets the Unix permission bits of the finished file, e.g. `0o755` for
+    /// a script. Without it an `X-Content-Mode` header sent by the server
+    /// is used, limited to the read, write and execute bits.
+    pub fn output_permissions(mut self, mode: Option<u32>) -> DefaultEventsHandler {
+        self.output_permissions = mode;
+        self
+    }
+
+    /// Syncs the output to disk after every `bytes` written.
+    pub fn sync_interval(self, bytes: u64) -> DefaultEventsHandler {
+        if let Some(ref writer) = self.writer {
+            writer.set_sync_interval(bytes);
+        }
+        self
+    }
+
+    /// Reads every `interval`th concurrent piece back from the output after
+    /// writing it. A piece that reads back differently isn't counted as
+    /// written: `DownloadError::ChunkWriteCorrupt` has it fetched again.
+    pub fn verify_interval(mut self, interval: Option<u64>) -> DefaultEventsHandler {
+        self.verify_interval = interval.filter(|&n| n > 0);
+        self
+    }
+
+    /// Keeps `data` up to date as concurrent chunks land and rewrites it
+    /// at `path` after each one, see `crate::checkpoint`.
+    pub fn checkpoint(
+        mut self,
+        checkpoint: Option<(PathBuf, CheckpointData)>,
+    ) -> DefaultEventsHandler {
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    pub fn checkpoint_data(&self) -> Option<&CheckpointData> {
+        self.checkpoint.as_ref().map(|(_, data)| data)
+    }
+
+    /// Creates a handler that writes to `storage` instead of opening
+    /// `fname`, without a state file.
+    pub fn with_storage(
+        fname: &str,
+        storage: Box<dyn Storage>,
+        verbosity: Verbosity,
+    ) -> DefaultEventsHandler {
+        DefaultEventsHandler {
+            prog_bar: None,
+            bytes_on_disk: None,
+            fname: fname.to_owned(),
+            file: storage,
+            writer: None,
+            state_suffix: None,
+            st_file: None,
+            server_supports_resume: false,
+            verbosity,
+            show_progress: true,
+            ascii_progress: false,
+            verify_content_md5: false,
+            content_md5: None,
+            md5_ctx: None,
+            stats_file: None,
+            save_headers: None,
+            status_line: String::new(),
+            pending_headers: None,
+            save_to_clipboard: false,
+            expected_sha256: None,
+            output_hash: None,
+            checkpoint: None,
+            progress_fd: 2,
+            range: None,
+            multi_progress: None,
+            term_progress: false,
+            term_bar: None,
+            output_permissions: None,
+            remote_modified: None,
+            verify_interval: None,
+            chunk_counter: 0,
+            last_verified: false,
+            received: 0,
+            total: None,
+            started: None,
+        }
+    }
+
+    fn verify_sha256(&mut self) -> Fallible<()> {
+        let expected = match self.expected_sha256 {
+            Some(val) => val,
+            None => return Ok(()),
+        };
+        self.file.flush()?;
+        let digest = HashAlgorithm::Sha256.digest_file(&self.fname)?;
+        if digest != expected {
+            return Err(DownloadError::ChecksumMismatch {
+                algorithm: "SHA-256",
+                file: self.fname.clone(),
+                expected: to_hex(&expected),
+                actual: to_hex(&digest),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Writes `{digest}  {name}` to the `--output-hash` sidecar, in the
+    /// format `sha256sum -c`, `sha512sum -c` and `md5sum -c` read.
+    fn write_output_hash(&mut self) -> Fallible<()> {
+        let algo = match self.output_hash {
+            Some(algo) => algo,
+            None => return Ok(()),
+        };
+        self.file.flush()?;
+        let digest = algo.digest_file(&self.fname)?;
+        let name = Path::new(&self.fname)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.fname.clone());
+        fs::write(
+            format!("{}.{}", self.fname, algo.extension()),
+            format!("{}  {}\n", to_hex(&digest), name),
+        )?;
+        Ok(())
+    }
+
+    fn set_output_permissions(&mut self) -> Fallible<()> {
+        let mode = match self.output_permissions {
+            Some(mode) if self.fname != "-" => mode,
+            _ => return Ok(()),
+        };
+        self.file.flush()?;
+        set_file_mode(&self.fname, mode)
+            .map_err(|e| format_err!("cannot set the mode of {}: {}", self.fname, e))?;
+        Ok(())
+    }
+
+    fn set_modified_time(&mut self) -> Fallible<()> {
+        let modified = match self.remote_modified {
+            Some(modified) if self.fname != "-" => modified,
+            _ => return Ok(()),
+        };
+        self.file.flush()?;
+        fs::File::options()
+            .write(true)
+            .open(&self.fname)?
+            .set_modified(modified)?;
+        Ok(())
+    }
+
+    /// Clipboard failures only warn: the file on disk is what counts.
+    fn copy_to_clipboard(&mut self) -> Fallible<()> {
+        self.file.flush()?;
+        let size = fs::metadata(&self.fname)?.len();
+        let copied = if size > MAX_CLIPBOARD_SIZE {
+            Err(format_err!(
+                "{} is larger than {}",
+                HumanBytes(size),
+                HumanBytes(MAX_CLIPBOARD_SIZE)
+            ))
+        } else {
+            copy_to_clipboard(&fs::read(&self.fname)?)
+        };
+        match copied {
+            Ok(()) => self.on_copied_to_clipboard(size),
+            Err(e) if self.verbosity != Verbosity::Quiet => eprintln!(
+                "{}",
+                style(format!("warning: not copied to the clipboard: {}", e)).yellow()
+            ),
+            Err(_) => {}
+        }
+        Ok(())
+    }
+
+    fn write_saved_headers(&mut self) -> Fallible<()> {
+        let block = match self.pending_headers.take() {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        match self.save_headers {
+            Some(SaveHeaders::Prepend) => {
+                if let Err(err) = self.file.write_all(block.as_bytes()) {
+                    return Err(self.write_failed(err));
+                }
+            }
+            _ => fs::write(format!("{}.headers", self.fname), block)?,
+        }
+        Ok(())
+    }
+
+    /// Whether the `buf` just written at `offset` is due a check and reads
+    /// back differently from the output.
+    fn chunk_write_corrupt(&mut self, offset: u64, buf: &[u8]) -> Fallible<bool> {
+        self.last_verified = false;
+        let interval = match self.verify_interval {
+            Some(interval) if self.fname != "-" => interval,
+            _ => return Ok(false),
+        };
+        self.chunk_counter += 1;
+        if !self.chunk_counter.is_multiple_of(interval) {
+            return Ok(false);
+        }
+        let mut file = fs::File::open(&self.fname)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut written = Vec::with_capacity(buf.len());
+        file.take(buf.len() as u64).read_to_end(&mut written)?;
+        self.last_verified = written == buf;
+        Ok(!self.last_verified)
+    }
+
+    fn write_failed(&mut self, err: io::Error) -> failure::Error {
+        // keep what is already recorded so the download can be resumed
+        if let Some(ref mut file) = self.st_file {
+            let _ = file.flush();
+        }
+        if err.kind() == io::ErrorKind::StorageFull {
+            format_err!(
+                "disk full while writing {}; the partial download was kept, \
+                 resume it with -c once space is freed",
+                self.fname
+            )
+        } else {
+            err.into()
+        }
+    }
+
+    fn create_prog_bar(&mut self, length: Option<u64>) {
+        let byte_count = if self.server_supports_resume {
+            self.bytes_on_disk
+        } else {
+            None
+        };
+        if let Some(len) = length {
+            let exact = style(len).green();
+            let human_readable = style(format!("{}", HumanBytes(len))).red();
+
+            self.info(format!("Length: {} ({})", exact, human_readable));
+        } else {
+            self.info(format!("Length: {}", style("unknown").red()));
+        }
+
+        if !self.show_progress {
+            return;
+        }
+        if self.term_progress {
+            let mut term_bar = TermProgress::stderr();
+            term_bar.start(length, byte_count.unwrap_or(0));
+            self.term_bar = Some(term_bar);
+        }
+        let prog_bar = create_progress_bar(
+            &self.fname,
+            length,
+            self.ascii_progress,
+            progress_draw_target(self.progress_fd),
+        );
+        let prog_bar = match self.multi_progress {
+            Some(ref multi) => multi.add(prog_bar),
+            None => prog_bar,
+        };
+        if let Some(count) = byte_count {
+            prog_bar.inc(count);
+        }
+        self.prog_bar = Some(prog_bar);
+    }
+
+    fn set_content_md5(&mut self, headers: &HeaderMap) {
+        let digest = headers
+            .get("content-md5")
+            .and_then(|val| base64::decode(val.as_bytes()).ok())
+            .filter(|val| val.len() == 16);
+        if let Some(digest) = digest {
+            let mut expected = [0u8; 16];
+            expected.copy_from_slice(&digest);
+            self.content_md5 = Some(expected);
+            // the body can only be hashed as it streams in when it arrives
+            // whole and in order, otherwise the file is hashed on finish.
+            if self.st_file.is_none() && !self.server_supports_resume {
+                self.md5_ctx = Some(md5::Context::new());
+            }
+        }
+    }
+
+    fn verify_content_md5(&mut self) -> Fallible<()> {
+        let expected = match self.content_md5 {
+            Some(val) => val,
+            None => return Ok(()),
+        };
+        self.file.flush()?;
+        let digest = match self.md5_ctx.take() {
+            Some(ctx) => ctx.compute(),
+            None => {
+                let mut input = fs::File::open(&self.fname)?;
+                let mut ctx = md5::Context::new();
+                let mut buffer = vec![0; 64 * 1024];
+                loop {
+                    let bcount = input.read(&mut buffer[..])?;
+                    if bcount == 0 {
+                        break;
+                    }
+                    ctx.consume(&buffer[..bcount]);
+                }
+                ctx.compute()
+            }
+        };
+        if digest.0 != expected {
+            return Err(DownloadError::ChecksumMismatch {
+                algorithm: "Content-MD5",
+                file: self.fname.clone(),
+                expected: base64::encode(expected),
+                actual: base64::encode(digest.0),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl EventsHandler for DefaultEventsHandler {
+    fn on_status_line(&mut self, status_line: &str) {
+        self.status_line = status_line.to_owned();
+    }
+
+    fn on_headers(&mut self, headers: HeaderMap) {
+        if self.save_headers.is_some() {
+            let mut block = format!("{}\r\n", self.status_line);
+            for (name, value) in headers.iter() {
+                block.push_str(&format!("{}: {}\r\n", name, value.to_str().unwrap_or("")));
+            }
+            block.push_str("\r\n");
+            self.pending_headers = Some(block);
+        }
+        if self.verify_content_md5 {
+            self.set_content_md5(&headers);
+        }
+        if self.output_permissions.is_none() {
+            self.output_permissions = headers
+                .get("x-content-mode")
+                .and_then(|val| val.to_str().ok())
+                .and_then(|val| parse_mode(val).ok())
+                .map(|mode| mode & 0o777);
+        }
+        let ct_len = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.parse::<u64>().ok());
+        if let Some((_, ref mut data)) = self.checkpoint {
+            if let Some(ct_len) = ct_len {
+                data.total_bytes = ct_len;
+            }
+        }
+        self.total = ct_len;
+        self.started = Some(Instant::now());
+        if self.verbosity == Verbosity::Quiet {
+            return;
+        }
+        let ct_type = if let Some(val) = headers.get(header::CONTENT_TYPE) {
+            val.to_str().unwrap_or("")
+        } else {
+            ""
+        };
+        self.info(format!("Type: {}", style(ct_type).green()));
+        if self.verbosity == Verbosity::Verbose {
+            for (name, value) in headers.iter() {
+                self.info(format!("  {}: {}", name, value.to_str().unwrap_or("")));
+            }
+        }
+
+        if let Some(range) = self.range {
+            let range = format_range(range);
+            self.info(format!(
+                "Range: {}",
+                style(range.trim_start_matches("bytes=")).green()
+            ));
+        }
+        self.info(format!("Saving to: {}", style(&self.fname).green()));
+        if let Some(val) = headers.get(header::CONTENT_LENGTH) {
+            self.create_prog_bar(val.to_str().unwrap_or("").parse::<u64>().ok());
+        } else {
+            self.create_prog_bar(None);
+        }
+    }
+
+    fn on_ftp_content_length(&mut self, ct_len: Option<u64>) {
+        self.total = ct_len;
+        self.started = Some(Instant::now());
+        if self.verbosity != Verbosity::Quiet {
+            self.create_prog_bar(ct_len);
+        }
+    }
+
+    fn on_server_supports_resume(&mut self) {
+        self.server_supports_resume = true;
+    }
+
+    fn on_content(&mut self, content: &[u8], _progress: &Progress) -> Fallible<()> {
+        self.write_saved_headers()?;
+        let byte_count = content.len() as u64;
+        if let Err(err) = self.file.write_all(content) {
+            return Err(self.write_failed(err));
+        }
+        if let Some(ref mut ctx) = self.md5_ctx {
+            ctx.consume(content);
+        }
+        self.received += byte_count;
+        if let Some(ref mut b) = self.prog_bar {
+            b.inc(byte_count);
+        }
+        if let Some(ref mut b) = self.term_bar {
+            b.inc(byte_count);
+        }
+
+        Ok(())
+    }
+
+    fn on_concurrent_content(
+        &mut self,
+        content: (u64, u64, &[u8]),
+        _progress: &Progress,
+    ) -> Fallible<()> {
+        self.write_saved_headers()?;
+        let (byte_count, offset, buf) = content;
+        let written = self
+            .file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(buf))
+            .and_then(|_| self.file.flush());
+        if let Err(err) = written {
+            return Err(self.write_failed(err));
+        }
+        if self.chunk_write_corrupt(offset, buf)? {
+            return Err(DownloadError::ChunkWriteCorrupt { offset, byte_count }.into());
+        }
+        self.received += byte_count;
+        if let Some(ref mut b) = self.prog_bar {
+            b.inc(byte_count);
+        }
+        if let Some(ref mut b) = self.term_bar {
+            b.inc(byte_count);
+        }
+        if let Some(ref mut file) = self.st_file {
+            writeln!(file, "{}:{}", byte_count, offset)?;
+            file.flush()?;
+        }
+        if let Some((ref path, ref mut data)) = self.checkpoint {
+            data.add_chunk(offset, byte_count);
+            let path = path.clone();
+            save_checkpoint(self, &path)?;
+        }
+        Ok(())
+    }
+
+    fn on_file_renamed(&mut self, old: &str, new: &str) -> Fallible<()> {
+        self.file.flush()?;
+        fs::rename(old, new)?;
+        if let Some(ref suffix) = self.state_suffix {
+            if let Some(ref writer) = self.writer {
+                writer.move_record(PathBuf::from(state_file(new, suffix, "synced")));
+            }
+            for ext in &["st", "target", "synced"] {
+                let old_fname = state_file(old, suffix, ext);
+                if Path::new(&old_fname).exists() {
+                    fs::rename(old_fname, state_file(new, suffix, ext))?;
+                }
+            }
+        }
+        if self.verbosity != Verbosity::Quiet {
+            self.info(format!("Renamed to: {}", style(new).green()));
+        }
+        self.fname = new.to_owned();
+        Ok(())
+    }
+
+    fn on_resume_download(&mut self, bytes_on_disk: u64) {
+        self.bytes_on_disk = Some(bytes_on_disk);
+    }
+
+    fn on_remote_modified(&mut self, modified: SystemTime) {
+        self.remote_modified = Some(modified);
+    }
+
+    fn on_not_modified(&mut self) {
+        if self.verbosity != Verbosity::Quiet {
+            println!(
+                "{} not modified on the server, not retrieving",
+                style(&self.fname).green()
+            );
+        }
+    }
+
+    fn on_finish(&mut self) -> Fallible<()> {
+        // an empty body never triggered a content event
+        self.write_saved_headers()?;
+        if let Some(ref mut b) = self.prog_bar {
             b.finish();
         }
-        match fs::remove_file(&format!("{}.st", self.fname)) {
-            _ => {}
+        // dropping it clears the indicator
+        self.term_bar = None;
+        if let Some(ref writer) = self.writer {
+            writer.finish()?;
+        }
+        if let Some(ref suffix) = self.state_suffix {
+            let _ = fs::remove_file(state_file(&self.fname, suffix, "st"));
+            let _ = fs::remove_file(state_file(&self.fname, suffix, "target"));
+            let _ = fs::remove_file(state_file(&self.fname, suffix, "synced"));
+        }
+        self.verify_content_md5()?;
+        self.verify_sha256()?;
+        self.write_output_hash()?;
+        self.set_output_permissions()?;
+        self.set_modified_time()?;
+        if self.save_to_clipboard {
+            self.copy_to_clipboard()?;
+        }
+        Ok(())
+    }
+
+    fn on_finish_with_stats(&mut self, stats: &DownloadStats) -> Fallible<()> {
+        let path = match self.stats_file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let secs = stats.duration.as_secs_f64();
+        let report = serde_json::json!({
+            "url": stats.url,
+            "file": self.fname,
+            "bytes": stats.bytes,
+            "duration_secs": secs,
+            "avg_speed": stats.avg_speed(),
+            "retries": stats.retries,
+            "chunks": stats.chunks,
+            "resumed": stats.resumed,
+            "wasted_bytes": stats.wasted_bytes,
+        });
+        fs::write(path, serde_json::to_string_pretty(&report)?)?;
+        Ok(())
+    }
+
+    fn on_copied_to_clipboard(&mut self, bytes: u64) {
+        if self.verbosity != Verbosity::Quiet {
+            self.info(format!(
+                "Copied {} to the clipboard",
+                style(HumanBytes(bytes)).green()
+            ));
+        }
+    }
+
+    fn on_connections_chosen(&mut self, num_workers: usize) {
+        self.progress_message(format!("Connections: {}", style(num_workers).green()));
+    }
+
+    fn on_paused(&mut self) {
+        self.progress_message("Paused, send SIGUSR1 again to resume");
+    }
+
+    fn on_resumed(&mut self) {
+        self.progress_message("Resumed");
+    }
+
+    fn on_status_query(&mut self) {
+        // asked for explicitly, so shown even with --quiet
+        let done = self.received
+            + if self.server_supports_resume {
+                self.bytes_on_disk.unwrap_or(0)
+            } else {
+                0
+            };
+        let secs = self
+            .started
+            .map_or(0.0, |started| started.elapsed().as_secs_f64());
+        let speed = if secs > 0.0 {
+            (self.received as f64 / secs) as u64
+        } else {
+            0
+        };
+        let progress = match self.total {
+            Some(total) if total > 0 => format!(
+                "{}/{} bytes ({}%)",
+                done,
+                total,
+                done.min(total) * 100 / total
+            ),
+            _ => format!("{} bytes", done),
+        };
+        let msg = format!("{}: {}, {}/s", self.fname, progress, HumanBytes(speed));
+        match self.prog_bar {
+            Some(ref bar) if !bar.is_hidden() => bar.println(msg),
+            _ => eprintln!("{}", msg),
+        }
+    }
+
+    fn on_chunk_skipped(&mut self, offsets: (u64, u64)) {
+        if self.verbosity != Verbosity::Quiet {
+            eprintln!(
+                "{}",
+                style(format!(
+                    "warning: giving up on bytes {}-{}, resume with -c to fetch them",
+                    offsets.0, offsets.1
+                ))
+                .yellow()
+            );
+        }
+    }
+
+    fn on_chunk_write_corrupt(&mut self, offset: u64, byte_count: u64) {
+        if self.verbosity != Verbosity::Quiet {
+            eprintln!(
+                "{}",
+                style(format!(
+                    "warning: bytes {}-{} read back wrong from {}, fetching them again",
+                    offset,
+                    offset + byte_count - 1,
+                    self.fname
+                ))
+                .yellow()
+            );
+        }
+    }
+
+    fn last_write_verified(&self) -> bool {
+        self.last_verified
+    }
+
+    fn on_concurrent_fallback(&mut self) {
+        if self.verbosity != Verbosity::Quiet {
+            eprintln!(
+                "{}",
+                style("warning: server ignored the byte ranges, downloading in a single thread")
+                    .yellow()
+            );
         }
     }
 
     fn on_max_retries(&mut self) {
-        if !self.quiet_mode {
+        if self.verbosity != Verbosity::Quiet {
             eprintln!("{}", style("max retries exceeded. Quitting!").red());
         }
-        match self.file.flush() {
-            _ => {}
-        }
+        let _ = self.file.flush();
         if let Some(ref mut file) = self.st_file {
-            match file.flush() {
-                _ => {}
-            }
+            let _ = file.flush();
         }
-        ::std::process::exit(0);
     }
 
     fn on_failure_status(&self, status: i32) {
-        if self.quiet_mode {
+        if self.verbosity == Verbosity::Quiet {
             return;
         }
         if status == 416 {
-            println!(
-                "{}",
-                &style("\nThe file is already fully retrieved; nothing to do.\n").red()
-            );
+            self.info(style("\nThe file is already fully retrieved; nothing to do.\n").red());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gen_filename_from_content_type() {
+        let url = Url::parse("http://example.com/api/export/").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/zip"),
+        );
+        let url_last = NamingStrategy::UrlLast;
+        assert_eq!(
+            gen_filename(&url, None, Some(&headers), url_last).unwrap(),
+            "download.zip"
+        );
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        assert_eq!(
+            gen_filename(&url, None, Some(&headers), url_last).unwrap(),
+            "index.html"
+        );
+        assert_eq!(
+            gen_filename(&url, None, None, url_last).unwrap(),
+            "index.html"
+        );
+    }
+
+    #[test]
+    fn test_gen_filename_from_query() {
+        let url_last = NamingStrategy::UrlLast;
+        let url = Url::parse("http://example.com/get/?id=7&filename=annual+report%2B.pdf").unwrap();
+        assert_eq!(
+            gen_filename(&url, None, None, url_last).unwrap(),
+            "annual report+.pdf"
+        );
+        let url = Url::parse("http://example.com/get/?filename=..%2F..%2Fetc+passwd").unwrap();
+        assert_eq!(
+            gen_filename(&url, None, None, url_last).unwrap(),
+            "etc passwd"
+        );
+        // the path still wins when it names a file
+        let url = Url::parse("http://example.com/foo.txt?filename=bar.txt").unwrap();
+        assert_eq!(gen_filename(&url, None, None, url_last).unwrap(), "foo.txt");
+    }
+
+    fn target(length: u64, etag: Option<&str>) -> ResumeTarget {
+        ResumeTarget {
+            length,
+            etag: etag.map(|val| val.to_owned()),
+            last_modified: None,
+        }
+    }
+
+    #[test]
+    fn test_resume_when_remote_is_larger() {
+        assert_eq!(restart_reason(100, &target(500, None), None), None);
+        let recorded = target(500, Some("\"v1\""));
+        assert_eq!(
+            restart_reason(100, &target(500, Some("\"v1\"")), Some(&recorded)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resume_when_sizes_are_equal() {
+        assert_eq!(restart_reason(500, &target(500, None), None), None);
+        assert_eq!(
+            restart_reason(500, &target(500, None), Some(&target(500, None))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_restart_when_remote_is_smaller() {
+        assert!(restart_reason(600, &target(500, None), None).is_some());
+    }
+
+    #[test]
+    fn test_restart_when_remote_changed() {
+        let recorded = target(400, None);
+        assert!(restart_reason(100, &target(500, None), Some(&recorded)).is_some());
+        let recorded = target(500, Some("\"v1\""));
+        assert!(restart_reason(100, &target(500, Some("\"v2\"")), Some(&recorded)).is_some());
+    }
+
+    #[test]
+    fn test_resume_target_round_trip() {
+        let dir = std::env::temp_dir().join(format!("duma-target-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let fname = dir.join("foo.txt").to_str().unwrap().to_owned();
+        let saved = ResumeTarget {
+            length: 84687,
+            etag: Some("\"abc\"".to_owned()),
+            last_modified: Some("Wed, 11 Nov 2020 10:00:00 GMT".to_owned()),
+        };
+        saved.save(&fname, "1a2b3c4d").unwrap();
+        assert!(dir.join("foo.txt.1a2b3c4d.target").exists());
+        assert_eq!(ResumeTarget::load(&fname, "1a2b3c4d").unwrap(), Some(saved));
+        assert_eq!(ResumeTarget::load(&fname, "5e6f7a8b").unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remote_mtime_is_kept() {
+        let dir = std::env::temp_dir().join(format!("duma-mtime-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("foo.txt");
+        let fname = path.to_str().unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        assert!(!is_local_current(&path, Some(4), Some(mtime)));
+
+        let mut handler =
+            DefaultEventsHandler::new(fname, None, false, false, Verbosity::Quiet, false, None)
+                .unwrap();
+        handler.on_remote_modified(mtime);
+        handler
+            .on_content(b"data", &Progress::new(Some(4)))
+            .unwrap();
+        handler.on_finish().unwrap();
+        drop(handler);
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+        assert!(is_local_current(&path, Some(4), Some(mtime)));
+        assert!(is_local_current(&path, None, Some(mtime)));
+        assert!(!is_local_current(&path, Some(5), Some(mtime)));
+        assert!(!is_local_current(&path, Some(4), None));
+        let newer = mtime + Duration::from_secs(60);
+        assert!(!is_local_current(&path, Some(4), Some(newer)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Writes through to a file with the first byte of every write flipped.
+    struct CorruptingStorage(fs::File);
+
+    impl Write for CorruptingStorage {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut corrupted = buf.to_vec();
+            corrupted[0] ^= 0xff;
+            self.0.write_all(&corrupted)?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl io::Seek for CorruptingStorage {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_every_nth_chunk_is_read_back() {
+        let dir = std::env::temp_dir().join(format!("duma-verify-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("foo.txt");
+        let fname = path.to_str().unwrap();
+
+        let storage = Box::new(fs::File::create(&path).unwrap());
+        let mut handler = DefaultEventsHandler::with_storage(fname, storage, Verbosity::Quiet)
+            .verify_interval(Some(1));
+        let progress = Progress::new(Some(8));
+        handler
+            .on_concurrent_content((4, 4, b"data"), &progress)
+            .unwrap();
+        handler
+            .on_concurrent_content((4, 0, b"more"), &progress)
+            .unwrap();
+
+        let storage = Box::new(CorruptingStorage(fs::File::create(&path).unwrap()));
+        let mut handler = DefaultEventsHandler::with_storage(fname, storage, Verbosity::Quiet)
+            .verify_interval(Some(2));
+        // only the second piece is checked
+        handler
+            .on_concurrent_content((4, 0, b"data"), &progress)
+            .unwrap();
+        let err = handler
+            .on_concurrent_content((4, 4, b"more"), &progress)
+            .unwrap_err();
+        match err.downcast::<DownloadError>().unwrap() {
+            DownloadError::ChunkWriteCorrupt { offset, byte_count } => {
+                assert_eq!((offset, byte_count), (4, 4))
+            }
+            e => panic!("unexpected error: {}", e),
         }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct FailingStorage(io::ErrorKind);
+
+    impl Write for FailingStorage {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(self.0, "write failed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Seek for FailingStorage {
+        fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_disk_full_is_reported() {
+        let storage = Box::new(FailingStorage(io::ErrorKind::StorageFull));
+        let mut handler = DefaultEventsHandler::with_storage("foo.txt", storage, Verbosity::Quiet);
+        let progress = Progress::new(Some(4));
+        let err = handler.on_content(b"data", &progress).unwrap_err();
+        assert!(err.to_string().starts_with("disk full"));
+        let err = handler
+            .on_concurrent_content((4, 0, b"data"), &progress)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("disk full"));
+    }
+
+    #[test]
+    fn test_bars_join_the_shared_multi_progress() {
+        let multi = Arc::new(MultiProgress::with_draw_target(
+            indicatif::ProgressDrawTarget::hidden(),
+        ));
+        for fname in &["foo.txt", "bar.txt"] {
+            let storage = Box::new(io::Cursor::new(Vec::new()));
+            let mut handler = DefaultEventsHandler::with_storage(fname, storage, Verbosity::Normal)
+                .multi_progress(Some(multi.clone()));
+            handler.on_ftp_content_length(Some(4));
+            // bars in a MultiProgress draw through it, not on their own
+            assert!(!handler.prog_bar.as_ref().unwrap().is_hidden());
+        }
+    }
+
+    #[test]
+    fn test_other_write_errors_pass_through() {
+        let storage = Box::new(FailingStorage(io::ErrorKind::PermissionDenied));
+        let mut handler = DefaultEventsHandler::with_storage("foo.txt", storage, Verbosity::Quiet);
+        let err = handler
+            .on_content(b"data", &Progress::new(Some(4)))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "write failed");
     }
 }
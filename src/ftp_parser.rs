@@ -0,0 +1,192 @@
+/// What kind of file system object an FTP `LIST` line describes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FtpEntryKind {
+    File,
+    Directory,
+    /// A symbolic link, with its target when the server reports one.
+    Symlink(Option<String>),
+}
+
+/// One entry of an FTP `LIST` reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FtpListEntry {
+    pub name: String,
+    pub kind: FtpEntryKind,
+    pub size: Option<u64>,
+}
+
+/// Parses a line of `LIST` output in UNIX (`ls -l`), Windows (IIS/DOS) or
+/// IBM mainframe (MVS) format. Returns `None` for lines that don't describe
+/// an entry, such as `total 12` or column headers.
+pub fn parse_ftp_list_line(line: &str) -> Option<FtpListEntry> {
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    parse_unix_line(line)
+        .or_else(|| parse_windows_line(line))
+        .or_else(|| parse_mainframe_line(line))
+}
+
+/// Splits off the first `n` whitespace separated fields and returns them
+/// with the untouched rest of the line, which keeps spaces in file names.
+fn split_fields(line: &str, n: usize) -> Option<(Vec<&str>, &str)> {
+    let mut fields = Vec::with_capacity(n);
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    if rest.is_empty() {
+        return None;
+    }
+    Some((fields, rest))
+}
+
+// -rw-r--r--   1 owner group    84687 Nov 11  2020 foo.txt
+fn parse_unix_line(line: &str) -> Option<FtpListEntry> {
+    let perms = line.split_whitespace().next()?;
+    if perms.len() != 10 || !"-dlbcps".contains(&perms[..1]) {
+        return None;
+    }
+    let (fields, name) = split_fields(line, 8)?;
+    let size = fields[4].parse().ok();
+    let (name, kind) = match &perms[..1] {
+        "d" => (name, FtpEntryKind::Directory),
+        "l" => match name.find(" -> ") {
+            Some(idx) => (
+                &name[..idx],
+                FtpEntryKind::Symlink(Some(name[idx + 4..].to_owned())),
+            ),
+            None => (name, FtpEntryKind::Symlink(None)),
+        },
+        _ => (name, FtpEntryKind::File),
+    };
+    Some(FtpListEntry {
+        name: name.to_owned(),
+        kind,
+        size,
+    })
+}
+
+// 11-11-20  02:15PM       <DIR>          docs
+// 11-11-20  02:15PM                84687 foo.txt
+fn parse_windows_line(line: &str) -> Option<FtpListEntry> {
+    let (fields, name) = split_fields(line, 3)?;
+    let is_date = fields[0].len() >= 8
+        && fields[0]
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '-' || c == '/');
+    if !is_date || !fields[1].contains(':') {
+        return None;
+    }
+    let (kind, size) = if fields[2].eq_ignore_ascii_case("<DIR>") {
+        (FtpEntryKind::Directory, None)
+    } else {
+        (FtpEntryKind::File, Some(fields[2].parse().ok()?))
+    };
+    Some(FtpListEntry {
+        name: name.to_owned(),
+        kind,
+        size,
+    })
+}
+
+// Volume Unit    Referred Ext Used Recfm Lrecl BlkSz Dsorg Dsname
+// WYOSPT 3420   2003/03/18  1    1  FB      80  3120  PS  USER.FOO.TXT
+// Migrated                                                USER.OLD.DATA
+fn parse_mainframe_line(line: &str) -> Option<FtpListEntry> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.as_slice() {
+        ["Migrated", name] => Some(FtpListEntry {
+            name: (*name).to_owned(),
+            kind: FtpEntryKind::File,
+            size: None,
+        }),
+        [_, _, referred, .., dsorg, name] if fields.len() >= 10 && referred.contains('/') => {
+            let kind = if *dsorg == "PO" || *dsorg == "PO-E" {
+                FtpEntryKind::Directory
+            } else {
+                FtpEntryKind::File
+            };
+            Some(FtpListEntry {
+                name: (*name).to_owned(),
+                kind,
+                size: None,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, kind: FtpEntryKind, size: Option<u64>) -> Option<FtpListEntry> {
+        Some(FtpListEntry {
+            name: name.to_owned(),
+            kind,
+            size,
+        })
+    }
+
+    #[test]
+    fn test_unix_lines() {
+        assert_eq!(
+            parse_ftp_list_line("-rw-r--r--   1 owner group    84687 Nov 11  2020 foo.txt\r\n"),
+            entry("foo.txt", FtpEntryKind::File, Some(84687))
+        );
+        assert_eq!(
+            parse_ftp_list_line("drwxr-xr-x   2 owner group     4096 Jan  1 12:00 my docs"),
+            entry("my docs", FtpEntryKind::Directory, Some(4096))
+        );
+        assert_eq!(
+            parse_ftp_list_line(
+                "lrwxrwxrwx   1 owner group        7 Jan  1 12:00 latest -> v1.2.3"
+            ),
+            entry(
+                "latest",
+                FtpEntryKind::Symlink(Some("v1.2.3".to_owned())),
+                Some(7)
+            )
+        );
+        assert_eq!(parse_ftp_list_line("total 12"), None);
+    }
+
+    #[test]
+    fn test_windows_lines() {
+        assert_eq!(
+            parse_ftp_list_line("11-11-20  02:15PM       <DIR>          docs"),
+            entry("docs", FtpEntryKind::Directory, None)
+        );
+        assert_eq!(
+            parse_ftp_list_line("11-11-20  02:15PM                84687 foo bar.txt"),
+            entry("foo bar.txt", FtpEntryKind::File, Some(84687))
+        );
+    }
+
+    #[test]
+    fn test_mainframe_lines() {
+        assert_eq!(
+            parse_ftp_list_line("Volume Unit    Referred Ext Used Recfm Lrecl BlkSz Dsorg Dsname"),
+            None
+        );
+        assert_eq!(
+            parse_ftp_list_line(
+                "WYOSPT 3420   2003/03/18  1    1  FB      80  3120  PS  USER.FOO.TXT"
+            ),
+            entry("USER.FOO.TXT", FtpEntryKind::File, None)
+        );
+        assert_eq!(
+            parse_ftp_list_line(
+                "WYOSPT 3420   2003/03/18  1   15  U     6447  6447  PO  USER.LOADLIB"
+            ),
+            entry("USER.LOADLIB", FtpEntryKind::Directory, None)
+        );
+        assert_eq!(
+            parse_ftp_list_line(
+                "Migrated                                                USER.OLD.DATA"
+            ),
+            entry("USER.OLD.DATA", FtpEntryKind::File, None)
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use std::cell::RefCell;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use failure::{bail, format_err, Fallible};
+use native_tls::{TlsConnector, TlsStream};
+use url::Url;
+
+use crate::core::{DownloadError, DownloadStats, Downloader, EventsHandler};
+use crate::progress::Progress;
+use crate::sha256::{to_hex, Sha256};
+
+const DEFAULT_PORT: u16 = 1965;
+/// The spec caps the response header at 1024 bytes of meta.
+const MAX_HEADER_LEN: usize = 1024 + 3 + 2;
+const MAX_REDIRECTS: usize = 5;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The `<STATUS> <META>` line a Gemini server answers with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeminiResponseHeader {
+    pub status: u8,
+    /// The MIME type on success, the target of a redirect, or an error
+    /// message.
+    pub meta: String,
+}
+
+impl GeminiResponseHeader {
+    pub fn is_success(&self) -> bool {
+        (20..30).contains(&self.status)
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        (30..40).contains(&self.status)
+    }
+
+    /// The MIME type of a successful response, which defaults to gemtext.
+    pub fn mime_type(&self) -> &str {
+        if self.meta.is_empty() {
+            "text/gemini; charset=utf-8"
+        } else {
+            &self.meta
+        }
+    }
+}
+
+/// Parses a response header line, with or without its trailing CRLF.
+pub fn parse_response_header(line: &str) -> Fallible<GeminiResponseHeader> {
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    let (status, meta) = match line.split_once(' ') {
+        Some((status, meta)) => (status, meta),
+        None => (line, ""),
+    };
+    if status.len() != 2 || !status.bytes().all(|b| b.is_ascii_digit()) {
+        bail!("invalid gemini response header: {:?}", line);
+    }
+    Ok(GeminiResponseHeader {
+        status: status.parse()?,
+        meta: meta.trim().to_owned(),
+    })
+}
+
+/// Certificate fingerprints seen so far, one `host:port sha256` per line.
+///
+/// Gemini servers mostly use self signed certificates, so they are trusted
+/// on first use and must stay the same afterwards.
+pub struct KnownHosts {
+    path: PathBuf,
+}
+
+impl KnownHosts {
+    pub fn new(path: PathBuf) -> KnownHosts {
+        KnownHosts { path }
+    }
+
+    /// `gemini_known_hosts` next to the user configuration.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("duma").join("gemini_known_hosts"))
+    }
+
+    /// Accepts `fingerprint` for `host` if it is the one recorded, or
+    /// records it if the host is new.
+    pub fn verify(&self, host: &str, fingerprint: &str) -> Fallible<()> {
+        let known = fs::read_to_string(&self.path).unwrap_or_default();
+        let recorded = known.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(name), Some(hash)) if name == host => Some(hash.to_owned()),
+                _ => None,
+            }
+        });
+        match recorded {
+            Some(hash) if hash == fingerprint => Ok(()),
+            Some(hash) => bail!(
+                "certificate of {} changed: expected {}, got {} (remove its line from {} if this is expected)",
+                host,
+                hash,
+                fingerprint,
+                self.path.display()
+            ),
+            None => {
+                if let Some(dir) = self.path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                writeln!(file, "{} {}", host, fingerprint)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+type GeminiBody = BufReader<TlsStream<TcpStream>>;
+
+/// Downloads `gemini://` urls, following redirects.
+///
+/// Call `fetch_header` first to learn the MIME type, e.g. to name the
+/// output file, then `download` to stream the body to the hooks.
+pub struct GeminiDownload {
+    url: Url,
+    hooks: Vec<RefCell<Box<dyn EventsHandler>>>,
+    known_hosts: Option<KnownHosts>,
+    response: Option<(GeminiResponseHeader, GeminiBody)>,
+}
+
+impl GeminiDownload {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            hooks: Vec::new(),
+            known_hosts: KnownHosts::default_path().map(KnownHosts::new),
+            response: None,
+        }
+    }
+
+    /// Where certificate fingerprints are kept, `None` to trust any.
+    pub fn known_hosts(&mut self, known_hosts: Option<KnownHosts>) -> &mut GeminiDownload {
+        self.known_hosts = known_hosts;
+        self
+    }
+
+    pub fn events_hook<E: EventsHandler + 'static>(&mut self, hk: E) -> &mut GeminiDownload {
+        self.hooks.push(RefCell::new(Box::new(hk)));
+        self
+    }
+
+    /// Sends the request and returns the successful response's header.
+    pub fn fetch_header(&mut self) -> Result<GeminiResponseHeader, DownloadError> {
+        self.try_fetch_header().map_err(DownloadError::from)
+    }
+
+    fn try_fetch_header(&mut self) -> Fallible<GeminiResponseHeader> {
+        if let Some((ref header, _)) = self.response {
+            return Ok(header.clone());
+        }
+        let mut url = self.url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let (header, body) = self.request(&url)?;
+            if header.is_success() {
+                self.response = Some((header.clone(), body));
+                return Ok(header);
+            }
+            if !header.is_redirect() {
+                bail!("gemini server answered {} {}", header.status, header.meta);
+            }
+            url = url.join(&header.meta)?;
+            if url.scheme() != "gemini" {
+                bail!("refusing to follow a gemini redirect to {}", url);
+            }
+        }
+        bail!("too many gemini redirects from {}", self.url)
+    }
+
+    fn request(&self, url: &Url) -> Fallible<(GeminiResponseHeader, GeminiBody)> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| format_err!("failed to parse hostname from url: {}", url))?;
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let tcp = TcpStream::connect((host, port))?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        // the certificate is checked against the known hosts instead
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()?;
+        let mut stream = connector
+            .connect(host, tcp)
+            .map_err(|e| format_err!("TLS handshake with {} failed: {}", host, e))?;
+        if let Some(ref known_hosts) = self.known_hosts {
+            let cert = stream
+                .peer_certificate()?
+                .ok_or_else(|| format_err!("{} sent no certificate", host))?;
+            let mut ctx = Sha256::new();
+            ctx.update(&cert.to_der()?);
+            known_hosts.verify(&format!("{}:{}", host, port), &to_hex(&ctx.finish()))?;
+        }
+        stream.write_all(format!("{}\r\n", url).as_bytes())?;
+        stream.flush()?;
+
+        let mut body = BufReader::new(stream);
+        let mut line = Vec::new();
+        body.by_ref()
+            .take(MAX_HEADER_LEN as u64)
+            .read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\n") {
+            bail!("gemini response header from {} is too long", host);
+        }
+        let header = parse_response_header(&String::from_utf8_lossy(&line))?;
+        Ok((header, body))
+    }
+
+    pub fn download(&mut self) -> Result<(), DownloadError> {
+        self.try_download().map_err(DownloadError::from)
+    }
+
+    fn try_download(&mut self) -> Fallible<()> {
+        let started = Instant::now();
+        self.try_fetch_header()?;
+        let (_, mut body) = self
+            .response
+            .take()
+            .ok_or_else(|| format_err!("no gemini response"))?;
+        // gemini has no content length, so the bar is a spinner
+        for hook in &self.hooks {
+            hook.borrow_mut().on_ftp_content_length(None);
+        }
+
+        let mut bytes = 0u64;
+        let mut progress = Progress::new(None);
+        let mut buffer = vec![0; 16 * 1024];
+        loop {
+            let bcount = body.read(&mut buffer[..])?;
+            if bcount == 0 {
+                break;
+            }
+            bytes += bcount as u64;
+            progress.bytes_downloaded = bytes;
+            for hk in &self.hooks {
+                hk.borrow_mut().on_content(&buffer[..bcount], &progress)?;
+            }
+        }
+
+        let stats = DownloadStats {
+            url: self.url.to_string(),
+            bytes,
+            duration: started.elapsed(),
+            retries: 0,
+            chunks: 1,
+            resumed: false,
+            wasted_bytes: 0,
+        };
+        for hook in &self.hooks {
+            hook.borrow_mut().on_finish()?;
+            hook.borrow_mut().on_finish_with_stats(&stats)?;
+        }
+        Ok(())
+    }
+}
+
+impl Downloader for GeminiDownload {
+    fn download(&mut self) -> Result<(), DownloadError> {
+        GeminiDownload::download(self)
+    }
+
+    fn events_hook_dyn(&mut self, hk: Box<dyn EventsHandler>) {
+        self.hooks.push(RefCell::new(hk));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response_header() {
+        let header = parse_response_header("20 application/zip\r\n").unwrap();
+        assert_eq!(header.status, 20);
+        assert_eq!(header.meta, "application/zip");
+        assert!(header.is_success());
+
+        let header = parse_response_header("31 gemini://example.org/new\r\n").unwrap();
+        assert!(header.is_redirect());
+        assert_eq!(header.meta, "gemini://example.org/new");
+
+        let header = parse_response_header("20\r\n").unwrap();
+        assert_eq!(header.mime_type(), "text/gemini; charset=utf-8");
+
+        assert!(parse_response_header("OK text/plain\r\n").is_err());
+        assert!(parse_response_header("200 text/plain\r\n").is_err());
+        assert!(parse_response_header("").is_err());
+    }
+
+    #[test]
+    fn test_known_hosts_trust_on_first_use() {
+        let path = std::env::temp_dir().join(format!("duma-known-hosts-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let known_hosts = KnownHosts::new(path.clone());
+        known_hosts.verify("example.org:1965", "aa").unwrap();
+        known_hosts.verify("example.org:1965", "aa").unwrap();
+        known_hosts.verify("other.org:1965", "bb").unwrap();
+        assert!(known_hosts.verify("example.org:1965", "cc").is_err());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "example.org:1965 aa\nother.org:1965 bb\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+use std::io::Write;
+use std::time::SystemTime;
+
+use failure::Fallible;
+use reqwest::header::{self, HeaderMap};
+
+use crate::core::{DownloadStats, EventsHandler};
+use crate::progress::Progress;
+
+/// Fans every event out to a list of handlers, in the order they were added.
+///
+/// Fallible events are delivered to all handlers even when one of them fails;
+/// the first error is returned once every handler has been called.
+#[derive(Default)]
+pub struct MultiHandler {
+    handlers: Vec<Box<dyn EventsHandler>>,
+}
+
+impl MultiHandler {
+    pub fn new(handlers: Vec<Box<dyn EventsHandler>>) -> Self {
+        MultiHandler { handlers }
+    }
+
+    pub fn push(&mut self, h: impl EventsHandler + 'static) {
+        self.handlers.push(Box::new(h));
+    }
+
+    fn dispatch<F>(&mut self, mut f: F) -> Fallible<()>
+    where
+        F: FnMut(&mut dyn EventsHandler) -> Fallible<()>,
+    {
+        let mut first_err = None;
+        for handler in &mut self.handlers {
+            if let Err(e) = f(handler.as_mut()) {
+                first_err.get_or_insert(e);
+            }
+        }
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl EventsHandler for MultiHandler {
+    fn on_resume_download(&mut self, bytes_on_disk: u64) {
+        for handler in &mut self.handlers {
+            handler.on_resume_download(bytes_on_disk);
+        }
+    }
+
+    fn on_status_line(&mut self, status_line: &str) {
+        for handler in &mut self.handlers {
+            handler.on_status_line(status_line);
+        }
+    }
+
+    fn on_headers(&mut self, headers: HeaderMap) {
+        for handler in &mut self.handlers {
+            handler.on_headers(headers.clone());
+        }
+    }
+
+    fn on_content(&mut self, content: &[u8], progress: &Progress) -> Fallible<()> {
+        self.dispatch(|handler| handler.on_content(content, progress))
+    }
+
+    fn on_concurrent_content(
+        &mut self,
+        content: (u64, u64, &[u8]),
+        progress: &Progress,
+    ) -> Fallible<()> {
+        self.dispatch(|handler| handler.on_concurrent_content(content, progress))
+    }
+
+    fn on_content_length(&mut self, ct_len: u64) {
+        for handler in &mut self.handlers {
+            handler.on_content_length(ct_len);
+        }
+    }
+
+    fn on_ftp_content_length(&mut self, ct_len: Option<u64>) {
+        for handler in &mut self.handlers {
+            handler.on_ftp_content_length(ct_len);
+        }
+    }
+
+    fn on_success_status(&self) {
+        for handler in &self.handlers {
+            handler.on_success_status();
+        }
+    }
+
+    fn on_failure_status(&self, status_code: i32) {
+        for handler in &self.handlers {
+            handler.on_failure_status(status_code);
+        }
+    }
+
+    fn on_finish(&mut self) -> Fallible<()> {
+        self.dispatch(|handler| handler.on_finish())
+    }
+
+    fn on_finish_with_stats(&mut self, stats: &DownloadStats) -> Fallible<()> {
+        self.dispatch(|handler| handler.on_finish_with_stats(stats))
+    }
+
+    fn on_connections_chosen(&mut self, num_workers: usize) {
+        for handler in &mut self.handlers {
+            handler.on_connections_chosen(num_workers);
+        }
+    }
+
+    fn on_copied_to_clipboard(&mut self, bytes: u64) {
+        for handler in &mut self.handlers {
+            handler.on_copied_to_clipboard(bytes);
+        }
+    }
+
+    fn on_concurrent_fallback(&mut self) {
+        for handler in &mut self.handlers {
+            handler.on_concurrent_fallback();
+        }
+    }
+
+    fn on_max_retries(&mut self) {
+        for handler in &mut self.handlers {
+            handler.on_max_retries();
+        }
+    }
+
+    fn on_paused(&mut self) {
+        for handler in &mut self.handlers {
+            handler.on_paused();
+        }
+    }
+
+    fn on_resumed(&mut self) {
+        for handler in &mut self.handlers {
+            handler.on_resumed();
+        }
+    }
+
+    fn on_status_query(&mut self) {
+        for handler in &mut self.handlers {
+            handler.on_status_query();
+        }
+    }
+
+    fn on_chunk_skipped(&mut self, offsets: (u64, u64)) {
+        for handler in &mut self.handlers {
+            handler.on_chunk_skipped(offsets);
+        }
+    }
+
+    fn on_chunk_write_corrupt(&mut self, offset: u64, byte_count: u64) {
+        for handler in &mut self.handlers {
+            handler.on_chunk_write_corrupt(offset, byte_count);
+        }
+    }
+
+    fn on_data_verified(&mut self, offset: u64, length: u64, hash: [u8; 32]) {
+        for handler in &mut self.handlers {
+            handler.on_data_verified(offset, length, hash);
+        }
+    }
+
+    fn last_write_verified(&self) -> bool {
+        self.handlers
+            .iter()
+            .any(|handler| handler.last_write_verified())
+    }
+
+    fn on_not_modified(&mut self) {
+        for handler in &mut self.handlers {
+            handler.on_not_modified();
+        }
+    }
+
+    fn on_server_supports_resume(&mut self) {
+        for handler in &mut self.handlers {
+            handler.on_server_supports_resume();
+        }
+    }
+
+    fn on_remote_modified(&mut self, modified: SystemTime) {
+        for handler in &mut self.handlers {
+            handler.on_remote_modified(modified);
+        }
+    }
+
+    fn on_file_renamed(&mut self, old: &str, new: &str) -> Fallible<()> {
+        self.dispatch(|handler| handler.on_file_renamed(old, new))
+    }
+}
+
+/// Reports progress as `bytes_done:N total:N` lines for other processes,
+/// e.g. on a file descriptor handed over by a shell script. An unknown
+/// total is reported as 0.
+pub struct FdProgressHandler<W: Write> {
+    out: W,
+    done: u64,
+    total: u64,
+}
+
+impl<W: Write> FdProgressHandler<W> {
+    pub fn new(out: W) -> Self {
+        FdProgressHandler {
+            out,
+            done: 0,
+            total: 0,
+        }
+    }
+
+    fn report(&mut self, byte_count: u64) -> Fallible<()> {
+        self.done += byte_count;
+        writeln!(self.out, "bytes_done:{} total:{}", self.done, self.total)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> EventsHandler for FdProgressHandler<W> {
+    fn on_headers(&mut self, headers: HeaderMap) {
+        self.total = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(0);
+    }
+
+    fn on_ftp_content_length(&mut self, ct_len: Option<u64>) {
+        self.total = ct_len.unwrap_or(0);
+    }
+
+    fn on_content(&mut self, content: &[u8], _progress: &Progress) -> Fallible<()> {
+        self.report(content.len() as u64)
+    }
+
+    fn on_concurrent_content(
+        &mut self,
+        content: (u64, u64, &[u8]),
+        _progress: &Progress,
+    ) -> Fallible<()> {
+        self.report(content.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failure::format_err;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Recorder {
+        name: &'static str,
+        fail: bool,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Recorder {
+        fn record(&self, event: &str) {
+            self.log
+                .borrow_mut()
+                .push(format!("{}:{}", self.name, event));
+        }
+    }
+
+    impl EventsHandler for Recorder {
+        fn on_headers(&mut self, _headers: HeaderMap) {
+            self.record("headers");
+        }
+
+        fn on_content_length(&mut self, ct_len: u64) {
+            self.record(&format!("length={}", ct_len));
+        }
+
+        fn on_content(&mut self, content: &[u8], _progress: &Progress) -> Fallible<()> {
+            self.record(&format!("content={}", content.len()));
+            if self.fail {
+                return Err(format_err!("{} failed", self.name));
+            }
+            Ok(())
+        }
+
+        fn on_success_status(&self) {
+            self.record("success");
+        }
+
+        fn on_finish(&mut self) -> Fallible<()> {
+            self.record("finish");
+            Ok(())
+        }
+    }
+
+    fn recorder(name: &'static str, fail: bool, log: &Rc<RefCell<Vec<String>>>) -> Recorder {
+        Recorder {
+            name,
+            fail,
+            log: log.clone(),
+        }
+    }
+
+    #[test]
+    fn test_all_handlers_receive_events() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut multi = MultiHandler::new(vec![Box::new(recorder("a", false, &log))]);
+        multi.push(recorder("b", false, &log));
+
+        multi.on_headers(HeaderMap::new());
+        multi.on_content_length(3);
+        multi.on_content(b"abc", &Progress::new(Some(3))).unwrap();
+        multi.on_success_status();
+        multi.on_finish().unwrap();
+
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "a:headers",
+                "b:headers",
+                "a:length=3",
+                "b:length=3",
+                "a:content=3",
+                "b:content=3",
+                "a:success",
+                "b:success",
+                "a:finish",
+                "b:finish",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fd_progress_lines() {
+        let mut out = Vec::new();
+        {
+            let mut handler = FdProgressHandler::new(&mut out);
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, "10".parse().unwrap());
+            handler.on_headers(headers);
+            let progress = Progress::new(Some(10));
+            handler.on_content(b"abcd", &progress).unwrap();
+            handler
+                .on_concurrent_content((6, 4, b"efghij"), &progress)
+                .unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "bytes_done:4 total:10\nbytes_done:10 total:10\n"
+        );
+    }
+
+    #[test]
+    fn test_first_error_is_returned() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut multi = MultiHandler::default();
+        multi.push(recorder("a", true, &log));
+        multi.push(recorder("b", true, &log));
+        multi.push(recorder("c", false, &log));
+
+        let err = multi.on_content(b"x", &Progress::new(None)).unwrap_err();
+        assert_eq!(err.to_string(), "a failed");
+        assert_eq!(
+            *log.borrow(),
+            vec!["a:content=1", "b:content=1", "c:content=1"]
+        );
+    }
+}
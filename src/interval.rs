@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+/// A set of byte ranges, kept as disjoint half open `[start, end)`
+/// intervals keyed by their start. Overlapping and touching ranges are
+/// merged as they are added, whatever order they come in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IntervalSet {
+    intervals: BTreeMap<u64, u64>,
+}
+
+impl IntervalSet {
+    pub fn new() -> IntervalSet {
+        IntervalSet::default()
+    }
+
+    /// Adds `[start, end)`. An empty range adds nothing.
+    pub fn add(&mut self, mut start: u64, mut end: u64) {
+        if start >= end {
+            return;
+        }
+        if let Some((&prev_start, &prev_end)) = self.intervals.range(..=start).next_back() {
+            if prev_end >= start {
+                start = prev_start;
+                end = end.max(prev_end);
+            }
+        }
+        let merged: Vec<(u64, u64)> = self
+            .intervals
+            .range(start..=end)
+            .map(|(&start, &end)| (start, end))
+            .collect();
+        for (next_start, next_end) in merged {
+            self.intervals.remove(&next_start);
+            end = end.max(next_end);
+        }
+        self.intervals.insert(start, end);
+    }
+
+    /// The disjoint `[start, end)` intervals, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.intervals.iter().map(|(&start, &end)| (start, end))
+    }
+
+    /// The `[start, end)` ranges of `[0, total)` the set doesn't cover, in
+    /// order.
+    pub fn complement(&self, total: u64) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut pos = 0;
+        for (start, end) in self.iter() {
+            if start >= total {
+                break;
+            }
+            if start > pos {
+                gaps.push((pos, start));
+            }
+            pos = pos.max(end);
+        }
+        if pos < total {
+            gaps.push((pos, total));
+        }
+        gaps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_add_merges() {
+        let mut set = IntervalSet::new();
+        set.add(10, 20);
+        set.add(30, 40);
+        set.add(0, 5);
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            vec![(0, 5), (10, 20), (30, 40)]
+        );
+        // touching, then spanning the gaps
+        set.add(5, 8);
+        set.add(15, 35);
+        set.add(7, 7);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![(0, 8), (10, 40)]);
+        set.add(0, 100);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![(0, 100)]);
+    }
+
+    #[test]
+    fn test_complement() {
+        let mut set = IntervalSet::new();
+        assert_eq!(set.complement(10), vec![(0, 10)]);
+        set.add(2, 4);
+        set.add(6, 12);
+        assert_eq!(set.complement(10), vec![(0, 2), (4, 6)]);
+        assert_eq!(set.complement(15), vec![(0, 2), (4, 6), (12, 15)]);
+        assert_eq!(set.complement(3), vec![(0, 2)]);
+        assert_eq!(set.complement(0), vec![]);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_set_and_complement_partition(
+            ranges in prop::collection::vec((0u64..1000, 0u64..100), 0..50),
+            total in 0u64..1200,
+        ) {
+            let mut set = IntervalSet::new();
+            for &(start, len) in &ranges {
+                set.add(start, start + len);
+            }
+            let intervals: Vec<_> = set.iter().collect();
+            // disjoint, not touching and in order
+            for pair in intervals.windows(2) {
+                prop_assert!(pair[0].0 < pair[0].1 && pair[0].1 < pair[1].0);
+            }
+            let gaps = set.complement(total);
+            for pos in 0..total {
+                let covered = ranges.iter().any(|&(start, len)| start <= pos && pos < start + len);
+                let in_gap = gaps.iter().any(|&(start, end)| start <= pos && pos < end);
+                prop_assert!(covered != in_gap, "byte {} covered: {}", pos, covered);
+            }
+            for &(start, end) in &gaps {
+                prop_assert!(start < end && end <= total);
+            }
+        }
+    }
+}
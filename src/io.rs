@@ -0,0 +1,181 @@
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// How much `ResumableWriter` writes between syncs unless told otherwise.
+pub const DEFAULT_SYNC_INTERVAL_BYTES: u64 = 4 * 1024 * 1024;
+
+/// A buffered output file that syncs its data to disk every
+/// `sync_interval_bytes` and remembers how far the synced data reaches.
+///
+/// A crash of the machine can lose whatever the OS hadn't written out yet,
+/// so the length of the file says more than what is safely on disk. With
+/// `record_to` the synced offset is also saved to a file for `--continue`
+/// to resume from. Clones share the same file, so one can go to the
+/// handler as its storage while another keeps track of the syncs.
+#[derive(Debug, Clone)]
+pub struct ResumableWriter(Rc<RefCell<Inner>>);
+
+#[derive(Debug)]
+struct Inner {
+    file: BufWriter<File>,
+    pos: u64,
+    /// One past the furthest byte written.
+    end: u64,
+    sync_interval_bytes: u64,
+    unsynced: u64,
+    last_synced_offset: u64,
+    record: Option<PathBuf>,
+}
+
+impl ResumableWriter {
+    /// Wraps `file`. An `append` file writes after what it already holds,
+    /// which counts as synced.
+    pub fn new(file: File, append: bool) -> io::Result<ResumableWriter> {
+        let start = if append { file.metadata()?.len() } else { 0 };
+        Ok(ResumableWriter(Rc::new(RefCell::new(Inner {
+            file: BufWriter::new(file),
+            pos: start,
+            end: start,
+            sync_interval_bytes: DEFAULT_SYNC_INTERVAL_BYTES,
+            unsynced: 0,
+            last_synced_offset: start,
+            record: None,
+        }))))
+    }
+
+    pub fn set_sync_interval(&self, bytes: u64) {
+        self.0.borrow_mut().sync_interval_bytes = bytes.max(1);
+    }
+
+    /// Saves the synced offset at `path` after every sync, see
+    /// `read_synced_offset`.
+    pub fn record_to(&self, path: PathBuf) {
+        self.0.borrow_mut().record = Some(path);
+    }
+
+    /// Records at `path` from now on, if recording at all, e.g. after the
+    /// file was renamed.
+    pub fn move_record(&self, path: PathBuf) {
+        let mut inner = self.0.borrow_mut();
+        if inner.record.is_some() {
+            inner.record = Some(path);
+        }
+    }
+
+    /// Everything before this offset has been synced to disk.
+    pub fn last_synced_offset(&self) -> u64 {
+        self.0.borrow().last_synced_offset
+    }
+
+    /// Syncs the rest and stops recording: the download is complete and
+    /// needs no resume point.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut inner = self.0.borrow_mut();
+        inner.record = None;
+        inner.sync()
+    }
+}
+
+impl Inner {
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.unsynced = 0;
+        self.last_synced_offset = self.end;
+        if let Some(ref path) = self.record {
+            fs::write(path, self.last_synced_offset.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // an interrupted download keeps what it got as its resume point
+        if self.record.is_some() {
+            let _ = self.sync();
+        }
+    }
+}
+
+impl Write for ResumableWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.0.borrow_mut();
+        let written = inner.file.write(buf)?;
+        inner.pos += written as u64;
+        inner.end = inner.end.max(inner.pos);
+        inner.unsynced += written as u64;
+        if inner.unsynced >= inner.sync_interval_bytes {
+            inner.sync()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().file.flush()
+    }
+}
+
+impl Seek for ResumableWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut inner = self.0.borrow_mut();
+        inner.pos = inner.file.seek(pos)?;
+        Ok(inner.pos)
+    }
+}
+
+/// The offset a `ResumableWriter` recorded at `path`, if there is one.
+pub fn read_synced_offset(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syncs_every_interval() {
+        let dir = std::env::temp_dir().join(format!("duma-io-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("foo.txt");
+        let record = dir.join("foo.txt.synced");
+        fs::write(&path, b"1234").unwrap();
+
+        let file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        let writer = ResumableWriter::new(file, true).unwrap();
+        writer.set_sync_interval(4);
+        writer.record_to(record.clone());
+        let mut output = writer.clone();
+        assert_eq!(writer.last_synced_offset(), 4);
+        output.write_all(b"56").unwrap();
+        assert_eq!(writer.last_synced_offset(), 4);
+        output.write_all(b"78").unwrap();
+        assert_eq!(writer.last_synced_offset(), 8);
+        assert_eq!(read_synced_offset(&record), Some(8));
+        output.write_all(b"9").unwrap();
+        assert_eq!(read_synced_offset(&record), Some(8));
+
+        // dropping an unfinished download records all of it
+        drop(output);
+        drop(writer);
+        assert_eq!(read_synced_offset(&record), Some(9));
+        assert_eq!(fs::read(&path).unwrap(), b"123456789");
+
+        fs::remove_file(&record).unwrap();
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let writer = ResumableWriter::new(file, false).unwrap();
+        writer.record_to(record.clone());
+        let mut output = writer.clone();
+        output.seek(SeekFrom::Start(2)).unwrap();
+        output.write_all(b"ab").unwrap();
+        writer.finish().unwrap();
+        drop(output);
+        drop(writer);
+        assert_eq!(read_synced_offset(&record), None);
+        assert_eq!(fs::read(&path).unwrap(), b"12ab56789");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,29 @@
+pub mod aria2;
+pub mod audit;
+pub mod auth;
+pub mod bandwidth;
 pub mod bar;
+pub mod checkpoint;
+pub mod clipboard;
+pub mod config_file;
 pub mod core;
+pub mod dns;
 pub mod download;
+pub mod ftp_parser;
+pub mod gemini;
+pub mod handler;
+pub mod interval;
+pub mod io;
+pub mod magic;
+pub mod mirror;
+pub mod progress;
+pub mod rewrite;
+pub mod robots;
+pub mod s3;
+pub mod sftp;
+pub mod sha256;
+pub mod sha512;
+pub mod spider;
+pub mod storage;
 pub mod utils;
+pub mod webdav;
@@ -0,0 +1,76 @@
+use std::path::Path;
+
+/// Number of leading bytes inspected when sniffing the content type.
+pub const SNIFF_LEN: usize = 512;
+
+static SIGNATURES: &[(&[u8], &str)] = &[
+    (b"PK\x03\x04", "zip"),
+    (b"%PDF", "pdf"),
+    (b"\x89PNG\r\n\x1a\n", "png"),
+    (b"GIF87a", "gif"),
+    (b"GIF89a", "gif"),
+    (b"\xff\xd8\xff", "jpg"),
+    (b"\x1f\x8b", "gz"),
+    (b"BZh", "bz2"),
+    (b"\xfd7zXZ\x00", "xz"),
+    (b"\x28\xb5\x2f\xfd", "zst"),
+    (b"7z\xbc\xaf\x27\x1c", "7z"),
+    (b"Rar!\x1a\x07", "rar"),
+    (b"\x7fELF", "elf"),
+    (b"OggS", "ogg"),
+];
+
+/// Extensions that name the same type as the detected one.
+static ALIASES: &[(&str, &str)] = &[("jpg", "jpeg"), ("gz", "tgz")];
+
+pub fn detect_extension(buf: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|(pattern, _)| buf.starts_with(pattern))
+        .map(|(_, ext)| *ext)
+}
+
+/// Returns `fname` with its extension replaced by `ext`, or `None` if the
+/// name already carries that extension.
+pub fn corrected_filename(fname: &str, ext: &str) -> Option<String> {
+    let path = Path::new(fname);
+    if let Some(current) = path.extension().and_then(|val| val.to_str()) {
+        let current = current.to_lowercase();
+        let is_alias = ALIASES
+            .iter()
+            .any(|(a, b)| (*a == ext && *b == current) || (*b == ext && *a == current));
+        if current == ext || is_alias {
+            return None;
+        }
+    }
+    Some(path.with_extension(ext).to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_extension() {
+        assert_eq!(detect_extension(b"PK\x03\x04rest"), Some("zip"));
+        assert_eq!(detect_extension(b"%PDF-1.7"), Some("pdf"));
+        assert_eq!(detect_extension(b"\x7fELF\x02\x01"), Some("elf"));
+        assert_eq!(detect_extension(b"<html>"), None);
+        assert_eq!(detect_extension(b""), None);
+    }
+
+    #[test]
+    fn test_corrected_filename() {
+        assert_eq!(
+            corrected_filename("index.html", "zip"),
+            Some("index.zip".to_owned())
+        );
+        assert_eq!(
+            corrected_filename("report", "pdf"),
+            Some("report.pdf".to_owned())
+        );
+        assert_eq!(corrected_filename("archive.ZIP", "zip"), None);
+        assert_eq!(corrected_filename("photo.jpeg", "jpg"), None);
+        assert_eq!(corrected_filename("src.tar.gz", "gz"), None);
+    }
+}
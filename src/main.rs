@@ -1,47 +1,193 @@
+use std::env;
+use std::path::Path;
 use std::process;
 
-use clap::{clap_app, crate_version};
-use duma::download::{ftp_download, http_download};
+use clap::{clap_app, crate_version, App, ArgMatches};
+use duma::config_file::load_user_config;
+use duma::core::{DownloadError, Verbosity};
+use duma::download::{
+    clear_bandwidth_history, ftp_download, gemini_download, http_download, s3_download,
+    sftp_download, show_bandwidth_report,
+};
 use duma::utils;
-use failure::{format_err, Fallible};
+use failure::Fallible;
 
 fn main() {
-    match run() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    });
+    let verbosity = Verbosity::from_flags(args.is_present("quiet"), args.is_present("verbose"));
+    match run(&args, verbosity) {
         Ok(_) => {}
         Err(e) => {
-            eprintln!("error: {}", e);
-            process::exit(1);
+            if verbosity == Verbosity::Verbose {
+                eprintln!("error: {:?}", e);
+            } else {
+                eprintln!("error: {}", e);
+            }
+            process::exit(exit_code(&e));
         }
     }
 }
 
-fn run() -> Fallible<()> {
-    let args = clap_app!(Duma =>
+/// Parses the command line, filling in flags it leaves out from the user
+/// config file.
+fn parse_args() -> Fallible<ArgMatches<'static>> {
+    let mut argv: Vec<String> = env::args().collect();
+    let args = cli()
+        .get_matches_from_safe(&argv)
+        .unwrap_or_else(|e| usage_error(e));
+    let user_config = load_user_config(args.value_of("USER_CONFIG").map(Path::new))?;
+    let defaults = user_config.default_args(|name| args.is_present(name));
+    if defaults.is_empty() {
+        return Ok(args);
+    }
+    argv.extend(defaults);
+    Ok(cli()
+        .get_matches_from_safe(argv)
+        .unwrap_or_else(|e| usage_error(e)))
+}
+
+/// Exits for a command line clap rejected, or after printing help/version.
+fn usage_error(err: clap::Error) -> ! {
+    if !err.use_stderr() {
+        err.exit();
+    }
+    eprintln!("{}", err.message);
+    process::exit(2);
+}
+
+fn cli() -> App<'static, 'static> {
+    clap_app!(Duma =>
     (version: crate_version!())
     (author: "Matt Gathu <mattgathu@gmail.com>")
     (about: "A minimal file downloader")
-    (@arg quiet: -q --quiet "quiet (no output)")
+    (@arg quiet: -q --quiet conflicts_with[verbose] "quiet (no progress or info output, errors are still printed to stderr)")
+    (@arg verbose: -v --verbose "print full error details and the response headers")
     (@arg continue: -c --continue "resume getting a partially-downloaded file")
+    (@arg aria2_compat: --("aria2-compat") requires[continue] conflicts_with[singlethread compressed RESUME_CHECKPOINT] "with --continue, take over a download aria2 left off from its FILE.aria2 control file")
+    (@arg IF_MODIFIED_SINCE: --("if-modified-since") +takes_value min_values(0) require_equals(true) "only download if the file changed since the local copy was written, or since --if-modified-since=DATE (an HTTP date, RFC 3339 time or YYYY-MM-DD)")
+    (@arg timestamping: -N --timestamping "don't fetch ftp files again when the local copy has the same size and is as new")
+    (@arg ENDPOINT_URL: --("endpoint-url") +takes_value "send s3:// requests to this S3 compatible server, e.g. MinIO, using path style bucket urls")
+    (@arg page_requisites: -p --("page-requisites") "also download the stylesheets, scripts and images an HTML page needs, next to it")
+    (@group recursion +multiple =>
+        (@arg recursive: -r --recursive "also download the files an HTML page links to on its host, and the files their pages link to, next to it")
+        (@arg MIRROR: --mirror +takes_value "save a local copy of the site into the directory MIRROR, as MIRROR/host/path: --recursive with --level 20 unless given, --no-parent, --page-requisites and --convert-links"))
+    (@arg LEVEL: -l --level +takes_value requires[recursion] "how many links away from the page --recursive goes (default 5)")
+    (@arg ACCEPT: -A --accept +takes_value requires[recursion] "only save the files --recursive finds with these comma separated extensions, e.g. png,jpg")
+    (@arg REJECT: -R --reject +takes_value requires[recursion] "don't save the files --recursive finds with these comma separated extensions")
+    (@arg no_robots: --("no-robots") requires[recursion] "let --recursive fetch the files the host's robots.txt disallows, e.g. to mirror a site you run")
+    (@arg no_parent: --("no-parent") "don't fetch page requisites or --recursive files from outside the page's directory")
+    (@arg convert_links: --("convert-links") "once downloaded, point the links of an HTML page at the local copies of the files downloaded with it")
+    (@arg no_progress: --("no-progress") "do not draw the progress bar, but still print the download details")
+    (@arg term_progress: --("term-progress") "also show the progress in the terminal's tab or taskbar (Windows Terminal, ConEmu)")
+    (@arg ascii_progress: --("ascii-progress") "draw the progress bar with ASCII characters only")
+    (@arg webdav: --webdav conflicts_with[compressed] "ask a WebDAV server for the size and date with PROPFIND before downloading")
+    (@arg compressed: --compressed conflicts_with[continue RANGES] "request a gzip compressed response and save it decompressed")
+    (@arg save_to_clipboard: -C --("save-to-clipboard") "also copy text and image downloads up to 10 MB to the clipboard (needs the clipboard feature)")
     (@arg singlethread: -s --singlethread "download using only a single thread")
+    (@arg ask_password: --("ask-password") "prompt for the HTTP basic or FTP password instead of reading it from the url")
+    (@arg WAIT_FOR_SERVER: --("wait-for-server") +takes_value "when the server refuses the connection, try again every second for up to WAIT_FOR_SERVER seconds")
+    (@arg retry_connrefused: --("retry-connrefused") "retry when the server refuses the connection instead of failing")
     (@arg headers: -H --headers "prints the headers sent by the HTTP server")
-    (@arg FILE: -O --output +takes_value "write documents to FILE")
+    (@arg head: --head conflicts_with[headers] "send a HEAD request and print the response status and headers (SIZE and MDTM for FTP)")
+    (@arg METHOD: -X --request +takes_value conflicts_with[head headers] possible_values(&["GET", "POST", "PUT", "PATCH", "DELETE"]) "send a METHOD request and save its response; anything but GET is sent once, without concurrent chunks")
+    (@arg UPLOAD_FILE: --("upload-file") +takes_value conflicts_with[head headers] "stream UPLOAD_FILE as the request body, with PUT unless --request PATCH is given")
+    (@arg detect_type: --("detect-type") "fix the file extension based on the downloaded content")
+    (@arg content_md5_verify: --("content-md5-verify") "verify the download against the Content-MD5 header sent by the HTTP server")
+    (@arg SHA256: --sha256 +takes_value "verify the finished download against the hex SHA256 digest, reading the file back once")
+    (@arg FILE: -O --output +takes_value conflicts_with[MIRROR] "write documents to FILE, or to stdout for -")
+    (@arg OUTPUT_PERMISSIONS: --("output-permissions") +takes_value "give the finished file the octal Unix mode OUTPUT_PERMISSIONS, e.g. 755")
+    (@arg preallocate: --preallocate "size the output file before a concurrent download starts")
+    (@arg no_sparse: --("no-sparse") "don't mark the output of a concurrent download as a sparse file on Windows")
+    (@arg keep_on_error: --("keep-on-error") conflicts_with[delete_on_error] "keep the output of a failed download, even one that failed its checksum")
+    (@arg delete_on_error: --("delete-on-error") "remove the output and resume state of a failed download")
+    (@arg TMP_SUFFIX: --("tmp-suffix") +takes_value "name the resume state files FILE.SUFFIX.st instead of after a hash of the url")
+    (@arg NORMALIZE_FILENAME: --("normalize-filename") +takes_value min_values(0) require_equals(true) possible_values(&["lower"]) "save under a name with spaces turned into underscores and only letters, digits, '.', '_' and '-' kept; --normalize-filename=lower also lowercases it")
+    (@arg OUTPUT_NAMING: --("output-naming") +takes_value possible_values(&["url-last", "content-disposition", "hash", "timestamp"]) "name downloads without -O after the url path (default), only the Content-Disposition header, the url's SHA-256 or a timestamped url-last name")
+    (@arg to_downloads: --("to-downloads") "save into the downloads folder ($XDG_DOWNLOAD_DIR) instead of the current directory")
+    (@arg CREDENTIALS_FILE: --("digest-auth-file") +takes_value "send the credentials of the first matching pattern in the TOML file CREDENTIALS_FILE")
     (@arg AGENT: -U --useragent +takes_value "identify as AGENT instead of Duma/VERSION")
+    (@arg USER_AGENT_FILE: --("user-agent-file") +takes_value "identify as a user agent picked at random from the lines of USER_AGENT_FILE, overriding -U")
     (@arg SECONDS: -T --timeout +takes_value "set all timeout values to SECONDS")
+    (@arg MAX_TIME: --("max-time") +takes_value "abort the download once it has run for MAX_TIME seconds, keeping the partial file")
+    (@arg USER_CONFIG: --("user-config") +takes_value "read default flags from the TOML file USER_CONFIG instead of ~/.config/duma/config.toml")
+    (@arg PROGRESS_FD: --("progress-to-fd") +takes_value "write \"bytes_done:N total:N\" progress lines to file descriptor PROGRESS_FD (Unix only)")
+    (@arg PROGRESS_BAR_FD: --("progress-fd") +takes_value "draw the progress bar on file descriptor PROGRESS_BAR_FD instead of stderr, e.g. when -O - sends the file to stdout")
+    (@arg OUTPUT_HASH: --("output-hash") +takes_value min_values(0) require_equals(true) possible_values(&["sha256", "sha512", "md5"]) "write the file's digest to FILE.sha256, or FILE.sha512 or FILE.md5 with --output-hash=sha512 or --output-hash=md5")
+    (@arg SAVE_HEADERS: --("save-headers") +takes_value min_values(0) require_equals(true) possible_values(&["sidecar", "prepend"]) "save the response headers to FILE.headers, or before the content with --save-headers=prepend")
+    (@arg STATS_FILE: --("stats-file") +takes_value "write transfer statistics as JSON to STATS_FILE")
+    (@arg RESUME_CHECKPOINT: --("resume-from-checkpoint") +takes_value conflicts_with[RANGES compressed singlethread SAVE_HEADERS] "resume the download described by the JSON checkpoint RESUME_CHECKPOINT")
+    (@arg SAVE_CHECKPOINT: --("save-checkpoint") +takes_value conflicts_with[RANGES compressed singlethread SAVE_HEADERS] "keep a JSON checkpoint of the completed chunks in SAVE_CHECKPOINT")
+    (@arg RESUME_MIN_PARALLEL: --("resume-min-parallel") +takes_value "resume in a single thread when fewer than RESUME_MIN_PARALLEL bytes remain")
+    (@arg IFNAME: --interface +takes_value "send from the address network interface IFNAME has at start; the routing table still picks the way out, so this doesn't force traffic through IFNAME (Linux and macOS only)")
+    (@arg RANGES: --ranges +takes_value conflicts_with[continue content_md5_verify SAVE_HEADERS SHA256] "download only the byte RANGES (e.g. 0-99,500-599) into the output file")
+    (@arg RANGE: --range +takes_value conflicts_with[continue RANGES compressed RESUME_CHECKPOINT SAVE_CHECKPOINT] "download only the bytes START[-END] in a single thread; the output file holds just that slice")
+    (@arg CHUNK_SIZE: --("chunk-size") +takes_value "size of the byte ranges fetched concurrently, with an optional k, m, g, ki, mi or gi suffix (default is 512k)")
+    (@arg force_concurrent: --("force-concurrent") conflicts_with[singlethread continue] "try concurrent ranged requests even if the server doesn't advertise Accept-Ranges")
+    (@arg auto_connections: --("auto-connections") conflicts_with[NUM_CONNECTIONS singlethread] "measure the speed with 1, 2, 4 and 8 connections and download with the fastest")
     (@arg NUM_CONNECTIONS: -n --num_connections +takes_value "maximum number of concurrent connections (default is 8)")
-    (@arg URL: +required +takes_value "url to download")
+    (@arg browser_headers: --("browser-headers") "send the Origin, Accept-Language and Sec-Fetch-* headers of a browser following a link")
+    (@arg tcp_no_delay: --("tcp-no-delay") "send small packets at once instead of batching them (lower latency, more packets)")
+    (@arg TCP_KEEPALIVE: --("tcp-keepalive") +takes_value "send TCP keepalive probes once a connection has been idle for TCP_KEEPALIVE seconds, so NATs and firewalls don't drop it silently (a few more packets)")
+    (@arg HEADER_SIZE_LIMIT: --("http-header-size-limit") +takes_value "fail on responses with more than BYTES of headers, e.g. 64ki (default is 256ki; checked once the headers are read, and headers over about 400ki fail anyway)")
+    (@arg FTP_PORT: --("ftp-port") +takes_value "connect to ftp servers on port N when the url has none (default is 21)")
+    (@arg VERIFY_HOST: --("verify-host") +takes_value multiple(true) number_of_values(1) "connect to IP for HOST:PORT, given as HOST:PORT:IP; certificates are still checked")
+    (@arg DNS_SERVERS: --("dns-servers") +takes_value multiple(true) number_of_values(1) visible_alias("dns-server") "look the host up on these comma separated DNS servers, e.g. 1.1.1.1,8.8.8.8; may be repeated, and servers on port 853 are asked over TLS")
+    (@arg PROXY: --proxy +takes_value "send all requests through the proxy at PROXY, e.g. http://proxy.example.com:3128")
+    (@arg PROXY_AUTH: --("proxy-auth") +takes_value requires[PROXY] "authenticate to the proxy as USER:PASS (also read from $PROXY_AUTH)")
+    (@arg MAX_CONNECTIONS_TOTAL: --("max-connections-total") +takes_value visible_aliases(&["max-connections-global", "max-open-connections"]) "open at most MAX_CONNECTIONS_TOTAL chunk connections at a time across all hosts (default is 32)")
+    (@arg MAX_CONNS_PER_HOST: --("max-conns-per-host") +takes_value "open at most MAX_CONNS_PER_HOST connections to one host at a time (default is unlimited)")
+    (@arg CHUNK_VERIFY_INTERVAL: --("chunk-verify-interval") +takes_value "read every Nth written chunk back from the output and fetch it again if it doesn't match")
+    (@arg EXPECTED_SIZE: --("expected-size") +takes_value "take the file to be EXPECTED_SIZE bytes (e.g. 10M) whatever the server's Content-Length says; a body that ends sooner still ends the download")
+    (@arg TRIES: -t --tries +takes_value conflicts_with[retry_forever] "give up after TRIES attempts in total, 0 to keep retrying until a fatal error (default is 100 retries)")
+    (@arg retry_forever: --("retry-forever") "keep retrying until the download succeeds or fails with an error that can't be retried, same as --tries 0")
+    (@arg MAX_RETRIES_PER_CHUNK: --("max-retries-per-chunk") +takes_value "retry a failing chunk at most N times, then skip it and finish the rest (default is 100)")
+    (@arg TOTAL_RETRY_BUDGET: --("total-retry-budget") +takes_value "allow at most N retries in total, across all chunks and reconnects")
+    (@arg bandwidth_report: --("bandwidth-report") conflicts_with[clear_bandwidth_history] "print the average download speed recorded for each host and exit (needs the bandwidth-db feature, which records them)")
+    (@arg clear_bandwidth_history: --("clear-bandwidth-history") "forget the recorded download speeds and exit")
+    (@arg URL: required_unless_one(&["bandwidth_report", "clear_bandwidth_history"]) +takes_value "url to download")
     )
-    .get_matches_safe().unwrap_or_else(|e| e.exit());
+}
 
-    let url = utils::parse_url(
+/// Maps a failure to the process exit code documented in the README.
+fn exit_code(err: &DownloadError) -> i32 {
+    match err {
+        DownloadError::Network(_)
+        | DownloadError::MaxRetries
+        | DownloadError::PartialDownload { .. }
+        | DownloadError::HeadersTooLarge { .. } => 4,
+        DownloadError::HttpStatus(_) => 8,
+        DownloadError::ChecksumMismatch { .. } => 16,
+        _ => 1,
+    }
+}
+
+fn run(args: &ArgMatches, verbosity: Verbosity) -> Result<(), DownloadError> {
+    if args.is_present("bandwidth_report") {
+        return show_bandwidth_report();
+    }
+    if args.is_present("clear_bandwidth_history") {
+        return clear_bandwidth_history();
+    }
+    let mut url = utils::parse_url(
         args.value_of("URL")
-            .ok_or_else(|| format_err!("missing URL argument"))?,
+            .ok_or_else(|| DownloadError::InvalidUrl("missing URL argument".to_owned()))?,
     )?;
-    let quiet_mode = args.is_present("quiet");
-    let file_name = args.value_of("FILE");
+    if args.is_present("ask_password") {
+        utils::ask_password(&mut url)?;
+    }
 
     match url.scheme() {
-        "ftp" => ftp_download(url, quiet_mode, file_name),
-        "http" | "https" => http_download(url, &args, crate_version!()),
-        _ => utils::gen_error(format!("unsupported url scheme '{}'", url.scheme())),
+        "ftp" => ftp_download(url, args, verbosity),
+        "sftp" => sftp_download(url, args, verbosity),
+        "s3" => s3_download(url, args, verbosity, crate_version!()),
+        "gemini" => gemini_download(url, args, verbosity),
+        "http" | "https" => http_download(url, args, verbosity, crate_version!()),
+        _ => Err(DownloadError::InvalidUrl(format!(
+            "unsupported url scheme '{}'",
+            url.scheme()
+        ))),
     }
 }
@@ -0,0 +1,304 @@
+use std::cell::RefCell;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::{bail, Fallible};
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use threadpool::ThreadPool;
+use url::Url;
+
+use crate::bandwidth::BandwidthEstimator;
+use crate::core::{
+    build_client, get_chunk_offsets, send_retrying, CancellationToken, Config, DownloadError,
+    DownloadStats, EventsHandler,
+};
+use crate::progress::Progress;
+use crate::utils::parse_content_range;
+
+/// Downloads every chunk from two mirrors at once and keeps whichever copy
+/// arrives first, cancelling the other.
+///
+/// This trades bandwidth for latency: the bytes the losing mirror sent
+/// before it was cancelled are counted in `wasted_bytes`.
+pub struct MirroredDownload {
+    urls: Vec<Url>,
+    conf: Config,
+    hooks: Vec<RefCell<Box<dyn EventsHandler>>>,
+    wasted_bytes: Arc<AtomicU64>,
+}
+
+/// A chunk as delivered by the winning mirror: `(offset, bytes)`.
+type Chunk = (u64, Vec<u8>);
+
+impl MirroredDownload {
+    pub fn new(urls: Vec<Url>, conf: Config) -> MirroredDownload {
+        MirroredDownload {
+            urls,
+            conf,
+            hooks: Vec::new(),
+            wasted_bytes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn events_hook<E: EventsHandler + 'static>(&mut self, hk: E) -> &mut MirroredDownload {
+        self.hooks.push(RefCell::new(Box::new(hk)));
+        self
+    }
+
+    /// Moves the mirror with the best recorded speed to the front, so it
+    /// takes part in the first race and answers the probe.
+    pub fn prefer_fastest(&mut self, estimator: &BandwidthEstimator) -> &mut MirroredDownload {
+        let hosts: Vec<&str> = self.urls.iter().filter_map(|url| url.host_str()).collect();
+        let fastest = estimator
+            .get_fastest_mirror(&hosts)
+            .map(|host| host.to_owned());
+        if let Some(host) = fastest {
+            if let Some(idx) = self
+                .urls
+                .iter()
+                .position(|url| url.host_str() == Some(&host))
+            {
+                let url = self.urls.remove(idx);
+                self.urls.insert(0, url);
+            }
+        }
+        self
+    }
+
+    /// Bytes received from mirrors that lost a race.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.wasted_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Returns the headers and content length of the first mirror that
+    /// reports a length, as the chunks can't be planned without one.
+    fn probe(&self, client: &Client) -> Fallible<(HeaderMap, u64)> {
+        let user_agent = HeaderValue::from_str(&self.conf.user_agent)?;
+        let mut status = None;
+        for url in &self.urls {
+            let resp = send_retrying(&self.conf, || {
+                client
+                    .get(url.as_ref())
+                    .timeout(Duration::from_secs(self.conf.timeout))
+                    .headers(self.conf.headers.clone())
+                    .header(header::USER_AGENT, user_agent.clone())
+            })?;
+            if !resp.status().is_success() {
+                status = Some(resp.status());
+                continue;
+            }
+            let ct_len = resp
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|val| val.to_str().ok())
+                .and_then(|val| val.parse::<u64>().ok());
+            if let Some(ct_len) = ct_len {
+                return Ok((resp.headers().clone(), ct_len));
+            }
+        }
+        if let Some(status) = status {
+            for hook in &self.hooks {
+                hook.borrow().on_failure_status(i32::from(status.as_u16()));
+            }
+            return Err(DownloadError::HttpStatus(status.as_u16()).into());
+        }
+        bail!("mirrored downloads need a server that reports the content length")
+    }
+
+    pub fn download(&mut self) -> Result<(), DownloadError> {
+        self.try_download().map_err(DownloadError::from)
+    }
+
+    fn try_download(&mut self) -> Fallible<()> {
+        if self.urls.is_empty() {
+            bail!("no mirrors to download from");
+        }
+        let started = Instant::now();
+        let client = build_client(&self.conf)?;
+        let (headers, ct_len) = self.probe(&client)?;
+        for hook in &self.hooks {
+            hook.borrow().on_success_status();
+            hook.borrow_mut().on_headers(headers.clone());
+        }
+
+        let client = RangeClient {
+            client,
+            headers: self.conf.headers.clone(),
+            user_agent: HeaderValue::from_str(&self.conf.user_agent)?,
+            timeout: Duration::from_secs(self.conf.timeout),
+        };
+        let offsets = get_chunk_offsets(ct_len, self.conf.chunk_size);
+        let chunks = offsets.len();
+        let (data_tx, data_rx) = mpsc::channel::<Fallible<Chunk>>();
+        let worker_pool = ThreadPool::new(self.conf.num_workers);
+        for (idx, offsets) in offsets.into_iter().enumerate() {
+            // spread the pairs so every mirror serves its share of chunks
+            let first = self.urls[idx % self.urls.len()].clone();
+            let second = self.urls[(idx + 1) % self.urls.len()].clone();
+            let client = client.clone();
+            let data_tx = data_tx.clone();
+            let wasted = self.wasted_bytes.clone();
+            let max_retries = self.conf.max_retries;
+            worker_pool.execute(move || {
+                let result = race_chunk(&client, &first, &second, offsets, &wasted, max_retries);
+                let _ = data_tx.send(result.map(|buf| (offsets.0, buf)));
+            });
+        }
+        drop(data_tx);
+
+        let mut bytes = 0;
+        let mut progress = Progress::new(Some(ct_len));
+        for result in data_rx.iter().take(chunks) {
+            let (offset, buf) = result?;
+            bytes += buf.len() as u64;
+            progress.bytes_downloaded = bytes;
+            for hk in &self.hooks {
+                hk.borrow_mut()
+                    .on_concurrent_content((buf.len() as u64, offset, &buf), &progress)?;
+            }
+        }
+        if bytes != ct_len {
+            bail!("got {} of {} bytes from the mirrors", bytes, ct_len);
+        }
+
+        let stats = DownloadStats {
+            url: self.urls[0].to_string(),
+            bytes,
+            duration: started.elapsed(),
+            retries: 0,
+            chunks,
+            resumed: false,
+            wasted_bytes: self.wasted_bytes(),
+        };
+        for hook in &self.hooks {
+            hook.borrow_mut().on_finish()?;
+            hook.borrow_mut().on_finish_with_stats(&stats)?;
+        }
+        Ok(())
+    }
+}
+
+/// The client the chunks are fetched with, sending the same headers, user
+/// agent and timeout as the probe.
+#[derive(Clone)]
+struct RangeClient {
+    client: Client,
+    headers: HeaderMap,
+    user_agent: HeaderValue,
+    timeout: Duration,
+}
+
+impl RangeClient {
+    fn get(&self, url: &Url) -> RequestBuilder {
+        self.client
+            .get(url.as_ref())
+            .timeout(self.timeout)
+            .headers(self.headers.clone())
+            .header(header::USER_AGENT, self.user_agent.clone())
+    }
+}
+
+/// Fetches `offsets` from both mirrors and returns the first complete copy,
+/// trying again while both fail.
+fn race_chunk(
+    client: &RangeClient,
+    first: &Url,
+    second: &Url,
+    offsets: (u64, u64),
+    wasted: &Arc<AtomicU64>,
+    max_retries: i32,
+) -> Fallible<Vec<u8>> {
+    let mut retries = 0;
+    loop {
+        let cancel = CancellationToken::new();
+        let won = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::channel();
+        let mut racers = vec![first.clone()];
+        if second != first {
+            racers.push(second.clone());
+        }
+        let count = racers.len();
+        for url in racers {
+            let (client, cancel, won, wasted, tx) = (
+                client.clone(),
+                cancel.clone(),
+                won.clone(),
+                wasted.clone(),
+                tx.clone(),
+            );
+            thread::spawn(move || {
+                let result = fetch_range(&client, &url, offsets, &cancel, &wasted);
+                match result {
+                    Ok(buf) if !won.swap(true, Ordering::SeqCst) => {
+                        cancel.cancel();
+                        let _ = tx.send(Ok(buf));
+                    }
+                    // finished, but after the other mirror
+                    Ok(buf) => {
+                        wasted.fetch_add(buf.len() as u64, Ordering::SeqCst);
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let mut last_err = None;
+        for result in rx.iter().take(count) {
+            match result {
+                Ok(buf) => return Ok(buf),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        retries += 1;
+        if retries > max_retries {
+            return Err(last_err.unwrap_or_else(|| DownloadError::MaxRetries.into()));
+        }
+    }
+}
+
+/// Downloads the inclusive byte range `offsets` of `url`, giving up once
+/// `cancel` is set. Bytes read before a cancellation are added to `wasted`.
+fn fetch_range(
+    client: &RangeClient,
+    url: &Url,
+    offsets: (u64, u64),
+    cancel: &CancellationToken,
+    wasted: &AtomicU64,
+) -> Fallible<Vec<u8>> {
+    let byte_range = format!("bytes={}-{}", offsets.0, offsets.1);
+    let mut resp = client
+        .get(url)
+        .header(header::RANGE, HeaderValue::from_str(&byte_range)?)
+        .send()?;
+    match resp
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|val| val.to_str().ok())
+        .and_then(parse_content_range)
+    {
+        Some((start, _, _)) if start == offsets.0 => {}
+        _ => bail!("{} did not honour range {}", url, byte_range),
+    }
+    let len = (offsets.1 - offsets.0 + 1) as usize;
+    let mut data = Vec::with_capacity(len);
+    let mut buf = vec![0; 16 * 1024];
+    while data.len() < len {
+        if cancel.is_cancelled() {
+            wasted.fetch_add(data.len() as u64, Ordering::SeqCst);
+            return Err(DownloadError::Cancelled.into());
+        }
+        let byte_count = resp.read(&mut buf)?;
+        if byte_count == 0 {
+            bail!("{} closed the connection in range {}", url, byte_range);
+        }
+        data.extend_from_slice(&buf[..byte_count.min(len - data.len())]);
+    }
+    Ok(data)
+}
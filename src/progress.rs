@@ -0,0 +1,81 @@
+use std::time::{Duration, Instant};
+
+/// How far a download has got, handed to `EventsHandler::on_content` and
+/// `on_concurrent_content` along with each piece of the body.
+///
+/// The counts cover this run only: a resumed download starts from zero
+/// and expects just the bytes it still lacks.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub started_at: Instant,
+    /// Bytes received so far, the piece being handed over included.
+    pub bytes_downloaded: u64,
+    /// Bytes expected in all, if the server said.
+    pub total_bytes: Option<u64>,
+}
+
+impl Progress {
+    pub fn new(total_bytes: Option<u64>) -> Progress {
+        Progress {
+            started_at: Instant::now(),
+            bytes_downloaded: 0,
+            total_bytes,
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// The average speed so far, in bytes per second.
+    pub fn speed_bps(&self) -> f64 {
+        let secs = self.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            self.bytes_downloaded as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// How long the rest will take at the average speed so far. `None`
+    /// until something arrived, or when the total is unknown.
+    pub fn eta(&self) -> Option<Duration> {
+        let left = self.total_bytes?.saturating_sub(self.bytes_downloaded);
+        let speed = self.speed_bps();
+        if speed > 0.0 {
+            Some(Duration::from_secs_f64(left as f64 / speed))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(secs: u64, bytes_downloaded: u64, total_bytes: Option<u64>) -> Progress {
+        Progress {
+            started_at: Instant::now()
+                .checked_sub(Duration::from_secs(secs))
+                .unwrap(),
+            bytes_downloaded,
+            total_bytes,
+        }
+    }
+
+    #[test]
+    fn test_speed_and_eta() {
+        let halfway = progress(10, 1000, Some(2000));
+        assert!(halfway.elapsed() >= Duration::from_secs(10));
+        assert!((halfway.speed_bps() - 100.0).abs() < 1.0);
+        // as long again as it took so far, give or take the clock moving on
+        let eta = halfway.eta().unwrap();
+        assert!(eta > Duration::from_millis(9_900) && eta < Duration::from_secs(11));
+
+        assert_eq!(progress(10, 2000, Some(2000)).eta(), Some(Duration::ZERO));
+        assert_eq!(progress(10, 1000, None).eta(), None);
+        assert_eq!(progress(10, 0, Some(2000)).eta(), None);
+        assert_eq!(Progress::new(Some(2000)).speed_bps(), 0.0);
+    }
+}
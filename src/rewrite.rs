@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
+use url::Url;
+
+use crate::utils::{decode_percent_encoded_data, plain_file_name};
+
+/// Attributes whose value is a url to rewrite.
+const URL_ATTRIBUTES: &[&str] = &["href", "src", "action"];
+
+/// Points the links of a saved HTML page at the local copies of the files
+/// downloaded with it, for `--convert-links`.
+///
+/// Only the `href`, `src` and `action` attributes and `url()` in `style`
+/// attributes and `<style>` blocks are looked at. The scan is lenient
+/// rather than a full HTML parser: markup it doesn't understand is copied
+/// through untouched.
+#[derive(Debug, Clone)]
+pub struct LinkConverter {
+    page: Url,
+}
+
+impl LinkConverter {
+    /// `page` is the url the HTML was downloaded from, which relative links
+    /// are resolved against.
+    pub fn new(page: Url) -> LinkConverter {
+        LinkConverter { page }
+    }
+
+    /// Rewrites the links in `html` that lead to one of the `saved` urls,
+    /// given as absolute urls with the file each was saved as, into a path
+    /// relative to `page_dir`, the directory the page itself was saved in.
+    /// Links to anything else, or to files missing on disk, are left as
+    /// they are.
+    pub fn convert(&self, html: &str, page_dir: &Path, saved: &HashMap<String, PathBuf>) -> String {
+        let rewrite = |link: &str| self.local_link(link, page_dir, saved);
+        let mut out = String::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = rest.find('<') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if rest.starts_with("<!--") {
+                let end = rest.find("-->").map_or(rest.len(), |end| end + 3);
+                out.push_str(&rest[..end]);
+                rest = &rest[end..];
+                continue;
+            }
+            let end = match tag_end(rest) {
+                Some(end) => end,
+                None => break,
+            };
+            let tag = &rest[..end];
+            out.push_str(&convert_tag(tag, &rewrite));
+            rest = &rest[end..];
+            if tag_name(tag).eq_ignore_ascii_case("style") {
+                let end = find_ignore_case(rest, "</style").unwrap_or(rest.len());
+                out.push_str(&convert_css(&rest[..end], &rewrite));
+                rest = &rest[end..];
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// The local replacement for `link`, if it leads to a downloaded file.
+    fn local_link(
+        &self,
+        link: &str,
+        page_dir: &Path,
+        saved: &HashMap<String, PathBuf>,
+    ) -> Option<String> {
+        let mut url = self.page.join(&link.trim().replace("&amp;", "&")).ok()?;
+        let fragment = url.fragment().map(|frag| frag.to_owned());
+        url.set_fragment(None);
+        let path = saved.get(url.as_str())?;
+        if !path.is_file() {
+            return None;
+        }
+        let mut local = relative_link(page_dir, path)?;
+        if let Some(fragment) = fragment {
+            local.push('#');
+            local.push_str(&fragment);
+        }
+        Some(local)
+    }
+}
+
+/// The name a download of `url` is saved under without `-O`: the last
+/// path segment, or `index.html` for a directory. Escaped slashes and `..`
+/// are dropped, so the name never leads out of the directory.
+pub fn local_name(url: &Url) -> String {
+    let name = url.path().split('/').next_back().unwrap_or("");
+    let decoded = decode_percent_encoded_data(name);
+    plain_file_name(decoded.as_deref().unwrap_or(name))
+        .unwrap_or("index.html")
+        .to_owned()
+}
+
+/// Where `--mirror` saves `url`, relative to the mirror directory:
+/// `host/dir/name`, with `host+port` for a port that isn't the scheme's
+/// own and `index.html` for a directory. Every part goes through
+/// `plain_file_name`, so the path can't lead out of the mirror directory.
+pub fn mirror_path(url: &Url) -> PathBuf {
+    let host = url
+        .host_str()
+        .and_then(plain_file_name)
+        .unwrap_or("localhost");
+    let mut path = PathBuf::from(match url.port() {
+        Some(port) => format!("{}+{}", host, port),
+        None => host.to_owned(),
+    });
+    let mut dirs: Vec<&str> = url
+        .path_segments()
+        .map_or_else(Vec::new, |segs| segs.collect());
+    // the last segment is the file name
+    dirs.pop();
+    for dir in dirs {
+        let decoded = decode_percent_encoded_data(dir);
+        if let Some(dir) = plain_file_name(decoded.as_deref().unwrap_or(dir)) {
+            path.push(dir);
+        }
+    }
+    path.push(local_name(url));
+    path
+}
+
+/// `target` as a relative url from the directory `from`, when both are
+/// relative to the same directory or both absolute.
+fn relative_link(from: &Path, target: &Path) -> Option<String> {
+    fn parts(path: &Path) -> Vec<Component<'_>> {
+        path.components()
+            .filter(|part| *part != Component::CurDir)
+            .collect()
+    }
+    let (from, target) = (parts(from), parts(target));
+    let common = from.iter().zip(&target).take_while(|(a, b)| a == b).count();
+    let mut link = Vec::new();
+    for part in &from[common..] {
+        match part {
+            Component::Normal(_) => link.push("..".to_owned()),
+            _ => return None,
+        }
+    }
+    for part in &target[common..] {
+        match part {
+            Component::Normal(name) => link
+                .push(utf8_percent_encode(&name.to_string_lossy(), DEFAULT_ENCODE_SET).to_string()),
+            _ => return None,
+        }
+    }
+    Some(link.join("/"))
+}
+
+/// The length of the tag at the start of `html`, up to its closing `>`
+/// outside of any quotes.
+pub(crate) fn tag_end(html: &str) -> Option<usize> {
+    let mut quote = None;
+    for (idx, c) in html.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '>') => return Some(idx + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+pub(crate) fn tag_name(tag: &str) -> &str {
+    let name = &tag[1..];
+    let end = name
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(name.len());
+    &name[..end]
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .to_ascii_lowercase()
+        .find(&needle.to_ascii_lowercase())
+}
+
+/// `tag` with the values of its url attributes rewritten, and the urls in
+/// its `style` attribute.
+fn convert_tag<F>(tag: &str, rewrite: &F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(tag.len());
+    let name_len = 1 + tag_name(tag).len();
+    out.push_str(&tag[..name_len]);
+    let mut rest = &tag[name_len..];
+    loop {
+        let trimmed = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        out.push_str(&rest[..rest.len() - trimmed.len()]);
+        rest = trimmed;
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        if name_end == 0 {
+            break;
+        }
+        let name = &rest[..name_end];
+        out.push_str(name);
+        rest = &rest[name_end..];
+        let after_ws = rest.trim_start();
+        if !after_ws.starts_with('=') {
+            continue;
+        }
+        let value_start = after_ws[1..].trim_start();
+        out.push_str(&rest[..rest.len() - value_start.len()]);
+        rest = value_start;
+        let (quote, value, len) = match rest.chars().next() {
+            Some(q) if q == '"' || q == '\'' => {
+                let end = rest[1..].find(q).map_or(rest.len(), |end| end + 1);
+                (Some(q), &rest[1..end], (end + 1).min(rest.len()))
+            }
+            _ => {
+                let end = rest
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .unwrap_or(rest.len());
+                (None, &rest[..end], end)
+            }
+        };
+        let converted = if URL_ATTRIBUTES
+            .iter()
+            .any(|attr| name.eq_ignore_ascii_case(attr))
+        {
+            rewrite(value)
+        } else if name.eq_ignore_ascii_case("style") {
+            Some(convert_css(value, rewrite))
+        } else {
+            None
+        };
+        match (converted, quote) {
+            (Some(value), Some(q)) => {
+                out.push(q);
+                out.push_str(&value);
+                out.push(q);
+            }
+            (Some(value), None) => out.push_str(&value),
+            (None, _) => out.push_str(&rest[..len]),
+        }
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `css` with the urls in its `url()` references rewritten.
+fn convert_css<F>(css: &str, rewrite: &F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = find_ignore_case(rest, "url(") {
+        out.push_str(&rest[..start + 4]);
+        rest = &rest[start + 4..];
+        let end = match rest.find(')') {
+            Some(end) => end,
+            None => break,
+        };
+        let inner = &rest[..end];
+        let link = inner.trim().trim_matches(|c| c == '"' || c == '\'');
+        match rewrite(link) {
+            Some(local) => out.push_str(&inner.replacen(link, &local, 1)),
+            None => out.push_str(inner),
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn convert(html: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("duma-rewrite-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["logo.png", "style.css", "my page.html", "index.html"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let saved: HashMap<String, PathBuf> = [
+            "http://example.com/img/logo.png",
+            "http://example.com/style.css",
+            "http://example.com/my%20page.html",
+            "http://example.com/",
+            "http://example.com/missing.png",
+        ]
+        .iter()
+        .map(|url| {
+            (
+                url.to_string(),
+                dir.join(local_name(&Url::parse(url).unwrap())),
+            )
+        })
+        .collect();
+        let page = Url::parse("http://example.com/docs/page.html").unwrap();
+        LinkConverter::new(page).convert(html, &dir, &saved)
+    }
+
+    #[test]
+    fn test_mirror_path() {
+        let path = |url: &str| mirror_path(&Url::parse(url).unwrap());
+        assert_eq!(
+            path("http://example.com/a/b%20c/page.html?q=1"),
+            Path::new("example.com/a/b c/page.html")
+        );
+        assert_eq!(
+            path("https://example.com/a/"),
+            Path::new("example.com/a/index.html")
+        );
+        assert_eq!(
+            path("http://example.com:8080/"),
+            Path::new("example.com+8080/index.html")
+        );
+        assert_eq!(
+            path("http://example.com/..%2F..%2Fa/%2Ftmp/x.txt"),
+            Path::new("example.com/a/tmp/x.txt")
+        );
+    }
+
+    #[test]
+    fn test_relative_link() {
+        let link = |from: &str, to: &str| relative_link(Path::new(from), Path::new(to));
+        assert_eq!(link(".", "./logo.png").unwrap(), "logo.png");
+        assert_eq!(link("site/h/a", "site/h/a/b/c.png").unwrap(), "b/c.png");
+        assert_eq!(
+            link("site/h/a/b", "site/h/c/my page.html").unwrap(),
+            "../../c/my%20page.html"
+        );
+        assert_eq!(link("/m/h", "/m/h/x.css").unwrap(), "x.css");
+        assert_eq!(link("../up", "x.css"), None);
+    }
+
+    #[test]
+    fn test_local_name() {
+        let name = |url: &str| local_name(&Url::parse(url).unwrap());
+        assert_eq!(name("http://example.com/a/my%20page.html"), "my page.html");
+        assert_eq!(name("http://example.com/a/"), "index.html");
+        // a broken escape is kept as it is
+        assert_eq!(name("http://example.com/a/foo%"), "foo%");
+        assert_eq!(name("http://example.com/a/foo%zz.txt"), "foo%zz.txt");
+        assert_eq!(
+            name("http://example.com/a/..%2F..%2Fpwned.txt"),
+            "pwned.txt"
+        );
+        assert_eq!(name("http://example.com/a/%2Ftmp%2Fabs.txt"), "abs.txt");
+        assert_eq!(name("http://example.com/a/..%2F"), "index.html");
+    }
+
+    #[test]
+    fn test_attributes_are_rewritten() {
+        assert_eq!(
+            convert(r#"<img src="http://example.com/img/logo.png" alt="logo">"#),
+            r#"<img src="logo.png" alt="logo">"#
+        );
+        assert_eq!(
+            convert("<a HREF='/my%20page.html#top'>a</a>"),
+            "<a HREF='my%20page.html#top'>a</a>"
+        );
+        assert_eq!(
+            convert(r#"<link rel=stylesheet href=../style.css>"#),
+            r#"<link rel=stylesheet href=style.css>"#
+        );
+        assert_eq!(
+            convert(r#"<form action="http://example.com/"></form>"#),
+            r#"<form action="index.html"></form>"#
+        );
+    }
+
+    #[test]
+    fn test_other_links_are_kept() {
+        for html in &[
+            r#"<a href="http://example.org/img/logo.png">elsewhere</a>"#,
+            r#"<img src="http://example.com/missing.png">"#,
+            r#"<a title="http://example.com/style.css">not a link</a>"#,
+            r#"<!-- <img src="http://example.com/img/logo.png"> -->"#,
+            "<p>1 < 2 and <br/> text</p>",
+            "<a href=\"unterminated",
+        ] {
+            assert_eq!(convert(html), *html);
+        }
+    }
+
+    #[test]
+    fn test_css_urls_are_rewritten() {
+        assert_eq!(
+            convert(r#"<div style="background: url('/img/logo.png') no-repeat">"#),
+            r#"<div style="background: url('logo.png') no-repeat">"#
+        );
+        assert_eq!(
+            convert(
+                "<STYLE>body { background: URL(http://example.com/img/logo.png) }\
+                 a { background: url(/img/other.png) }</STYLE>"
+            ),
+            "<STYLE>body { background: URL(logo.png) }\
+             a { background: url(/img/other.png) }</STYLE>"
+        );
+    }
+}
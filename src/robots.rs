@@ -0,0 +1,156 @@
+/// The rules of a `robots.txt` file that apply to one crawler, for
+/// `--recursive`.
+///
+/// The rules come from the groups naming the crawler's product token, or
+/// from the `*` groups when none do. The longest rule matching a path
+/// decides, and `Allow` wins a tie. Rules may use `*` for any run of
+/// characters and end in `$` to match the end of the path.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Robots {
+    /// `(allowed, pattern)` pairs.
+    rules: Vec<(bool, String)>,
+}
+
+impl Robots {
+    /// The rules in `text` for the crawler called `agent`, e.g. `duma`.
+    pub fn parse(text: &str, agent: &str) -> Robots {
+        let mut own = None;
+        let mut any = None;
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+                None => continue,
+            };
+            match key.as_str() {
+                "user-agent" => {
+                    // a user-agent line after rules starts a new group
+                    if in_rules {
+                        agents.clear();
+                        in_rules = false;
+                    }
+                    agents.push(value.to_owned());
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    let rules = if agents.iter().any(|name| name.eq_ignore_ascii_case(agent)) {
+                        own.get_or_insert_with(Vec::new)
+                    } else if agents.iter().any(|name| name == "*") {
+                        any.get_or_insert_with(Vec::new)
+                    } else {
+                        continue;
+                    };
+                    // an empty disallow allows everything
+                    if !value.is_empty() {
+                        rules.push((key == "allow", value.to_owned()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Robots {
+            rules: own.or(any).unwrap_or_default(),
+        }
+    }
+
+    /// Whether the crawler may fetch `path`, the path and query of a url.
+    pub fn allows(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|(_, pattern)| matches(pattern, path))
+            .max_by_key(|(allowed, pattern)| (pattern.len(), *allowed))
+            .is_none_or(|&(allowed, _)| allowed)
+    }
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let mut rest = match path.strip_prefix(parts.next().unwrap_or("")) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    if parts.is_empty() {
+        return !anchored || rest.is_empty();
+    }
+    for (idx, part) in parts.iter().enumerate() {
+        if anchored && idx == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(start) => rest = &rest[start + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROBOTS: &str = "\
+# keep out of the build output
+User-agent: *
+Disallow: /private/
+Allow: /private/shared/
+Disallow: /*.bak$
+
+User-agent: wget
+User-agent: Duma
+Disallow: /tmp # scratch space
+Disallow:
+
+User-agent: other
+Disallow: /
+";
+
+    #[test]
+    fn test_own_group() {
+        let robots = Robots::parse(ROBOTS, "duma");
+        assert!(!robots.allows("/tmp/a.txt"));
+        assert!(!robots.allows("/tmpfile"));
+        // the * group is left out once there is a group for duma
+        assert!(robots.allows("/private/a.txt"));
+        assert!(robots.allows("/"));
+    }
+
+    #[test]
+    fn test_any_group() {
+        let robots = Robots::parse(ROBOTS, "curl");
+        assert!(!robots.allows("/private/a.txt"));
+        assert!(robots.allows("/private/shared/a.txt"));
+        assert!(!robots.allows("/files/a.bak"));
+        assert!(robots.allows("/files/a.bak.txt"));
+        assert!(robots.allows("/tmp/a.txt"));
+
+        assert!(!Robots::parse(ROBOTS, "other").allows("/index.html"));
+        assert_eq!(Robots::parse("", "duma"), Robots::default());
+        assert!(Robots::default().allows("/anything"));
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("/a", "/a/b"));
+        assert!(!matches("/a", "/b/a"));
+        assert!(matches("/a$", "/a"));
+        assert!(!matches("/a$", "/ab"));
+        assert!(matches("/*/c", "/a/b/c"));
+        assert!(matches("/a*$", "/abc"));
+        assert!(matches("/*.php$", "/x/index.php"));
+        assert!(!matches("/*.php$", "/x/index.php?q=1"));
+        assert!(matches("/*?", "/search?q=1"));
+    }
+
+    #[test]
+    fn test_allow_wins_a_tie() {
+        let robots = Robots::parse("User-agent: *\nDisallow: /page\nAllow: /page\n", "duma");
+        assert!(robots.allows("/page"));
+    }
+}
@@ -0,0 +1,274 @@
+use url::Url;
+
+use crate::rewrite::{local_name, tag_end, tag_name};
+
+/// The urls of the stylesheets, scripts, images and media sources that
+/// `html` needs to be shown offline, for `--page-requisites`. They are
+/// resolved against `base`, the url of the page, and listed in page order
+/// without repeats. Only http and https urls are kept.
+pub fn extract_page_requisites(html: &str, base: &Url) -> Vec<Url> {
+    extract_urls(html, base, requisite_link)
+}
+
+/// The urls of the page requisites of `html` and of the pages it links to
+/// with `<a>` and `<area>`, for `--recursive`, resolved and listed as by
+/// `extract_page_requisites`.
+pub fn extract_links(html: &str, base: &Url) -> Vec<Url> {
+    extract_urls(html, base, any_link)
+}
+
+fn any_link<'a>(tag: &str, attrs: &[(&str, &'a str)]) -> Option<&'a str> {
+    match tag.to_ascii_lowercase().as_str() {
+        "a" | "area" => attribute(attrs, "href"),
+        _ => requisite_link(tag, attrs),
+    }
+}
+
+fn requisite_link<'a>(tag: &str, attrs: &[(&str, &'a str)]) -> Option<&'a str> {
+    match tag.to_ascii_lowercase().as_str() {
+        "link" if is_stylesheet(attrs) => attribute(attrs, "href"),
+        "script" | "img" | "source" => attribute(attrs, "src"),
+        _ => None,
+    }
+}
+
+/// Picks the link out of a tag, given its name and attributes.
+type LinkOf = for<'a> fn(&str, &[(&str, &'a str)]) -> Option<&'a str>;
+
+/// The urls `link` finds in the tags of `html`.
+fn extract_urls(html: &str, base: &Url, link: LinkOf) -> Vec<Url> {
+    let mut urls: Vec<Url> = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map_or(rest.len(), |end| end + 3);
+            rest = &rest[end..];
+            continue;
+        }
+        let end = match tag_end(rest) {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[..end];
+        rest = &rest[end..];
+        let attrs = attributes(tag);
+        let url = link(tag_name(tag), &attrs)
+            .and_then(|link| base.join(&link.trim().replace("&amp;", "&")).ok());
+        if let Some(mut url) = url {
+            url.set_fragment(None);
+            if (url.scheme() == "http" || url.scheme() == "https") && !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+    }
+    urls
+}
+
+/// Whether `url` is on the host of `page` and in its directory or below,
+/// which is as far as `--no-parent` lets a download go.
+pub fn is_below_page(url: &Url, page: &Url) -> bool {
+    let dir = &page.path()[..page.path().rfind('/').map_or(0, |idx| idx + 1)];
+    url.scheme() == page.scheme()
+        && url.host_str() == page.host_str()
+        && url.port_or_known_default() == page.port_or_known_default()
+        && url.path().starts_with(dir)
+}
+
+/// Whether `url` is on the same host and port as `page`, which is as far
+/// as `--recursive` goes.
+pub fn is_same_host(url: &Url, page: &Url) -> bool {
+    url.host_str() == page.host_str() && url.port_or_known_default() == page.port_or_known_default()
+}
+
+/// The `--accept` and `--reject` lists of file name extensions, given
+/// comma separated with or without the dot.
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionFilter {
+    accept: Vec<String>,
+    reject: Vec<String>,
+}
+
+impl ExtensionFilter {
+    pub fn new(accept: Option<&str>, reject: Option<&str>) -> ExtensionFilter {
+        let list = |exts: Option<&str>| -> Vec<String> {
+            exts.into_iter()
+                .flat_map(|exts| exts.split(','))
+                .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|ext| !ext.is_empty())
+                .collect()
+        };
+        ExtensionFilter {
+            accept: list(accept),
+            reject: list(reject),
+        }
+    }
+
+    /// Whether the file `url` is saved as has an extension that is
+    /// accepted, if there is an accept list, and not rejected.
+    pub fn allows(&self, url: &Url) -> bool {
+        let name = local_name(url);
+        let ext = name
+            .rsplit_once('.')
+            .map_or(String::new(), |(_, ext)| ext.to_ascii_lowercase());
+        (self.accept.is_empty() || self.accept.contains(&ext)) && !self.reject.contains(&ext)
+    }
+}
+
+fn is_stylesheet(attrs: &[(&str, &str)]) -> bool {
+    attribute(attrs, "rel").is_some_and(|rel| {
+        rel.split_whitespace()
+            .any(|rel| rel.eq_ignore_ascii_case("stylesheet"))
+    })
+}
+
+fn attribute<'a>(attrs: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    attrs
+        .iter()
+        .find(|(attr, _)| attr.eq_ignore_ascii_case(name))
+        .map(|&(_, value)| value)
+}
+
+/// The `(name, value)` pairs of `tag`, with the quotes taken off the
+/// values. Attributes without a value have an empty one.
+fn attributes(tag: &str) -> Vec<(&str, &str)> {
+    let mut attrs = Vec::new();
+    let mut rest = tag[1 + tag_name(tag).len()..].trim_end_matches('>');
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        if name_end == 0 {
+            break;
+        }
+        let name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+        if !rest.starts_with('=') {
+            attrs.push((name, ""));
+            continue;
+        }
+        rest = rest[1..].trim_start();
+        let (value, len) = match rest.chars().next() {
+            Some(q) if q == '"' || q == '\'' => {
+                let end = rest[1..].find(q).map_or(rest.len(), |end| end + 1);
+                (&rest[1..end], (end + 1).min(rest.len()))
+            }
+            _ => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+        attrs.push((name, value));
+        rest = &rest[len..];
+    }
+    attrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_page_requisites() {
+        let html = r#"<!DOCTYPE html>
+<html><head>
+<link rel="stylesheet" href="css/site.css">
+<LINK REL="alternate stylesheet" HREF='/print.css' media=print>
+<link rel=icon href=favicon.ico>
+<script src="https://cdn.example.org/lib.js?v=1&amp;x=2"></script>
+<script>if (a < b) { load("skipped.js"); }</script>
+</head><body>
+<!-- <img src="commented.png"> -->
+<img alt="logo" src=../img/logo.png#top>
+<img src="css/site.css">
+<a href="other.html"><img src="data:image/png;base64,AAAA"></a>
+<video><source src="movie.webm" type="video/webm"></video>
+</body></html>"#;
+        let base = Url::parse("http://example.com/docs/page.html").unwrap();
+        let urls: Vec<String> = extract_page_requisites(html, &base)
+            .iter()
+            .map(|url| url.to_string())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "http://example.com/docs/css/site.css",
+                "http://example.com/print.css",
+                "https://cdn.example.org/lib.js?v=1&x=2",
+                "http://example.com/img/logo.png",
+                "http://example.com/docs/movie.webm",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_below_page() {
+        let page = Url::parse("http://example.com/docs/page.html").unwrap();
+        for (url, below) in &[
+            ("http://example.com/docs/style.css", true),
+            ("http://example.com/docs/img/logo.png", true),
+            ("http://example.com:80/docs/app.js", true),
+            ("http://example.com/app.js", false),
+            ("http://example.com/docsets/app.js", false),
+            ("https://example.com/docs/app.js", false),
+            ("http://cdn.example.com/docs/app.js", false),
+        ] {
+            assert_eq!(
+                is_below_page(&Url::parse(url).unwrap(), &page),
+                *below,
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_links() {
+        let html = r##"<html><head><link rel="stylesheet" href="site.css"></head>
+<body><a href="docs/">Docs</a> <a href="#top">Top</a>
+<map><area href="map.html" shape="rect"></map>
+<a href="mailto:me@example.com">Mail</a> <img src="logo.png">
+<a href="docs/#intro">Intro</a></body></html>"##;
+        let base = Url::parse("http://example.com/index.html").unwrap();
+        let urls: Vec<String> = extract_links(html, &base)
+            .iter()
+            .map(|url| url.to_string())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "http://example.com/site.css",
+                "http://example.com/docs/",
+                "http://example.com/index.html",
+                "http://example.com/map.html",
+                "http://example.com/logo.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extension_filter() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        let filter = ExtensionFilter::new(Some("png, .JPG"), None);
+        assert!(filter.allows(&url("http://example.com/a.png")));
+        assert!(filter.allows(&url("http://example.com/b.jpg?size=2")));
+        assert!(!filter.allows(&url("http://example.com/c.gif")));
+        assert!(!filter.allows(&url("http://example.com/docs/")));
+
+        let filter = ExtensionFilter::new(None, Some("iso,zip"));
+        assert!(filter.allows(&url("http://example.com/docs/")));
+        assert!(filter.allows(&url("http://example.com/README")));
+        assert!(!filter.allows(&url("http://example.com/disk.ISO")));
+
+        assert!(ExtensionFilter::default().allows(&url("http://example.com/x.zip")));
+        assert!(is_same_host(
+            &url("https://example.com:443/x"),
+            &url("https://example.com/")
+        ));
+        assert!(!is_same_host(
+            &url("http://cdn.example.com/x"),
+            &url("http://example.com/")
+        ));
+    }
+}
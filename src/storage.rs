@@ -0,0 +1,94 @@
+use std::cell::RefCell;
+use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::mem;
+use std::rc::Rc;
+
+/// Destination the downloaded bytes are written to.
+pub trait Storage: Write + Seek {}
+
+impl<T: Write + Seek> Storage for T {}
+
+/// Streams the download to stdout for `-O -`. Only sequential writes work,
+/// so seeking anywhere but the current position fails.
+#[derive(Debug, Default)]
+pub struct StdoutStorage {
+    pos: u64,
+}
+
+impl Write for StdoutStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = io::stdout().write(buf)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl Seek for StdoutStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Start(offset) if offset == self.pos => Ok(self.pos),
+            SeekFrom::Current(0) => Ok(self.pos),
+            _ => Err(io::Error::other("cannot seek when writing to stdout")),
+        }
+    }
+}
+
+/// Keeps the download in memory. Clones share the same buffer, so one can
+/// go to the handler while the caller keeps another to take the bytes.
+#[derive(Debug, Clone, Default)]
+pub struct VecStorage(Rc<RefCell<Cursor<Vec<u8>>>>);
+
+impl VecStorage {
+    /// Takes the bytes written so far, leaving the buffer empty.
+    pub fn take(&self) -> Vec<u8> {
+        let mut cursor = self.0.borrow_mut();
+        cursor.set_position(0);
+        mem::take(cursor.get_mut())
+    }
+}
+
+impl Write for VecStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for VecStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.borrow_mut().seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stdout_storage_only_seeks_in_place() {
+        let mut storage = StdoutStorage::default();
+        assert_eq!(storage.stream_position().unwrap(), 0);
+        assert_eq!(storage.seek(SeekFrom::Start(0)).unwrap(), 0);
+        assert!(storage.seek(SeekFrom::Start(10)).is_err());
+        assert!(storage.seek(SeekFrom::End(0)).is_err());
+    }
+
+    #[test]
+    fn test_vec_storage_assembles_out_of_order_writes() {
+        let storage = VecStorage::default();
+        let mut writer = storage.clone();
+        writer.seek(SeekFrom::Start(4)).unwrap();
+        writer.write_all(b"5678").unwrap();
+        writer.seek(SeekFrom::Start(0)).unwrap();
+        writer.write_all(b"1234").unwrap();
+        assert_eq!(storage.take(), b"12345678");
+        assert!(storage.take().is_empty());
+    }
+}
@@ -1,14 +1,49 @@
-use failure::{bail, Fallible};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use console::Term;
+use failure::{bail, format_err, Fallible};
+use std::env;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::ffi::CStr;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io;
-use std::path::Path;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::sync::OnceLock;
 use url::{ParseError, Url};
 
+use crate::core::StateHandle;
+use crate::sha256::{to_hex, Sha256};
+
+/// What sets the `.st` and `.target` files of a download apart from those
+/// of other urls saved under the same name: `tmp_suffix` when given, else
+/// a short hash of the url, so resuming the same url finds them again.
+pub fn state_suffix(url: &str, tmp_suffix: Option<&str>) -> Fallible<String> {
+    match tmp_suffix {
+        Some(suffix) if suffix.is_empty() || suffix.contains(['/', std::path::MAIN_SEPARATOR]) => {
+            bail!("invalid --tmp-suffix: {:?}", suffix)
+        }
+        Some(suffix) => Ok(suffix.to_owned()),
+        None => {
+            let mut ctx = Sha256::new();
+            ctx.update(url.as_bytes());
+            Ok(to_hex(&ctx.finish())[..8].to_owned())
+        }
+    }
+}
+
+/// The `ext` state file of `fname`, e.g. `foo.txt.1a2b3c4d.st`.
+pub fn state_file(fname: &str, suffix: &str, ext: &str) -> String {
+    format!("{}.{}.{}", fname, suffix, ext)
+}
+
 pub fn parse_url(url: &str) -> Result<Url, ParseError> {
     match Url::parse(url) {
         Ok(url) => Ok(url),
-        Err(error) if error == ParseError::RelativeUrlWithoutBase => {
+        Err(ParseError::RelativeUrlWithoutBase) => {
             let url_with_base = format!("{}{}", "http://", url);
             Url::parse(url_with_base.as_str())
         }
@@ -20,25 +55,96 @@ pub fn gen_error(msg: String) -> Fallible<()> {
     bail!(msg)
 }
 
+/// Prompts on the terminal for the password of `url` unless it already
+/// carries one.
+pub fn ask_password(url: &mut Url) -> Fallible<()> {
+    if url.password().is_some() {
+        return Ok(());
+    }
+    let term = Term::stderr();
+    if !term.features().is_attended() {
+        bail!("--ask-password needs an interactive terminal to prompt on");
+    }
+    let user = if url.username().is_empty() {
+        String::new()
+    } else {
+        format!("{}@", url.username())
+    };
+    term.write_str(&format!(
+        "Password for {}{}: ",
+        user,
+        url.host_str().unwrap_or_default()
+    ))?;
+    let password = term.read_secure_line()?;
+    url.set_password(Some(&password))
+        .map_err(|_| format_err!("cannot set a password on url: {}", url))?;
+    Ok(())
+}
+
 pub fn get_file_handle(fname: &str, resume_download: bool, append: bool) -> io::Result<File> {
-    if resume_download && Path::new(fname).exists() {
+    let path = to_windows_long_path(Path::new(fname));
+    if resume_download && path.exists() {
         if append {
-            match OpenOptions::new().append(true).open(fname) {
-                Ok(file) => Ok(file),
-                Err(error) => Err(error),
-            }
+            OpenOptions::new().append(true).open(&path)
         } else {
-            match OpenOptions::new().write(true).open(fname) {
-                Ok(file) => Ok(file),
-                Err(error) => Err(error),
+            OpenOptions::new().write(true).open(&path)
+        }
+    } else {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+    }
+}
+
+/// On Windows, `path` in the `\\?\` form that may be longer than 260
+/// characters. Elsewhere, and for paths that aren't valid UTF-8, `path`
+/// is returned as is.
+pub fn to_windows_long_path(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        if let (Some(name), Ok(cwd)) = (path.to_str(), env::current_dir()) {
+            if let Some(cwd) = cwd.to_str() {
+                return PathBuf::from(windows_long_path(name, cwd));
             }
         }
+    }
+    path.to_path_buf()
+}
+
+/// Prefixes the Windows path `path` with `\\?\`, first making it absolute
+/// against `cwd`, since Windows leaves prefixed paths alone: `/`, `.` and
+/// `..` are resolved here instead.
+pub fn windows_long_path(path: &str, cwd: &str) -> String {
+    if path.starts_with(r"\\?\") {
+        return path.to_owned();
+    }
+    let path = path.replace('/', "\\");
+    if let Some(share) = path.strip_prefix(r"\\") {
+        return format!(r"\\?\UNC\{}", share);
+    }
+    let has_drive = path.as_bytes().get(1) == Some(&b':');
+    let absolute = if has_drive && path.as_bytes().get(2) == Some(&b'\\') {
+        path
+    } else if path.starts_with('\\') {
+        // rooted on the drive of the current directory
+        format!("{}{}", cwd.get(..2).unwrap_or_default(), path)
     } else {
-        match OpenOptions::new().write(true).create(true).open(fname) {
-            Ok(file) => Ok(file),
-            Err(error) => Err(error),
+        format!("{}\\{}", cwd, path)
+    };
+    let mut parts: Vec<&str> = vec![];
+    for part in absolute.split('\\') {
+        match part {
+            "" | "." => {}
+            ".." if parts.len() > 1 => {
+                parts.pop();
+            }
+            ".." => {}
+            _ => parts.push(part),
         }
     }
+    format!(r"\\?\{}", parts.join("\\"))
 }
 
 pub fn decode_percent_encoded_data(data: &str) -> Fallible<String> {
@@ -47,9 +153,14 @@ pub fn decode_percent_encoded_data(data: &str) -> Fallible<String> {
     while let Some(b) = bytes.next() {
         match b as char {
             '%' => {
-                let bytes_to_decode = &[bytes.next().unwrap(), bytes.next().unwrap()];
-                let hex_str = std::str::from_utf8(bytes_to_decode).unwrap();
-                unescaped_bytes.push(u8::from_str_radix(hex_str, 16).unwrap());
+                let hex = [bytes.next(), bytes.next()];
+                let byte = match hex {
+                    [Some(hi), Some(lo)] if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                        u8::from_str_radix(std::str::from_utf8(&[hi, lo])?, 16)?
+                    }
+                    _ => bail!("invalid percent escape in {:?}", data),
+                };
+                unescaped_bytes.push(byte);
             }
             _ => {
                 unescaped_bytes.push(b);
@@ -59,14 +170,906 @@ pub fn decode_percent_encoded_data(data: &str) -> Fallible<String> {
     Ok(String::from_utf8(unescaped_bytes)?)
 }
 
+/// The last `/` or `\` separated part of a name taken from a url, or
+/// `None` when that is empty, `.` or `..`. Saving under the result can't
+/// leave the directory it is joined to, whatever the url decoded to.
+pub fn plain_file_name(name: &str) -> Option<&str> {
+    match name.rsplit(['/', '\\']).next().unwrap_or("") {
+        "" | "." | ".." => None,
+        name => Some(name),
+    }
+}
+
+/// Decodes an `application/x-www-form-urlencoded` value such as a query
+/// parameter, where `+` stands for a space and `%2B` for a plus.
+pub fn decode_form_encoded(data: &str) -> Fallible<String> {
+    decode_percent_encoded_data(&data.replace('+', " "))
+}
+
+/// Parses a byte count such as `512`, `10m` or `1.5Gi`.
+///
+/// `k`, `m` and `g` are decimal multiples and `ki`, `mi` and `gi` binary
+/// ones, in any case. A fraction is allowed as long as it comes to a whole
+/// number of bytes.
+pub fn parse_size(size: &str) -> Fallible<u64> {
+    let invalid = || format_err!("invalid size: {}", size);
+    let lowered = size.trim().to_lowercase();
+    let split = lowered
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(lowered.len());
+    let (number, suffix) = lowered.split_at(split);
+    let multiplier: u128 = match suffix {
+        "" => 1,
+        "k" => 1_000,
+        "m" => 1_000_000,
+        "g" => 1_000_000_000,
+        "ki" => 1 << 10,
+        "mi" => 1 << 20,
+        "gi" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    let (whole, frac) = match number.find('.') {
+        Some(idx) => (&number[..idx], &number[idx + 1..]),
+        None => (number, ""),
+    };
+    if whole.is_empty() && frac.is_empty() || frac.len() > 9 {
+        return Err(invalid());
+    }
+    let parse = |digits: &str| -> Fallible<u128> {
+        if digits.is_empty() {
+            Ok(0)
+        } else {
+            digits.parse::<u128>().map_err(|_| invalid())
+        }
+    };
+    let scale = 10u128.pow(frac.len() as u32);
+    let too_large = || format_err!("size is too large: {}", size);
+    let frac = parse(frac)?;
+    let scaled = parse(whole)?
+        .checked_mul(scale)
+        .and_then(|val| val.checked_add(frac))
+        .and_then(|val| val.checked_mul(multiplier))
+        .ok_or_else(too_large)?;
+    if !scaled.is_multiple_of(scale) {
+        bail!("size is not a whole number of bytes: {}", size);
+    }
+    let bytes = scaled / scale;
+    if bytes > u128::from(u64::MAX) {
+        return Err(too_large());
+    }
+    Ok(bytes as u64)
+}
+
+/// Takes over file descriptor `fd`, which must not be stdin, stdout or
+/// stderr, e.g. one a shell opened with `3>progress.log`.
+#[cfg(unix)]
+pub fn open_progress_fd(fd: i32) -> Fallible<File> {
+    use std::os::unix::io::FromRawFd;
+
+    if fd <= 2 {
+        bail!(
+            "progress file descriptor must be greater than 2, got {}",
+            fd
+        );
+    }
+    // the descriptor is ours from here on and closed when the file drops
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+pub fn open_progress_fd(_fd: i32) -> Fallible<File> {
+    bail!("--progress-to-fd is not supported on this platform")
+}
+
+/// Sizes `file` to `len` bytes. On Linux the blocks are also reserved with
+/// `fallocate`, where the filesystem supports it.
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // best effort, `set_len` below still sizes the file
+        unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    }
+    file.set_len(len)
+}
+
+/// Marks `file` sparse, so the gaps between chunks written out of order
+/// take no disk space until they are filled. Unix file systems do this by
+/// themselves, NTFS only for files flagged with `FSCTL_SET_SPARSE`.
+#[cfg(windows)]
+pub fn set_sparse(file: &File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+    use winapi::um::ioapiset::DeviceIoControl;
+    use winapi::um::winioctl::FSCTL_SET_SPARSE;
+
+    let mut returned = 0;
+    let ok = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as _,
+            FSCTL_SET_SPARSE,
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn set_sparse(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Parses a file mode such as `755`, `0755` or `0o755`.
+pub fn parse_mode(mode: &str) -> Fallible<u32> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    if digits.is_empty() || !digits.chars().all(|c| ('0'..='7').contains(&c)) {
+        bail!("invalid file mode: {}", mode);
+    }
+    match u32::from_str_radix(digits, 8) {
+        Ok(bits) if bits <= 0o7777 => Ok(bits),
+        _ => bail!("invalid file mode: {}", mode),
+    }
+}
+
+/// Sets the permission bits of the file at `path` to `mode`.
+#[cfg(unix)]
+pub fn set_file_mode(path: &str, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_file_mode(_path: &str, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Joins the files in `parts`, in order, into `output`, e.g. the pieces of
+/// a download split with `HttpDownload::split_download`. Returns the bytes
+/// written.
+pub fn merge_partial_files(parts: &[&str], output: &str) -> Fallible<u64> {
+    let mut out = File::create(output)?;
+    let mut bytes = 0;
+    for part in parts {
+        let mut file =
+            File::open(part).map_err(|e| format_err!("cannot open part {}: {}", part, e))?;
+        bytes += io::copy(&mut file, &mut out)?;
+    }
+    out.sync_all()?;
+    Ok(bytes)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+static SIGUSR1_STATE: OnceLock<StateHandle> = OnceLock::new();
+
+/// Toggles `state` between paused and running on each `SIGUSR1`, e.g.
+/// `kill -USR1 <pid>` to free the bandwidth for a while. Only the first
+/// download of the process is controlled this way.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn pause_on_sigusr1(state: StateHandle) -> Fallible<()> {
+    extern "C" fn on_sigusr1(_: libc::c_int) {
+        if let Some(state) = SIGUSR1_STATE.get() {
+            state.toggle_pause();
+        }
+    }
+    if SIGUSR1_STATE.set(state).is_err() {
+        return Ok(());
+    }
+    let handler = on_sigusr1 as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGUSR1, handler) } == libc::SIG_ERR {
+        bail!("cannot handle SIGUSR1: {}", io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn pause_on_sigusr1(_state: StateHandle) -> Fallible<()> {
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+static SIGUSR2_STATE: OnceLock<StateHandle> = OnceLock::new();
+
+/// Prints a status line for the download of `state` on each `SIGUSR2`,
+/// e.g. `kill -USR2 <pid>`, the way `dd` answers `SIGUSR1` (which pauses
+/// duma). The download carries on. Only the first download of the process
+/// answers.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn status_on_sigusr2(state: StateHandle) -> Fallible<()> {
+    extern "C" fn on_sigusr2(_: libc::c_int) {
+        if let Some(state) = SIGUSR2_STATE.get() {
+            state.request_status();
+        }
+    }
+    if SIGUSR2_STATE.set(state).is_err() {
+        return Ok(());
+    }
+    let handler = on_sigusr2 as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGUSR2, handler) } == libc::SIG_ERR {
+        bail!("cannot handle SIGUSR2: {}", io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn status_on_sigusr2(_state: StateHandle) -> Fallible<()> {
+    Ok(())
+}
+
+/// Points stderr at file descriptor `fd` so that the progress bar, which
+/// indicatif can only draw on stdout or stderr, ends up there.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn redirect_stderr(fd: i32) -> Fallible<()> {
+    if unsafe { libc::dup2(fd, 2) } == -1 {
+        bail!(
+            "cannot draw the progress bar on file descriptor {}: {}",
+            fd,
+            io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn redirect_stderr(_fd: i32) -> Fallible<()> {
+    bail!("--progress-fd only supports 1 and 2 on this platform")
+}
+
+/// Resolves the user's downloads folder, honouring `$XDG_DOWNLOAD_DIR`
+/// before the platform default.
+pub fn default_download_dir() -> Option<PathBuf> {
+    env::var_os("XDG_DOWNLOAD_DIR")
+        .map(PathBuf::from)
+        .or_else(dirs::download_dir)
+}
+
+/// Returns the downloads folder found by `resolve`, failing when there is
+/// none or it does not exist.
+pub fn download_dir<F>(resolve: F) -> Fallible<PathBuf>
+where
+    F: FnOnce() -> Option<PathBuf>,
+{
+    match resolve() {
+        Some(dir) if dir.is_dir() => Ok(dir),
+        Some(dir) => bail!("downloads folder {} does not exist", dir.display()),
+        None => bail!("could not determine the downloads folder"),
+    }
+}
+
+/// Parses a `Content-Range` value such as `bytes 0-499/1234` into
+/// `(start, end, total)`. Unsatisfied (`bytes */1234`) and unknown length
+/// (`bytes 0-499/*`) forms yield `None`.
+pub fn parse_content_range(header: &str) -> Option<(u64, u64, u64)> {
+    let range = header.trim().strip_prefix("bytes ")?;
+    let (span, total) = range.split_once('/')?;
+    let (start, end) = span.split_once('-')?;
+    let (start, end, total) = (
+        start.trim().parse::<u64>().ok()?,
+        end.trim().parse::<u64>().ok()?,
+        total.trim().parse::<u64>().ok()?,
+    );
+    if start > end || end >= total {
+        return None;
+    }
+    Some((start, end, total))
+}
+
+/// Parses a comma separated list of inclusive byte ranges, e.g. `0-99,500-599`.
+pub fn parse_ranges(ranges: &str) -> Fallible<Vec<(u64, u64)>> {
+    let mut parsed = Vec::new();
+    for range in ranges.split(',') {
+        let mut bounds = range.trim().splitn(2, '-');
+        let start = bounds.next().unwrap_or_default().trim();
+        let end = bounds
+            .next()
+            .ok_or_else(|| format_err!("invalid byte range: {}", range))?
+            .trim();
+        match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => parsed.push((start, end)),
+            _ => bail!("invalid byte range: {}", range),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Parses the `--user-agent-file` list, one user agent per line, skipping
+/// blank lines and `#` comments.
+pub fn parse_user_agents(list: &str) -> Fallible<Vec<String>> {
+    let agents: Vec<String> = list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+    if agents.is_empty() {
+        bail!("the user agent file lists no user agents");
+    }
+    Ok(agents)
+}
+
+static MIME_EXTENSIONS: &[(&str, &str)] = &[
+    ("application/zip", "zip"),
+    ("application/pdf", "pdf"),
+    ("application/gzip", "gz"),
+    ("application/x-gzip", "gz"),
+    ("application/x-bzip2", "bz2"),
+    ("application/x-xz", "xz"),
+    ("application/zstd", "zst"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/vnd.rar", "rar"),
+    ("application/x-tar", "tar"),
+    ("application/json", "json"),
+    ("application/xml", "xml"),
+    ("application/octet-stream", "bin"),
+    ("text/plain", "txt"),
+    ("text/gemini", "gmi"),
+    ("text/csv", "csv"),
+    ("text/xml", "xml"),
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/svg+xml", "svg"),
+    ("audio/ogg", "ogg"),
+    ("video/mp4", "mp4"),
+];
+
+/// How a download is named when `-O` doesn't name it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NamingStrategy {
+    /// The Content-Disposition filename, else the last segment of the url
+    /// path.
+    UrlLast,
+    /// The Content-Disposition filename, failing without one.
+    ContentDisposition,
+    /// The SHA-256 of the url as hex, e.g. to avoid clashing names.
+    Hash,
+    /// The `UrlLast` name prefixed with the UTC time, e.g.
+    /// `2024-01-15T12:00:00-report.pdf`.
+    Timestamp,
+}
+
+impl FromStr for NamingStrategy {
+    type Err = failure::Error;
+
+    fn from_str(val: &str) -> Fallible<NamingStrategy> {
+        match val {
+            "url-last" => Ok(NamingStrategy::UrlLast),
+            "content-disposition" => Ok(NamingStrategy::ContentDisposition),
+            "hash" => Ok(NamingStrategy::Hash),
+            "timestamp" => Ok(NamingStrategy::Timestamp),
+            _ => bail!("unknown output naming strategy: {}", val),
+        }
+    }
+}
+
+impl NamingStrategy {
+    /// Names the download of `url` from its Content-Disposition filename
+    /// or, if needed, the name `url_name` derives from the url path.
+    pub fn name<F>(
+        self,
+        url: &Url,
+        content_disposition: Option<String>,
+        url_name: F,
+        now: DateTime<Utc>,
+    ) -> Fallible<String>
+    where
+        F: FnOnce() -> String,
+    {
+        match self {
+            NamingStrategy::UrlLast => Ok(content_disposition.unwrap_or_else(url_name)),
+            NamingStrategy::ContentDisposition => content_disposition
+                .ok_or_else(|| format_err!("{} sent no Content-Disposition filename", url)),
+            NamingStrategy::Hash => {
+                let mut ctx = Sha256::new();
+                ctx.update(url.as_str().as_bytes());
+                Ok(to_hex(&ctx.finish()))
+            }
+            NamingStrategy::Timestamp => Ok(format!(
+                "{}-{}",
+                now.format("%Y-%m-%dT%H:%M:%S"),
+                content_disposition.unwrap_or_else(url_name)
+            )),
+        }
+    }
+}
+
+/// Makes `name` safe to use unquoted in a shell: whitespace becomes `_`
+/// and anything but ASCII letters, digits, `.`, `_` and `-` is dropped.
+/// The extension is kept, a name left without a stem becomes
+/// `download.EXT`, and one left with nothing at all `index.html`.
+pub fn normalize_filename(name: &str, lowercase: bool) -> String {
+    let mut cleaned: String = name
+        .chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        .collect();
+    if lowercase {
+        cleaned.make_ascii_lowercase();
+    }
+    let (stem, ext) = match cleaned.rfind('.') {
+        Some(dot) => (&cleaned[..dot], &cleaned[dot + 1..]),
+        None => (&cleaned[..], ""),
+    };
+    // no hidden files, and no `..`
+    let stem = stem.trim_matches('.');
+    match (stem.is_empty(), ext.is_empty()) {
+        (false, false) => format!("{}.{}", stem, ext),
+        (false, true) => stem.to_owned(),
+        (true, false) => format!("download.{}", ext),
+        (true, true) => "index.html".to_owned(),
+    }
+}
+
+/// Parses an `--if-modified-since` date: an HTTP date such as
+/// `Wed, 21 Oct 2015 07:28:00 GMT`, an RFC 3339 time or a plain
+/// `2015-10-21`, taken as midnight UTC.
+pub fn parse_http_date(date: &str) -> Fallible<DateTime<Utc>> {
+    let date = date.trim();
+    if let Ok(time) = DateTime::parse_from_rfc2822(date) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(date) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        // midnight is always a valid time
+        .map(|day| Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).unwrap()))
+        .map_err(|_| format_err!("invalid date: {}", date))
+}
+
+/// `time` in the format of HTTP headers, e.g. `Wed, 21 Oct 2015 07:28:00 GMT`.
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Maps a `Content-Type` value to a file extension, ignoring parameters
+/// such as `charset`.
+pub fn mime_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_lowercase();
+    MIME_EXTENSIONS
+        .iter()
+        .find(|(name, _)| *name == mime)
+        .map(|(_, ext)| *ext)
+}
+
+/// Parses a single `START[-END]` byte range; without an end it runs to the
+/// end of the file.
+pub fn parse_range(range: &str) -> Fallible<(u64, Option<u64>)> {
+    let range = range.trim();
+    let (start, end) = match range.split_once('-') {
+        Some((start, "")) => (start, None),
+        Some((start, end)) => (start, Some(end)),
+        None => (range, None),
+    };
+    let start = start
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format_err!("invalid byte range: {}", range))?;
+    match end.map(|end| end.trim().parse::<u64>()) {
+        None => Ok((start, None)),
+        Some(Ok(end)) if start <= end => Ok((start, Some(end))),
+        Some(_) => bail!("invalid byte range: {}", range),
+    }
+}
+
+/// Returns the address assigned to the network interface `ifname`,
+/// preferring IPv4 when the interface has both.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn interface_address(ifname: &str) -> Fallible<IpAddr> {
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut found = None;
+    let mut cur = addrs;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) };
+        if name.to_bytes() != ifname.as_bytes() || ifa.ifa_addr.is_null() {
+            continue;
+        }
+        match i32::from(unsafe { (*ifa.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                found = Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    sin.sin_addr.s_addr,
+                ))));
+                break;
+            }
+            libc::AF_INET6 if found.is_none() => {
+                let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                found = Some(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    found.ok_or_else(|| format_err!("no address assigned to network interface {}", ifname))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_windows_long_path() {
+        let cwd = r"C:\Users\duma";
+        assert_eq!(
+            windows_long_path(r"D:\dl\foo.txt", cwd),
+            r"\\?\D:\dl\foo.txt"
+        );
+        assert_eq!(
+            windows_long_path("foo.txt", cwd),
+            r"\\?\C:\Users\duma\foo.txt"
+        );
+        assert_eq!(
+            windows_long_path("./a/../b/foo.txt", cwd),
+            r"\\?\C:\Users\duma\b\foo.txt"
+        );
+        assert_eq!(windows_long_path(r"\dl\foo.txt", cwd), r"\\?\C:\dl\foo.txt");
+        assert_eq!(windows_long_path(r"C:\..\foo.txt", cwd), r"\\?\C:\foo.txt");
+        assert_eq!(
+            windows_long_path(r"\\server\share\foo.txt", cwd),
+            r"\\?\UNC\server\share\foo.txt"
+        );
+        assert_eq!(windows_long_path(r"\\?\C:\foo.txt", cwd), r"\\?\C:\foo.txt");
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_sparse_gaps_take_no_space() {
+        use std::io::{Seek, SeekFrom, Write};
+        use std::os::windows::ffi::OsStrExt;
+        use winapi::um::fileapi::GetCompressedFileSizeW;
+
+        let path = env::temp_dir().join(format!("duma-sparse-{}", std::process::id()));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        set_sparse(&file).unwrap();
+        // a chunk landing far past the ones before it
+        file.seek(SeekFrom::Start(64 * 1024 * 1024)).unwrap();
+        file.write_all(b"end").unwrap();
+        file.sync_all().unwrap();
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        let mut high = 0;
+        let low = unsafe { GetCompressedFileSizeW(wide.as_ptr(), &mut high) };
+        let allocated = (u64::from(high) << 32) | u64::from(low);
+        assert_eq!(file.metadata().unwrap().len(), 64 * 1024 * 1024 + 3);
+        assert!(allocated < 1024 * 1024, "{} bytes allocated", allocated);
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_long_path_is_a_no_op() {
+        let path = Path::new("some/dir/foo.txt");
+        assert_eq!(to_windows_long_path(path), path);
+    }
+
+    #[test]
+    fn test_state_suffix() {
+        let a = state_suffix("http://a.example.com/foo.txt", None).unwrap();
+        assert_eq!(a.len(), 8);
+        assert_eq!(
+            a,
+            state_suffix("http://a.example.com/foo.txt", None).unwrap()
+        );
+        assert_ne!(
+            a,
+            state_suffix("http://b.example.com/foo.txt", None).unwrap()
+        );
+        assert_eq!(
+            state_suffix("http://a.example.com/foo.txt", Some("1")).unwrap(),
+            "1"
+        );
+        assert!(state_suffix("http://a.example.com/foo.txt", Some("a/b")).is_err());
+        assert_eq!(state_file("foo.txt", "1", "st"), "foo.txt.1.st");
+    }
+
     #[test]
     fn test_decode_percent_encoded_data() {
         let x = "hello%20world";
         let y = decode_percent_encoded_data(x).unwrap();
         assert_eq!(&y, "hello world");
+        assert!(decode_percent_encoded_data("foo%").is_err());
+        assert!(decode_percent_encoded_data("foo%4").is_err());
+        assert!(decode_percent_encoded_data("foo%zz").is_err());
+        assert!(decode_percent_encoded_data("foo%+1").is_err());
+    }
+
+    #[test]
+    fn test_plain_file_name() {
+        assert_eq!(plain_file_name("a.txt"), Some("a.txt"));
+        assert_eq!(plain_file_name("../../pwned.txt"), Some("pwned.txt"));
+        assert_eq!(plain_file_name("/tmp/abs.txt"), Some("abs.txt"));
+        assert_eq!(plain_file_name("..\\x\\y.txt"), Some("y.txt"));
+        assert_eq!(plain_file_name(".."), None);
+        assert_eq!(plain_file_name("a/.."), None);
+        assert_eq!(plain_file_name("dir/"), None);
+    }
+
+    #[test]
+    fn test_decode_form_encoded() {
+        assert_eq!(decode_form_encoded("hello+world").unwrap(), "hello world");
+        assert_eq!(decode_form_encoded("hello%2Bworld").unwrap(), "hello+world");
+        assert_eq!(decode_form_encoded("a%20b+c").unwrap(), "a b c");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size(" 64k ").unwrap(), 64_000);
+        assert_eq!(parse_size("16M").unwrap(), 16_000_000);
+        assert_eq!(parse_size("1g").unwrap(), 1_000_000_000);
+    }
+
+    #[test]
+    fn test_parse_size_binary_suffixes() {
+        assert_eq!(parse_size("1ki").unwrap(), 1024);
+        assert_eq!(parse_size("2Mi").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_size("1GI").unwrap(), 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_parse_size_fractions() {
+        assert_eq!(parse_size("1.5k").unwrap(), 1500);
+        assert_eq!(parse_size("0.5ki").unwrap(), 512);
+        assert_eq!(parse_size(".25m").unwrap(), 250_000);
+        assert_eq!(parse_size("2.").unwrap(), 2);
+        assert!(parse_size("1.5").is_err());
+        assert!(parse_size("0.0001k").is_err());
+    }
+
+    #[test]
+    fn test_parse_size_rejects_garbage() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size(".").is_err());
+        assert!(parse_size("k").is_err());
+        assert!(parse_size("12x").is_err());
+        assert!(parse_size("12kb").is_err());
+        assert!(parse_size("1 k").is_err());
+        assert!(parse_size("-1k").is_err());
+        assert_eq!(
+            parse_size("1.2.3k").unwrap_err().to_string(),
+            "invalid size: 1.2.3k"
+        );
+        assert!(parse_size("18446744073709551616").is_err());
+        assert!(parse_size("20000000000gi").is_err());
+        assert!(parse_size("999999999999999999999999999999999999999").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_progress_fd_rejects_std_streams() {
+        for fd in 0..=2 {
+            assert!(open_progress_fd(fd).is_err());
+        }
+    }
+
+    #[test]
+    fn test_download_dir() {
+        let tmp = env::temp_dir();
+        assert_eq!(download_dir(|| Some(tmp.clone())).unwrap(), tmp);
+        assert!(download_dir(|| None).is_err());
+        assert!(download_dir(|| Some(tmp.join("duma-no-such-dir"))).is_err());
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 0-499/1234"),
+            Some((0, 499, 1234))
+        );
+        assert_eq!(
+            parse_content_range("bytes 734-1233/1234"),
+            Some((734, 1233, 1234))
+        );
+        assert_eq!(parse_content_range("bytes */1234"), None);
+        assert_eq!(parse_content_range("bytes 0-499/*"), None);
+        assert_eq!(parse_content_range("bytes 500-499/1234"), None);
+        assert_eq!(parse_content_range("bytes 0-1234/1234"), None);
+        assert_eq!(parse_content_range("items 0-499/1234"), None);
+        assert_eq!(parse_content_range("bytes 0-abc/1234"), None);
+        assert_eq!(parse_content_range(""), None);
+    }
+
+    #[test]
+    fn test_normalize_filename() {
+        assert_eq!(
+            normalize_filename("My Report (final).PDF", false),
+            "My_Report_final.PDF"
+        );
+        assert_eq!(
+            normalize_filename("My Report (final).PDF", true),
+            "my_report_final.pdf"
+        );
+        assert_eq!(
+            normalize_filename("foo-1.2.tar.gz", false),
+            "foo-1.2.tar.gz"
+        );
+        assert_eq!(normalize_filename("résumé.txt", false), "rsum.txt");
+        assert_eq!(normalize_filename("日本.pdf", false), "download.pdf");
+        assert_eq!(normalize_filename(".bashrc", false), "download.bashrc");
+        assert_eq!(normalize_filename("..", false), "index.html");
+        assert_eq!(normalize_filename("日本", false), "index.html");
+        assert_eq!(normalize_filename("", true), "index.html");
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        let expected = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        for date in &[
+            "Wed, 21 Oct 2015 07:28:00 GMT",
+            "2015-10-21T09:28:00+02:00",
+            "2015-10-21T07:28:00Z",
+        ] {
+            assert_eq!(parse_http_date(date).unwrap(), expected);
+        }
+        assert_eq!(
+            http_date(parse_http_date("2015-10-21").unwrap()),
+            "Wed, 21 Oct 2015 00:00:00 GMT"
+        );
+        assert_eq!(http_date(expected), "Wed, 21 Oct 2015 07:28:00 GMT");
+        assert!(parse_http_date("yesterday").is_err());
+        assert!(parse_http_date("2015-13-01").is_err());
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert_eq!(parse_mode("0644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0o4755").unwrap(), 0o4755);
+        assert!(parse_mode("").is_err());
+        assert!(parse_mode("0o").is_err());
+        assert!(parse_mode("+755").is_err());
+        assert!(parse_mode("789").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn test_parse_user_agents() {
+        let list = "# desktop\nMozilla/5.0 (X11)\n\n  Wget/1.21  \n";
+        assert_eq!(
+            parse_user_agents(list).unwrap(),
+            vec!["Mozilla/5.0 (X11)", "Wget/1.21"]
+        );
+        assert!(parse_user_agents("").is_err());
+        assert!(parse_user_agents("# nothing\n\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(
+            parse_ranges("0-99,500-599").unwrap(),
+            vec![(0, 99), (500, 599)]
+        );
+        assert_eq!(parse_ranges(" 7-7 ").unwrap(), vec![(7, 7)]);
+        assert!(parse_ranges("").is_err());
+        assert!(parse_ranges("10").is_err());
+        assert!(parse_ranges("10-").is_err());
+        assert!(parse_ranges("99-0").is_err());
+        assert!(parse_ranges("0-99,,").is_err());
+    }
+
+    fn naming_time() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-01-15T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_naming_url_last() {
+        let url = Url::parse("http://example.com/files/report.pdf").unwrap();
+        let url_name = || "report.pdf".to_owned();
+        let strategy = NamingStrategy::UrlLast;
+        assert_eq!(
+            strategy.name(&url, None, url_name, naming_time()).unwrap(),
+            "report.pdf"
+        );
+        let disposition = Some("q1.pdf".to_owned());
+        assert_eq!(
+            strategy
+                .name(&url, disposition, url_name, naming_time())
+                .unwrap(),
+            "q1.pdf"
+        );
+    }
+
+    #[test]
+    fn test_naming_content_disposition() {
+        let url = Url::parse("http://example.com/files/report.pdf").unwrap();
+        let url_name = || "report.pdf".to_owned();
+        let strategy = NamingStrategy::ContentDisposition;
+        let disposition = Some("q1.pdf".to_owned());
+        assert_eq!(
+            strategy
+                .name(&url, disposition, url_name, naming_time())
+                .unwrap(),
+            "q1.pdf"
+        );
+        assert!(strategy.name(&url, None, url_name, naming_time()).is_err());
+    }
+
+    #[test]
+    fn test_naming_hash() {
+        let url = Url::parse("http://example.com/files/report.pdf").unwrap();
+        let name = NamingStrategy::Hash
+            .name(&url, None, || "report.pdf".to_owned(), naming_time())
+            .unwrap();
+        let mut ctx = Sha256::new();
+        ctx.update(b"http://example.com/files/report.pdf");
+        assert_eq!(name, to_hex(&ctx.finish()));
+        assert_eq!(name.len(), 64);
+    }
+
+    #[test]
+    fn test_naming_timestamp() {
+        let url = Url::parse("http://example.com/files/report.pdf").unwrap();
+        let name = NamingStrategy::Timestamp
+            .name(&url, None, || "report.pdf".to_owned(), naming_time())
+            .unwrap();
+        assert_eq!(name, "2024-01-15T12:00:00-report.pdf");
+    }
+
+    #[test]
+    fn test_parse_naming_strategy() {
+        assert_eq!(
+            "content-disposition".parse::<NamingStrategy>().unwrap(),
+            NamingStrategy::ContentDisposition
+        );
+        assert!("newest".parse::<NamingStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_mime_extension() {
+        assert_eq!(mime_extension("application/zip"), Some("zip"));
+        assert_eq!(mime_extension("Application/PDF"), Some("pdf"));
+        assert_eq!(mime_extension("text/plain; charset=utf-8"), Some("txt"));
+        assert_eq!(mime_extension("text/html"), None);
+        assert_eq!(mime_extension(""), None);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("100-199").unwrap(), (100, Some(199)));
+        assert_eq!(parse_range("84000-").unwrap(), (84000, None));
+        assert_eq!(parse_range(" 5 ").unwrap(), (5, None));
+        assert!(parse_range("").is_err());
+        assert!(parse_range("-100").is_err());
+        assert!(parse_range("9-1").is_err());
+        assert!(parse_range("0-99,200-299").is_err());
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn test_interface_address_loopback() {
+        let name = if cfg!(target_os = "linux") {
+            "lo"
+        } else {
+            "lo0"
+        };
+        assert!(interface_address(name).unwrap().is_loopback());
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn test_interface_address_unknown() {
+        assert!(interface_address("no-such-if0").is_err());
     }
 }
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use failure::{bail, Fallible};
+use reqwest::blocking::Client;
+use reqwest::header::{self, HeaderValue};
+use reqwest::Method;
+use url::Url;
+
+use crate::core::{send_retrying, Config, DownloadError};
+
+static PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="DAV:">
+  <D:prop><D:getcontentlength/><D:getlastmodified/></D:prop>
+</D:propfind>"#;
+
+/// The live properties of a WebDAV resource that a download cares about.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DavProperties {
+    pub content_length: Option<u64>,
+    pub last_modified: Option<String>,
+}
+
+/// Asks the server for the resource's size and modification date with a
+/// `PROPFIND` of depth 0, sending the same headers as the download.
+pub fn propfind(client: &Client, url: &Url, conf: &Config) -> Fallible<DavProperties> {
+    let user_agent = HeaderValue::from_str(&conf.user_agent)?;
+    let resp = send_retrying(conf, || {
+        client
+            .request(Method::from_bytes(b"PROPFIND").unwrap(), url.as_ref())
+            .timeout(Duration::from_secs(conf.timeout))
+            .headers(conf.headers.clone())
+            .header(header::USER_AGENT, user_agent.clone())
+            .header("Depth", "0")
+            .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY)
+    })?;
+    // 207 Multi-Status is the only answer that carries properties
+    if resp.status().as_u16() != 207 {
+        if !resp.status().is_success() {
+            return Err(DownloadError::HttpStatus(resp.status().as_u16()).into());
+        }
+        bail!("{} did not answer PROPFIND with a multi-status", url);
+    }
+    Ok(parse_propfind(&resp.text()?))
+}
+
+/// Picks `getcontentlength` and `getlastmodified` out of a multi-status
+/// reply, whatever namespace prefix the server uses.
+pub fn parse_propfind(xml: &str) -> DavProperties {
+    DavProperties {
+        content_length: element_text(xml, "getcontentlength").and_then(|val| val.parse().ok()),
+        last_modified: element_text(xml, "getlastmodified").map(|val| val.to_owned()),
+    }
+}
+
+/// Returns the trimmed text of the first element named `name`, e.g. both
+/// `<D:name>..</D:name>` and `<name xmlns="DAV:">..</name>`.
+fn element_text<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let tag_end = rest.find('>')?;
+        let tag = &rest[..tag_end];
+        let tag_name = tag.split_whitespace().next().unwrap_or("");
+        let local_name = tag_name.rsplit(':').next().unwrap_or("");
+        if local_name == name && !tag.ends_with('/') {
+            let text = &rest[tag_end + 1..];
+            let text_end = text.find('<')?;
+            return Some(text[..text_end].trim());
+        }
+        rest = &rest[tag_end + 1..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_prefixed_multistatus() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+            <D:multistatus xmlns:D="DAV:">
+              <D:response>
+                <D:href>/dav/foo.txt</D:href>
+                <D:propstat>
+                  <D:prop>
+                    <D:getcontentlength>84687</D:getcontentlength>
+                    <D:getlastmodified>Wed, 11 Nov 2020 10:00:00 GMT</D:getlastmodified>
+                  </D:prop>
+                  <D:status>HTTP/1.1 200 OK</D:status>
+                </D:propstat>
+              </D:response>
+            </D:multistatus>"#;
+        assert_eq!(
+            parse_propfind(xml),
+            DavProperties {
+                content_length: Some(84687),
+                last_modified: Some("Wed, 11 Nov 2020 10:00:00 GMT".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_default_namespace_and_missing_props() {
+        let xml = r#"<multistatus xmlns="DAV:"><response><propstat><prop>
+            <getcontentlength xmlns="DAV:"> 12 </getcontentlength>
+            <getlastmodified/>
+            </prop></propstat></response></multistatus>"#;
+        assert_eq!(
+            parse_propfind(xml),
+            DavProperties {
+                content_length: Some(12),
+                last_modified: None,
+            }
+        );
+    }
+}
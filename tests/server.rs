@@ -1,16 +1,58 @@
 extern crate tiny_http;
 use self::tiny_http::{Header, Request, Response, Server};
 use std::fs::File;
-use std::io::Error;
-use std::sync::Arc;
+use std::io::{BufRead, BufReader, Error, Read, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Once;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 static INIT: Once = Once::new();
+static COUNTED: Gauge = Gauge::new();
+static COUNTED_GLOBAL: Gauge = Gauge::new();
+static FLAKY_ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+static USER_AGENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Requests being handled now, and the most there were at once.
+struct Gauge {
+    active: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl Gauge {
+    const fn new() -> Gauge {
+        Gauge {
+            active: AtomicUsize::new(0),
+            max: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// The most requests to `/counted` the raw server has handled at once.
+pub fn max_counted_connections() -> usize {
+    COUNTED.max.load(Ordering::SeqCst)
+}
+
+/// The same for `/counted-global`, kept apart so the tests can run side
+/// by side.
+pub fn max_global_connections() -> usize {
+    COUNTED_GLOBAL.max.load(Ordering::SeqCst)
+}
+
+/// The User-Agent headers of the requests to `/user-agent` so far.
+pub fn seen_user_agents() -> Vec<String> {
+    USER_AGENTS.lock().unwrap().clone()
+}
 
 pub fn setup() {
     INIT.call_once(|| {
+        // keep the bandwidth history of test downloads out of the user's
+        std::env::set_var(
+            "DUMA_DATA_DIR",
+            std::env::temp_dir().join(format!("duma-test-data-{}", std::process::id())),
+        );
         let server = Arc::new(Server::http("0.0.0.0:35550").unwrap());
         for _ in 0..4 {
             let server = server.clone();
@@ -20,19 +62,417 @@ pub fn setup() {
                 handle_req(request).unwrap();
             });
         }
+
+        // tiny_http drops range related headers, so endpoints that need
+        // them are served by a bare bones responder on a second port.
+        let listener = TcpListener::bind("0.0.0.0:35551").unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                thread::spawn(move || handle_raw_req(stream));
+            }
+        });
     });
 }
 
+/// Starts accepting raw connections on `port` only after `delay`, so the
+/// first connection attempts are refused.
+pub fn serve_after(port: u16, delay: Duration) {
+    thread::spawn(move || {
+        thread::sleep(delay);
+        let listener = TcpListener::bind(("0.0.0.0", port)).unwrap();
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            thread::spawn(move || handle_raw_req(stream));
+        }
+    });
+}
+
+/// Answers every A query on UDP `port` with 127.0.0.1 and every other
+/// query with no records, a DNS server that knows only the test hosts.
+pub fn serve_dns(port: u16) {
+    let socket = UdpSocket::bind(("127.0.0.1", port)).unwrap();
+    thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+            let mut resp = buf[..len].to_vec();
+            resp[2] |= 0x80;
+            // the question ends with its type and class
+            if len > 16 && buf[len - 4..len - 2] == [0, 1] {
+                resp[7] = 1;
+                resp.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+            }
+            let _ = socket.send_to(&resp, peer);
+        }
+    });
+}
+
+struct RawRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RawRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.as_str())
+    }
+}
+
+fn read_raw_req(stream: &TcpStream) -> Result<RawRequest, Error> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_owned();
+    let path = parts.next().unwrap_or("").to_owned();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, val)) = line.split_once(':') {
+            headers.push((key.trim().to_owned(), val.trim().to_owned()));
+        }
+    }
+    Ok(RawRequest {
+        method,
+        path,
+        headers,
+    })
+}
+
+fn write_raw_resp(
+    mut stream: &TcpStream,
+    status: &str,
+    headers: &[String],
+    body: &[u8],
+) -> Result<(), Error> {
+    let mut resp = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for hdr in headers {
+        resp.push_str(hdr);
+        resp.push_str("\r\n");
+    }
+    resp.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    stream.write_all(resp.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn handle_raw_req(stream: TcpStream) {
+    let req = match read_raw_req(&stream) {
+        Ok(req) => req,
+        Err(_) => return,
+    };
+    // an absolute url is a request for the server to act as a proxy
+    if req.path.starts_with("http://") {
+        let _ = respond_as_proxy(&stream, &req);
+        return;
+    }
+    // presigned s3 requests carry their signature in the query string
+    if req.path.starts_with("/bucket/foo.txt?") {
+        let _ = respond_to_presigned(&stream, &req);
+        return;
+    }
+    let _ = match req.path.as_str() {
+        "/ranges" => respond_with_ranges(&stream, &req),
+        "/unadvertised-ranges" => serve_ranges(&stream, &req, false),
+        "/no-ranges" => write_raw_resp(&stream, "200 OK", &[], &foo_txt().unwrap_or_default()),
+        "/content-mode" => write_raw_resp(
+            &stream,
+            "200 OK",
+            &["X-Content-Mode: 4750".to_owned()],
+            b"#!/bin/sh\n",
+        ),
+        "/slow" => respond_slowly(&stream),
+        "/slow-ranges" => respond_with_slow_ranges(&stream, &req),
+        "/broken-chunk" => respond_with_broken_chunk(&stream, &req),
+        "/flaky-chunk" => respond_with_flaky_chunk(&stream, &req),
+        "/no-length" => respond_without_length(&stream, &req),
+        "/site/page.html" => write_raw_resp(
+            &stream,
+            "200 OK",
+            &["Content-Type: text/html".to_owned()],
+            b"<html><head><link rel=\"stylesheet\" href=\"style.css\">\
+              <script src=\"/app.js\"></script></head>\
+              <body><img src=\"img/logo.png\"><img src=\"gone.png\"></body></html>",
+        ),
+        "/site/style.css" => write_raw_resp(&stream, "200 OK", &[], b"body { color: red }"),
+        "/site/img/logo.png" => write_raw_resp(&stream, "200 OK", &[], b"\x89PNG\r\n"),
+        "/app.js" => write_raw_resp(&stream, "200 OK", &[], b"alert(1)"),
+        "/robots.txt" => write_raw_resp(
+            &stream,
+            "200 OK",
+            &["Content-Type: text/plain".to_owned()],
+            b"User-agent: *\nDisallow: /site/img/\n",
+        ),
+        "/browser-only" => respond_to_browsers(&stream, &req),
+        "/client-header" => match req.header("X-Duma-Client") {
+            Some("injected") => respond_with_ranges(&stream, &req),
+            _ => write_raw_resp(&stream, "403 Forbidden", &[], b""),
+        },
+        "/big-headers" => respond_with_big_headers(&stream),
+        "/gzip" => respond_with_gzip(&stream, &req),
+        "/auth" => respond_with_auth(&stream, &req),
+        "/counted" => respond_counted(&stream, &req, &COUNTED),
+        "/counted-global" => respond_counted(&stream, &req, &COUNTED_GLOBAL),
+        "/user-agent" => {
+            if let Some(agent) = req.header("User-Agent") {
+                USER_AGENTS.lock().unwrap().push(agent.to_owned());
+            }
+            respond_with_ranges(&stream, &req)
+        }
+        "/not-modified" => match req.header("If-Modified-Since") {
+            Some(_) => write_raw_resp(&stream, "304 Not Modified", &[], b""),
+            None => respond_with_ranges(&stream, &req),
+        },
+        "/method" => write_raw_resp(
+            &stream,
+            "200 OK",
+            &[format!("X-Request-Method: {}", req.method)],
+            b"",
+        ),
+        _ => write_raw_resp(&stream, "404 Not Found", &[], b""),
+    };
+}
+
+fn foo_txt() -> Result<Vec<u8>, Error> {
+    let mut path = std::env::current_dir()?;
+    path.push("tests");
+    path.push("foo.txt");
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn respond_with_ranges(stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    serve_ranges(stream, req, true)
+}
+
+/// Serves `/bucket/foo.txt` like an S3 server, to urls presigned with the
+/// `test-key` access key only.
+fn respond_to_presigned(stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    if req.path.contains("X-Amz-Credential=test-key%2F") && req.path.contains("&X-Amz-Signature=") {
+        respond_with_ranges(stream, req)
+    } else {
+        write_raw_resp(stream, "403 Forbidden", &[], b"")
+    }
+}
+
+/// Honours `Range` requests, with or without saying so in `Accept-Ranges`.
+fn serve_ranges(stream: &TcpStream, req: &RawRequest, advertise: bool) -> Result<(), Error> {
+    let data = foo_txt()?;
+    let total = data.len();
+    let body = if req.method == "HEAD" {
+        &[][..]
+    } else {
+        &data[..]
+    };
+    let ct_type = "Content-Type: text/plain".to_owned();
+    let mut headers = vec![ct_type];
+    if advertise {
+        headers.push("Accept-Ranges: bytes".to_owned());
+    }
+    match req.header("Range") {
+        Some(range) => {
+            let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+            let start = start.parse::<usize>().unwrap();
+            let end = match end {
+                "" => total - 1,
+                val => val.parse::<usize>().unwrap().min(total - 1),
+            };
+            headers.push(format!("Content-Range: bytes {}-{}/{}", start, end, total));
+            write_raw_resp(stream, "206 Partial Content", &headers, &body[start..=end])
+        }
+        None => write_raw_resp(stream, "200 OK", &headers, body),
+    }
+}
+
+fn respond_with_slow_ranges(stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    // long enough for the client to look at the file mid download
+    if req.method != "HEAD" {
+        thread::sleep(Duration::from_millis(300));
+    }
+    respond_with_ranges(stream, req)
+}
+
+fn respond_with_broken_chunk(stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    // hang up on the second 16 KiB chunk, every time
+    if req.header("Range") == Some("bytes=16384-32767") {
+        return Ok(());
+    }
+    respond_with_ranges(stream, req)
+}
+
+fn respond_as_proxy(stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    // user:pass
+    if req.header("Proxy-Authorization") == Some("Basic dXNlcjpwYXNz") {
+        respond_with_ranges(stream, req)
+    } else {
+        write_raw_resp(stream, "407 Proxy Authentication Required", &[], b"")
+    }
+}
+
+fn respond_with_flaky_chunk(stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    // hang up on the second 16 KiB chunk until its fifth attempt
+    if req.header("Range") == Some("bytes=16384-32767")
+        && FLAKY_ATTEMPTS.fetch_add(1, Ordering::SeqCst) < 4
+    {
+        return Ok(());
+    }
+    respond_with_ranges(stream, req)
+}
+
+/// Serves ranges, but leaves the length out of the whole file's response
+/// like a streaming proxy would.
+fn respond_without_length(mut stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    if req.header("Range").is_some() {
+        return respond_with_ranges(stream, req);
+    }
+    let head = "HTTP/1.1 200 OK\r\nConnection: close\r\nAccept-Ranges: bytes\r\n\r\n";
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&foo_txt()?)?;
+    stream.flush()
+}
+
+fn respond_to_browsers(stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    let looks_like_browser = req.header("Sec-Fetch-Mode") == Some("navigate")
+        && req.header("Origin") == Some("http://0.0.0.0:35551")
+        && req.header("Accept-Language").is_some();
+    if looks_like_browser {
+        respond_with_ranges(stream, req)
+    } else {
+        write_raw_resp(stream, "403 Forbidden", &[], b"")
+    }
+}
+
+fn respond_with_big_headers(stream: &TcpStream) -> Result<(), Error> {
+    // 32 KiB of headers in all
+    let headers: Vec<_> = (0..32)
+        .map(|i| format!("X-Padding-{:02}: {}", i, "x".repeat(1010)))
+        .collect();
+    write_raw_resp(stream, "200 OK", &headers, b"small body")
+}
+
+fn respond_counted(stream: &TcpStream, req: &RawRequest, gauge: &Gauge) -> Result<(), Error> {
+    let active = gauge.active.fetch_add(1, Ordering::SeqCst) + 1;
+    gauge.max.fetch_max(active, Ordering::SeqCst);
+    // linger so that overlapping requests are actually seen
+    thread::sleep(Duration::from_millis(20));
+    let result = respond_with_ranges(stream, req);
+    gauge.active.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+fn respond_with_auth(stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    // user:pass
+    if req.header("Authorization") == Some("Basic dXNlcjpwYXNz") {
+        respond_with_ranges(stream, req)
+    } else {
+        write_raw_resp(stream, "401 Unauthorized", &[], b"")
+    }
+}
+
+fn respond_with_gzip(stream: &TcpStream, req: &RawRequest) -> Result<(), Error> {
+    let accepts_gzip = req
+        .header("Accept-Encoding")
+        .is_some_and(|val| val.contains("gzip"));
+    if !accepts_gzip {
+        return write_raw_resp(stream, "200 OK", &[], &foo_txt()?);
+    }
+    let mut path = std::env::current_dir()?;
+    path.push("tests");
+    path.push("foo.txt.gz");
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    write_raw_resp(
+        stream,
+        "200 OK",
+        &["Content-Encoding: gzip".to_owned()],
+        &data,
+    )
+}
+
+fn respond_slowly(mut stream: &TcpStream) -> Result<(), Error> {
+    stream.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2000\r\n\r\n")?;
+    for _ in 0..100 {
+        stream.write_all(&[b'x'; 20])?;
+        stream.flush()?;
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
 fn handle_req(req: Request) -> Result<(), Error> {
     match req.url() {
         "/headers" => respond_with_headers(req),
         "/timeout" => respond_with_timeout(req),
         "/file" => respond_with_file(req),
+        "/dav" => respond_with_dav(req),
         "/content-disposition" => respond_with_content_disposition(req),
+        "/content-md5" => respond_with_content_md5(req, true),
+        "/bad-content-md5" => respond_with_content_md5(req, false),
+        "/archive/" => respond_with_zip(req),
+        "/export/" => respond_with_typed_zip(req),
+        "/upload" => respond_with_upload(req),
+        url if url.starts_with("/crawl/") => respond_with_crawl_site(req),
         _ => respond_with_headers(req),
     }
 }
 
+/// A small site of pages linking to each other and to a few assets.
+fn respond_with_crawl_site(req: Request) -> Result<(), Error> {
+    let (content_type, body) = match req.url() {
+        "/crawl/index.html" => (
+            "text/html",
+            "<html><body><a href=\"docs.html\">Docs</a><img src=\"a.png\">\
+             <a href=\"notes.txt\">Notes</a><a href=\"http://example.com/x.png\">Away</a>\
+             </body></html>",
+        ),
+        "/crawl/docs.html" => (
+            "text/html",
+            "<html><head><script src=\"b.js\"></script></head><body>\
+             <a href=\"index.html\">Home</a><a href=\"deeper.html\">More</a></body></html>",
+        ),
+        "/crawl/deeper.html" => (
+            "text/html",
+            "<html><body><img src=\"c.png\"><a href=\"sub/index.html\">Sub</a></body></html>",
+        ),
+        // a second index.html, in a directory of its own
+        "/crawl/sub/index.html" => (
+            "text/html",
+            "<html><body><a href=\"/crawl/docs.html\">Docs</a><img src=\"../a.png\"></body></html>",
+        ),
+        "/crawl/a.png" | "/crawl/c.png" => ("image/png", "PNG"),
+        "/crawl/b.js" => ("application/javascript", "alert(1)"),
+        "/crawl/notes.txt" => ("text/plain", "notes"),
+        // links whose names decode to paths outside the download directory
+        "/crawl/evil.html" => (
+            "text/html",
+            "<html><body><img src=\"..%2F..%2Fpwned.txt\">\
+             <img src=\"%2Ftmp%2Fduma-pwned.txt\"></body></html>",
+        ),
+        "/crawl/..%2F..%2Fpwned.txt" | "/crawl/%2Ftmp%2Fduma-pwned.txt" => ("text/plain", "pwned"),
+        _ => return req.respond(Response::empty(404)),
+    };
+    let res = Response::from_data(body)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap());
+    req.respond(res)
+}
+
+/// Answers with the method on a line of its own, then the request body.
+fn respond_with_upload(mut req: Request) -> Result<(), Error> {
+    let mut body = format!("{}\n", req.method().as_str()).into_bytes();
+    req.as_reader().read_to_end(&mut body)?;
+    req.respond(Response::from_data(body))
+}
+
 fn respond_with_headers(req: Request) -> Result<(), Error> {
     let res = Response::empty(200)
         .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap());
@@ -46,6 +486,37 @@ fn respond_with_timeout(req: Request) -> Result<(), Error> {
     req.respond(res)
 }
 
+fn respond_with_dav(req: Request) -> Result<(), Error> {
+    if req.method().to_string() != "PROPFIND" {
+        return respond_with_file(req);
+    }
+    let len = foo_txt()?.len();
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+  <D:response>
+    <D:href>/dav</D:href>
+    <D:propstat>
+      <D:prop>
+        <D:getcontentlength>{}</D:getcontentlength>
+        <D:getlastmodified>Wed, 11 Nov 2020 10:00:00 GMT</D:getlastmodified>
+      </D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+  </D:response>
+</D:multistatus>"#,
+        len
+    );
+    let ctype = "Content-Type: application/xml; charset=utf-8"
+        .parse::<Header>()
+        .unwrap();
+    req.respond(
+        Response::from_string(xml)
+            .with_status_code(207)
+            .with_header(ctype),
+    )
+}
+
 fn respond_with_file(req: Request) -> Result<(), Error> {
     let mut path = std::env::current_dir()?;
     path.push("tests");
@@ -83,3 +554,38 @@ fn respond_with_content_disposition(req: Request) -> Result<(), Error> {
             .with_header(clength),
     )
 }
+
+fn respond_with_content_md5(req: Request, valid: bool) -> Result<(), Error> {
+    let mut path = std::env::current_dir()?;
+    path.push("tests");
+    path.push("foo.txt");
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let digest = if valid {
+        md5::compute(&data)
+    } else {
+        md5::compute(b"not the file")
+    };
+    let cmd5 = format!("Content-MD5: {}", base64::encode(digest.0))
+        .parse::<Header>()
+        .unwrap();
+    let ctype = "Content-Type: text/plain".parse::<Header>().unwrap();
+    req.respond(
+        Response::from_data(data)
+            .with_header(ctype)
+            .with_header(cmd5),
+    )
+}
+
+fn respond_with_zip(req: Request) -> Result<(), Error> {
+    let mut data = b"PK\x03\x04".to_vec();
+    data.resize(1024, 0);
+    req.respond(Response::from_data(data))
+}
+
+fn respond_with_typed_zip(req: Request) -> Result<(), Error> {
+    let mut data = b"PK\x03\x04".to_vec();
+    data.resize(1024, 0);
+    let ct_type = Header::from_bytes(&b"Content-Type"[..], &b"application/zip"[..]).unwrap();
+    req.respond(Response::from_data(data).with_header(ct_type))
+}